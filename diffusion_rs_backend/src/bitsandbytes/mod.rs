@@ -2,9 +2,9 @@ use std::sync::Arc;
 
 use diffusion_rs_common::core::{DType, Device, Result, Shape, Tensor};
 use diffusion_rs_common::VarBuilder;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{QuantMethod, QuantMethodConfig};
+use crate::{QuantMethod, QuantMethodConfig, SerializedTensor};
 
 #[cfg(feature = "cuda")]
 mod ffi;
@@ -13,7 +13,7 @@ mod op;
 
 const SUPPORTED_BLOCKSIZE: [usize; 7] = [2048, 4096, 1024, 512, 256, 128, 64];
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
 pub enum BnbDType {
     #[serde(rename = "float32")]
     F32,
@@ -40,7 +40,7 @@ impl From<BnbDType> for DType {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct BnbQuantState {
     pub blocksize: usize,
     pub shape: Vec<usize>,
@@ -267,7 +267,11 @@ impl QuantMethod for BnbLinear {
         Self: Sized,
     {
         match method {
-            QuantMethodConfig::Gguf { .. } | QuantMethodConfig::Unquantized(_) => unreachable!(),
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Lora { .. }
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Fp8 { .. } => unreachable!(),
             QuantMethodConfig::Bnb4bit {
                 weight,
                 bias,
@@ -385,4 +389,64 @@ impl QuantMethod for BnbLinear {
             Self::Fp4Nf4 { weight, .. } | Self::Int8 { weight, .. } => weight.device().clone(),
         }
     }
+
+    fn serialize(&self) -> Result<Vec<(String, SerializedTensor)>> {
+        let (mut tensors, bias) = match self {
+            Self::Fp4Nf4 {
+                weight,
+                bias,
+                params,
+                quant_ty,
+            } => {
+                let state_name = match quant_ty {
+                    BnbQuantType::Nf4 => "weight.quant_state.bitsandbytes__nf4",
+                    BnbQuantType::Fp4 => "weight.quant_state.bitsandbytes__fp4",
+                    BnbQuantType::Int8 => unreachable!(),
+                };
+                let state = BnbQuantState {
+                    blocksize: params.blocksize,
+                    shape: params
+                        .shape
+                        .as_ref()
+                        .map(|shape| shape.dims().to_vec())
+                        .unwrap_or_else(|| weight.dims().to_vec()),
+                    dtype: params.dtype,
+                    nested_blocksize: params.nested.as_ref().map(|nested| nested.blocksize),
+                    nested_offset: params.offset,
+                    nested_dtype: params.nested.as_ref().map(|nested| nested.dtype),
+                };
+                let state =
+                    serde_json::to_vec(&state).map_err(diffusion_rs_common::core::Error::msg)?;
+                let state_len = state.len();
+                let mut tensors = vec![
+                    ("weight".to_string(), weight.clone()),
+                    (
+                        state_name.to_string(),
+                        Tensor::from_vec(state, state_len, &Device::Cpu)?,
+                    ),
+                    ("weight.absmax".to_string(), params.absmax.clone()),
+                    ("weight.quant_map".to_string(), params.code.clone()),
+                ];
+                if let Some(nested) = &params.nested {
+                    tensors.push(("weight.nested_absmax".to_string(), nested.absmax.clone()));
+                    tensors.push(("weight.nested_quant_map".to_string(), nested.code.clone()));
+                }
+                (tensors, bias)
+            }
+            Self::Int8 { weight, scb, bias } => (
+                vec![
+                    ("weight".to_string(), weight.clone()),
+                    ("SCB".to_string(), scb.clone()),
+                ],
+                bias,
+            ),
+        };
+        if let Some(bias) = bias {
+            tensors.push(("bias".to_string(), bias.clone()));
+        }
+        Ok(tensors
+            .into_iter()
+            .map(|(name, t)| (name, SerializedTensor::Tensor(t)))
+            .collect())
+    }
 }
@@ -0,0 +1,234 @@
+use std::sync::Arc;
+
+use diffusion_rs_common::core::{DType, Device, Result, Tensor, D};
+use diffusion_rs_common::VarBuilder;
+
+use crate::{QuantMethod, QuantMethodConfig, SerializedTensor};
+
+/// The largest finite value of FP8 E4M3.
+const F8E4M3_MAX: f64 = 448.;
+
+/// Names of the scale of an FP8 weight, as written by various tools.
+const SCALE_NAMES: &[&str] = &["weight_scale", "scale_weight"];
+
+/// A linear layer with an FP8 (E4M3) weight and a per-tensor or per-channel scale.
+///
+/// The weight is dequantized for the matmul, which works on any device supporting FP8 storage.
+#[derive(Debug)]
+pub struct Fp8Linear {
+    w: Tensor,
+    /// The scale of the weight in F32, of shape `(1, 1)` or `(out_dim, 1)`.
+    scale: Tensor,
+    b: Option<Tensor>,
+}
+
+impl Fp8Linear {
+    /// Load a layer whose weight is stored in FP8, with an optional scale. Without a scale, the weight is only
+    /// cast, as in most FP8 FLUX checkpoints.
+    pub fn linear_b(in_dim: usize, out_dim: usize, bias: bool, vb: VarBuilder) -> Result<Self> {
+        let w = vb.get_unchecked_dtype("weight", DType::F8E4M3)?;
+        if w.dims() != [out_dim, in_dim] {
+            diffusion_rs_common::bail!(
+                "Expected FP8 weight `{}.weight` of shape {:?}, got {:?}.",
+                vb.prefix(),
+                (out_dim, in_dim),
+                w.shape()
+            );
+        }
+        let scale = match SCALE_NAMES.iter().find(|name| vb.contains_tensor(name)) {
+            Some(name) => {
+                let scale = vb.get_unchecked_dtype(name, DType::F32)?;
+                match scale.elem_count() {
+                    1 => scale.reshape((1, 1))?,
+                    n if n == out_dim => scale.reshape((out_dim, 1))?,
+                    _ => diffusion_rs_common::bail!(
+                        "Expected a per-tensor or per-channel scale for the FP8 weight `{}.weight`, got shape {:?}.",
+                        vb.prefix(),
+                        scale.shape()
+                    ),
+                }
+            }
+            None => Tensor::ones((1, 1), DType::F32, vb.device())?,
+        };
+        let b = if bias {
+            Some(vb.get((out_dim,), "bias")?)
+        } else {
+            None
+        };
+        Ok(Self { w, scale, b })
+    }
+
+    /// Quantize `w` to FP8 with a scale per output channel. The layer is created on the device of `w`.
+    pub fn quantize(w: &Tensor, b: Option<Tensor>) -> Result<Self> {
+        let w = w.to_dtype(DType::F32)?;
+        let scale = (w.abs()?.max_keepdim(D::Minus1)? / F8E4M3_MAX)?.maximum(1e-12)?;
+        Ok(Self {
+            w: w.broadcast_div(&scale)?.to_dtype(DType::F8E4M3)?,
+            scale,
+            b,
+        })
+    }
+}
+
+impl QuantMethod for Fp8Linear {
+    fn new(method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Bnb4bit { .. }
+            | QuantMethodConfig::Lora { .. }
+            | QuantMethodConfig::Hqq { .. } => unreachable!(),
+            QuantMethodConfig::Fp8 { tensor, bias } => Self::quantize(&tensor, bias),
+        }
+    }
+
+    fn dequantize_w(&self, out_ty: DType) -> Result<Tensor> {
+        self.w
+            .to_dtype(out_ty)?
+            .broadcast_mul(&self.scale.to_dtype(out_ty)?)
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let w = self.dequantize_w(xs.dtype())?.t()?;
+        let res = xs.broadcast_matmul(&w)?;
+        if let Some(b) = &self.b {
+            res.broadcast_add(&b.to_dtype(res.dtype())?)
+        } else {
+            Ok(res)
+        }
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        None
+    }
+
+    fn to_device(&self, dev: &Device) -> Result<Arc<dyn QuantMethod>> {
+        let b = if let Some(b) = &self.b {
+            Some(b.to_device(dev)?)
+        } else {
+            None
+        };
+        Ok(Arc::new(Self {
+            w: self.w.to_device(dev)?,
+            scale: self.scale.to_device(dev)?,
+            b,
+        }))
+    }
+
+    fn device(&self) -> Device {
+        self.w.device().clone()
+    }
+
+    fn size_in_bytes(&self) -> Result<usize> {
+        Ok([Some(&self.w), Some(&self.scale), self.b.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|t| t.dtype().size_in_bytes() * t.elem_count())
+            .sum())
+    }
+
+    fn serialize(&self) -> Result<Vec<(String, SerializedTensor)>> {
+        let mut tensors = vec![
+            (
+                "weight".to_string(),
+                SerializedTensor::Tensor(self.w.clone()),
+            ),
+            (
+                "weight_scale".to_string(),
+                SerializedTensor::Tensor(self.scale.clone()),
+            ),
+        ];
+        if let Some(b) = &self.b {
+            tensors.push(("bias".to_string(), SerializedTensor::Tensor(b.clone())));
+        }
+        Ok(tensors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::test_utils::{serialized_vb, weight_diff};
+
+    /// A weight of 4 rows of 32 values with very different ranges, so that a per-tensor scale would lose the small
+    /// rows.
+    fn weight() -> Tensor {
+        let dev = Device::Cpu;
+        let w = Tensor::randn(0f32, 1., (4, 32), &dev).unwrap();
+        let ranges = Tensor::new(&[1e-3f32, 1., 1e2, 1e4], &dev)
+            .unwrap()
+            .reshape((4, 1))
+            .unwrap();
+        w.broadcast_mul(&ranges).unwrap()
+    }
+
+    /// The largest error of `layer` relative to the largest absolute value of each row of `w`.
+    fn max_relative_error(layer: &Fp8Linear, w: &Tensor) -> f32 {
+        let err = (layer.dequantize_w(DType::F32).unwrap() - w)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_keepdim(D::Minus1)
+            .unwrap();
+        let range = w.abs().unwrap().max_keepdim(D::Minus1).unwrap();
+        (err / range)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn quantize_round_trip() {
+        let w = weight();
+        let layer = Fp8Linear::quantize(&w, None).unwrap();
+        assert_eq!(layer.scale.dims(), [4, 1]);
+        // E4M3 has 3 mantissa bits, so the rounding error is at most 1 / 16 of a value.
+        let err = max_relative_error(&layer, &w);
+        assert!(err < 1. / 16., "max relative error {err}");
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let w = weight();
+        let b = Tensor::arange(0f32, 4., &Device::Cpu).unwrap();
+        let layer = Fp8Linear::quantize(&w, Some(b.clone())).unwrap();
+        let loaded = Fp8Linear::linear_b(32, 4, true, serialized_vb(&layer)).unwrap();
+        assert_eq!(weight_diff(&loaded, &layer), 0.);
+        assert_eq!(
+            loaded.b.unwrap().to_vec1::<f32>().unwrap(),
+            b.to_vec1::<f32>().unwrap()
+        );
+    }
+
+    #[test]
+    fn per_tensor_scale() {
+        let dev = Device::Cpu;
+        let w = Tensor::new(&[[1f32, -2.], [4., 8.]], &dev).unwrap();
+        let tensors = HashMap::from([
+            ("weight".to_string(), w.to_dtype(DType::F8E4M3).unwrap()),
+            (
+                "scale_weight".to_string(),
+                Tensor::new(0.5f32, &dev).unwrap(),
+            ),
+        ]);
+        let vb = VarBuilder::from_tensors(tensors, DType::F32, &dev);
+        let layer = Fp8Linear::linear_b(2, 2, false, vb).unwrap();
+        assert_eq!(
+            layer
+                .dequantize_w(DType::F32)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap(),
+            [[0.5, -1.], [2., 4.]]
+        );
+    }
+}
@@ -4,7 +4,7 @@ use diffusion_rs_common::core::Device;
 use diffusion_rs_common::core::{quantized::QMatMul, DType, Result, Tensor};
 use diffusion_rs_common::nn::Module;
 
-use crate::{QuantMethod, QuantMethodConfig};
+use crate::{QuantMethod, QuantMethodConfig, SerializedTensor};
 
 #[derive(Debug)]
 pub struct GgufMatMul {
@@ -22,7 +22,11 @@ impl QuantMethod for GgufMatMul {
                 w: QMatMul::from_arc(q_weight)?,
                 b,
             }),
-            QuantMethodConfig::Unquantized(_) | QuantMethodConfig::Bnb4bit { .. } => unreachable!(),
+            QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Bnb4bit { .. }
+            | QuantMethodConfig::Lora { .. }
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Fp8 { .. } => unreachable!(),
         }
     }
 
@@ -78,4 +82,16 @@ impl QuantMethod for GgufMatMul {
             QMatMul::Tensor(t) | QMatMul::TensorF16(t) => t.device().clone(),
         }
     }
+
+    fn serialize(&self) -> Result<Vec<(String, SerializedTensor)>> {
+        let w = match &self.w {
+            QMatMul::QTensor(q) => SerializedTensor::Quantized(q.clone()),
+            QMatMul::Tensor(t) | QMatMul::TensorF16(t) => SerializedTensor::Tensor(t.clone()),
+        };
+        let mut tensors = vec![("weight".to_string(), w)];
+        if let Some(b) = &self.b {
+            tensors.push(("bias".to_string(), SerializedTensor::Tensor(b.clone())));
+        }
+        Ok(tensors)
+    }
 }
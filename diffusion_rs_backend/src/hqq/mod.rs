@@ -0,0 +1,329 @@
+use std::sync::Arc;
+
+use diffusion_rs_common::core::{DType, Device, Result, Shape, Tensor};
+use diffusion_rs_common::VarBuilder;
+
+use crate::{QuantMethod, QuantMethodConfig, SerializedTensor};
+
+mod op;
+mod quantize;
+
+/// Number of bits of the quantized weights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HqqBits {
+    Eight = 8,
+    Four = 4,
+}
+
+impl HqqBits {
+    fn max_value(self) -> usize {
+        (1 << self as usize) - 1
+    }
+
+    fn values_per_byte(self) -> usize {
+        8 / self as usize
+    }
+
+    /// Pack quantized values of shape `(rows, cols)` into bytes. With 4 bits, the first half of the rows goes in
+    /// the high nibbles and the second half in the low ones, like the reference implementation.
+    fn pack(self, w_q: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Eight => w_q.to_dtype(DType::U8),
+            Self::Four => {
+                let half = w_q.dim(0)? / 2;
+                let high = w_q.narrow(0, 0, half)?;
+                let low = w_q.narrow(0, half, half)?;
+                ((high * 16.)? + low)?.to_dtype(DType::U8)
+            }
+        }
+    }
+}
+
+impl TryFrom<u32> for HqqBits {
+    type Error = diffusion_rs_common::core::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            8 => Ok(Self::Eight),
+            4 => Ok(Self::Four),
+            _ => diffusion_rs_common::bail!("HQQ supports 8 or 4 bits, got {value}."),
+        }
+    }
+}
+
+/// Axis of the weight, reshaped to have groups along it, over which the scales and zeros are computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HqqAxis {
+    /// The weight is reshaped to `(group_size, n / group_size)`, so groups are strided.
+    Zero = 0,
+    /// The weight is reshaped to `(n / group_size, group_size)`, so groups are contiguous.
+    One = 1,
+}
+
+impl TryFrom<u32> for HqqAxis {
+    type Error = diffusion_rs_common::core::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Zero),
+            1 => Ok(Self::One),
+            _ => diffusion_rs_common::bail!("HQQ axis must be 0 or 1, got {value}."),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HqqConfig {
+    pub bits: HqqBits,
+    pub group_size: usize,
+    pub axis: HqqAxis,
+    /// Maximum number of iterations of the solver for the zeros. If `None`, the zeros come from the range of
+    /// each group.
+    pub optimization_steps: Option<usize>,
+    pub round_zeros: bool,
+}
+
+impl HqqConfig {
+    /// The default settings, with groups of 64 contiguous values.
+    pub fn new(bits: HqqBits) -> Self {
+        Self {
+            bits,
+            group_size: 64,
+            axis: HqqAxis::One,
+            optimization_steps: Some(20),
+            round_zeros: false,
+        }
+    }
+
+    /// Whether a weight of `elem_count` elements can be quantized with these settings.
+    pub fn supports(&self, elem_count: usize) -> bool {
+        if !elem_count.is_multiple_of(self.group_size) {
+            return false;
+        }
+        let rows = match self.axis {
+            HqqAxis::Zero => self.group_size,
+            HqqAxis::One => elem_count / self.group_size,
+        };
+        rows.is_multiple_of(self.bits.values_per_byte())
+    }
+}
+
+/// A linear layer quantized with half-quadratic quantization (HQQ), with group-wise scales and zeros.
+///
+/// The weight is dequantized for the matmul, with a dedicated kernel on the CPU.
+#[derive(Debug)]
+pub struct HqqLayer {
+    /// The packed quantized values, of shape `(rows / values per byte, cols)` for the grouped weight.
+    w_q: Tensor,
+    /// The F32 scales and zeros of the groups. Zeros are in units of the quantized values, so they may be far
+    /// outside the F16 range for groups with a small range.
+    scales: Tensor,
+    zeros: Tensor,
+    bias: Option<Tensor>,
+    w_shape: Shape,
+    cfg: HqqConfig,
+}
+
+impl HqqLayer {
+    /// Load a layer serialized by [`QuantMethod::serialize`].
+    pub fn linear_b(in_dim: usize, out_dim: usize, bias: bool, vb: VarBuilder) -> Result<Self> {
+        let meta = vb
+            .get_unchecked_dtype("meta", DType::U32)?
+            .to_vec1::<u32>()?;
+        let [bits, group_size, axis] = meta.as_slice() else {
+            diffusion_rs_common::bail!("Expected HQQ meta with bits, group size and axis.");
+        };
+        let cfg = HqqConfig {
+            bits: HqqBits::try_from(*bits)?,
+            group_size: *group_size as usize,
+            axis: HqqAxis::try_from(*axis)?,
+            optimization_steps: None,
+            round_zeros: false,
+        };
+
+        let w_q = vb.get_unchecked_dtype("W_q", DType::U8)?;
+        let (packed_rows, cols) = w_q.dims2()?;
+        if packed_rows * cfg.bits.values_per_byte() * cols != in_dim * out_dim {
+            diffusion_rs_common::bail!(
+                "HQQ weight of shape {:?} does not match a linear layer of shape {:?}.",
+                w_q.shape(),
+                (out_dim, in_dim)
+            );
+        }
+        let scales = vb.get_unchecked_dtype("scale", DType::F32)?;
+        let zeros = vb.get_unchecked_dtype("zero", DType::F32)?;
+        let bias = if bias {
+            Some(vb.get((out_dim,), "bias")?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            w_q,
+            scales,
+            zeros,
+            bias,
+            w_shape: Shape::from((out_dim, in_dim)),
+            cfg,
+        })
+    }
+}
+
+impl QuantMethod for HqqLayer {
+    fn new(method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Bnb4bit { .. }
+            | QuantMethodConfig::Lora { .. }
+            | QuantMethodConfig::Fp8 { .. } => unreachable!(),
+            QuantMethodConfig::Hqq { tensor, bias, cfg } => Self::quantize(&tensor, bias, cfg),
+        }
+    }
+
+    fn dequantize_w(&self, out_ty: DType) -> Result<Tensor> {
+        op::dequantize(
+            &self.w_q,
+            &self.scales,
+            &self.zeros,
+            self.cfg.bits,
+            self.cfg.axis,
+            out_ty,
+        )?
+        .reshape(&self.w_shape)
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let w = self.dequantize_w(xs.dtype())?.t()?;
+        let res = xs.broadcast_matmul(&w)?;
+        if let Some(bias) = &self.bias {
+            res.broadcast_add(&bias.to_dtype(res.dtype())?)
+        } else {
+            Ok(res)
+        }
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        None
+    }
+
+    fn to_device(&self, dev: &Device) -> Result<Arc<dyn QuantMethod>> {
+        let bias = if let Some(bias) = &self.bias {
+            Some(bias.to_device(dev)?)
+        } else {
+            None
+        };
+        Ok(Arc::new(Self {
+            w_q: self.w_q.to_device(dev)?,
+            scales: self.scales.to_device(dev)?,
+            zeros: self.zeros.to_device(dev)?,
+            bias,
+            w_shape: self.w_shape.clone(),
+            cfg: self.cfg,
+        }))
+    }
+
+    fn device(&self) -> Device {
+        self.w_q.device().clone()
+    }
+
+    fn size_in_bytes(&self) -> Result<usize> {
+        Ok([
+            Some(&self.w_q),
+            Some(&self.scales),
+            Some(&self.zeros),
+            self.bias.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|t| t.dtype().size_in_bytes() * t.elem_count())
+        .sum())
+    }
+
+    fn serialize(&self) -> Result<Vec<(String, SerializedTensor)>> {
+        let meta = Tensor::new(
+            &[
+                self.cfg.bits as u32,
+                self.cfg.group_size as u32,
+                self.cfg.axis as u32,
+            ],
+            &Device::Cpu,
+        )?;
+        let mut tensors = vec![
+            ("W_q".to_string(), self.w_q.clone()),
+            ("scale".to_string(), self.scales.clone()),
+            ("zero".to_string(), self.zeros.clone()),
+            ("meta".to_string(), meta),
+        ];
+        if let Some(bias) = &self.bias {
+            tensors.push(("bias".to_string(), bias.clone()));
+        }
+        Ok(tensors
+            .into_iter()
+            .map(|(name, t)| (name, SerializedTensor::Tensor(t)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{serialized_vb, weight_diff};
+
+    /// A weight of 8 rows of 64 values: random rows and constant rows, including one far from zero.
+    fn weight() -> Tensor {
+        let dev = Device::Cpu;
+        let random = Tensor::randn(0f32, 1., (5, 64), &dev).unwrap();
+        let constant = Tensor::new(&[0f32, 1e3, -3.], &dev)
+            .unwrap()
+            .reshape((3, 1))
+            .unwrap()
+            .repeat((1, 64))
+            .unwrap();
+        Tensor::cat(&[random, constant], 0).unwrap()
+    }
+
+    /// The mean and max absolute errors of the dequantized weight, over the random and the constant rows.
+    fn errors(layer: &HqqLayer, w: &Tensor) -> ((f32, f32), f32) {
+        let err = (layer.dequantize_w(DType::F32).unwrap() - w)
+            .unwrap()
+            .abs()
+            .unwrap();
+        let random = err.narrow(0, 0, 5).unwrap().flatten_all().unwrap();
+        let constant = err.narrow(0, 5, 3).unwrap().flatten_all().unwrap();
+        (
+            (
+                random.mean_all().unwrap().to_scalar::<f32>().unwrap(),
+                random.max(0).unwrap().to_scalar::<f32>().unwrap(),
+            ),
+            constant.max(0).unwrap().to_scalar::<f32>().unwrap(),
+        )
+    }
+
+    #[test]
+    fn quantize_round_trip() {
+        let w = weight();
+        // The range of the random rows is about 5, so a quantization step is about 5 / 255 with 8 bits and
+        // 5 / 15 with 4 bits.
+        for (bits, mean_bound, max_bound) in
+            [(HqqBits::Eight, 0.01, 0.1), (HqqBits::Four, 0.15, 1.5)]
+        {
+            let layer = HqqLayer::quantize(&w, None, HqqConfig::new(bits)).unwrap();
+            let ((mean, max), constant) = errors(&layer, &w);
+            assert!(mean < mean_bound, "{bits:?}: mean error {mean}");
+            assert!(max < max_bound, "{bits:?}: max error {max}");
+            assert!(constant < 1e-3, "{bits:?}: constant group error {constant}");
+        }
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let w = weight();
+        let layer = HqqLayer::quantize(&w, None, HqqConfig::new(HqqBits::Four)).unwrap();
+        let loaded = HqqLayer::linear_b(64, 8, false, serialized_vb(&layer)).unwrap();
+        assert_eq!(weight_diff(&loaded, &layer), 0.);
+    }
+}
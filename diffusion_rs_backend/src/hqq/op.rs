@@ -0,0 +1,126 @@
+use diffusion_rs_common::core::{
+    backend::BackendStorage, CpuStorage, CustomOp3, DType, Layout, Result, Shape, Tensor, WithDType,
+};
+use rayon::prelude::*;
+
+use super::{HqqAxis, HqqBits};
+
+/// Dequantize packed HQQ weights of shape `(packed rows, cols)` into a `(rows, cols)` matrix.
+struct DequantizeOp {
+    bits: HqqBits,
+    axis: HqqAxis,
+    out_ty: DType,
+}
+
+impl DequantizeOp {
+    fn dequantize_cpu<T: WithDType>(
+        &self,
+        w_q: &[u8],
+        scales: &[f32],
+        zeros: &[f32],
+        rows: usize,
+        cols: usize,
+    ) -> Vec<T> {
+        let mut out = vec![T::zero(); rows * cols];
+        out.par_chunks_mut(cols).enumerate().for_each(|(r, out)| {
+            // With 4 bits, the first half of the rows is in the high nibbles and the second half in the low ones.
+            let (packed, shift) = match self.bits {
+                HqqBits::Eight => (&w_q[r * cols..(r + 1) * cols], 0),
+                HqqBits::Four if r < rows / 2 => (&w_q[r * cols..(r + 1) * cols], 4),
+                HqqBits::Four => {
+                    let r = r - rows / 2;
+                    (&w_q[r * cols..(r + 1) * cols], 0)
+                }
+            };
+            let mask = self.bits.max_value() as u8;
+            for (c, (out, q)) in out.iter_mut().zip(packed).enumerate() {
+                let group = match self.axis {
+                    HqqAxis::Zero => c,
+                    HqqAxis::One => r,
+                };
+                let q = f32::from((q >> shift) & mask);
+                let w = (q - zeros[group]) * scales[group];
+                *out = T::from_f64(w as f64);
+            }
+        });
+        out
+    }
+}
+
+impl CustomOp3 for DequantizeOp {
+    fn name(&self) -> &'static str {
+        "dequantize-hqq"
+    }
+
+    fn cpu_fwd(
+        &self,
+        w_q_s: &CpuStorage,
+        w_q_l: &Layout,
+        scales_s: &CpuStorage,
+        scales_l: &Layout,
+        zeros_s: &CpuStorage,
+        zeros_l: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        if !(w_q_l.is_contiguous() && scales_l.is_contiguous() && zeros_l.is_contiguous()) {
+            diffusion_rs_common::bail!("All inputs must be contiguous");
+        }
+        let rows = w_q_l.dim(0)? * self.bits.values_per_byte();
+        let cols = w_q_l.dim(1)?;
+        let groups = match self.axis {
+            HqqAxis::Zero => cols,
+            HqqAxis::One => rows,
+        };
+        if scales_l.shape().elem_count() != groups || zeros_l.shape().elem_count() != groups {
+            diffusion_rs_common::bail!("Expected {groups} HQQ scales and zeros.");
+        }
+
+        let (CpuStorage::U8(w_q), CpuStorage::F32(scales), CpuStorage::F32(zeros)) =
+            (w_q_s, scales_s, zeros_s)
+        else {
+            diffusion_rs_common::bail!(
+                "Unsupported dtypes for cpu dequant: {:?} weight, {:?} scales, {:?} zeros",
+                w_q_s.dtype(),
+                scales_s.dtype(),
+                zeros_s.dtype()
+            );
+        };
+        let w_q = &w_q[w_q_l.start_offset()..];
+        let scales = &scales[scales_l.start_offset()..];
+        let zeros = &zeros[zeros_l.start_offset()..];
+
+        let out = match self.out_ty {
+            DType::BF16 => CpuStorage::BF16(self.dequantize_cpu(w_q, scales, zeros, rows, cols)),
+            DType::F16 => CpuStorage::F16(self.dequantize_cpu(w_q, scales, zeros, rows, cols)),
+            DType::F32 => CpuStorage::F32(self.dequantize_cpu(w_q, scales, zeros, rows, cols)),
+            t => diffusion_rs_common::bail!("Unsupported output dtype for cpu dequant: {t:?}"),
+        };
+        Ok((out, Shape::from((rows, cols))))
+    }
+}
+
+/// Dequantize packed HQQ weights with a CPU kernel, or with tensor operations on other devices.
+pub(super) fn dequantize(
+    w_q: &Tensor,
+    scales: &Tensor,
+    zeros: &Tensor,
+    bits: HqqBits,
+    axis: HqqAxis,
+    out_ty: DType,
+) -> Result<Tensor> {
+    if w_q.device().is_cpu() {
+        return w_q.apply_op3_no_bwd(scales, zeros, &DequantizeOp { bits, axis, out_ty });
+    }
+
+    let w_q = w_q.to_dtype(DType::F32)?;
+    let w_q = match bits {
+        HqqBits::Eight => w_q,
+        HqqBits::Four => {
+            let high = (&w_q / 16.)?.floor()?;
+            let low = (&w_q - (&high * 16.)?)?;
+            Tensor::cat(&[high, low], 0)?
+        }
+    };
+    w_q.broadcast_sub(zeros)?
+        .broadcast_mul(scales)?
+        .to_dtype(out_ty)
+}
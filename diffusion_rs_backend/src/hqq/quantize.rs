@@ -0,0 +1,123 @@
+use diffusion_rs_common::core::{DType, Result, Tensor};
+
+use super::{HqqAxis, HqqConfig, HqqLayer};
+
+/// Parameters of the half-quadratic solver for the zeros.
+struct OptParams {
+    lp_norm: f64,
+    beta: f64,
+    kappa: f64,
+    iters: usize,
+}
+
+impl OptParams {
+    fn new(iters: usize) -> Self {
+        Self {
+            lp_norm: 0.7,
+            beta: 1e1,
+            kappa: 1.01,
+            iters,
+        }
+    }
+}
+
+/// Generalized soft-thresholding, the proximal operator of the `lp_norm` of the quantization error.
+fn shrink_lp(x: &Tensor, beta: f64, lp_norm: f64) -> Result<Tensor> {
+    let abs = x.abs()?;
+    let shrunk = if lp_norm == 1. {
+        (abs - 1. / beta)?
+    } else {
+        (&abs - (abs.powf(lp_norm - 1.)? / beta)?)?
+    };
+    x.sign()? * shrunk.relu()?
+}
+
+/// Optimize the zeros of the groups of `w` by minimizing the `lp_norm` of the quantization error with
+/// half-quadratic splitting, keeping the scales fixed.
+fn optimize_zeros(
+    w: &Tensor,
+    scale: &Tensor,
+    mut zero: Tensor,
+    max_v: f64,
+    axis: usize,
+    params: OptParams,
+) -> Result<Tensor> {
+    let mut beta = params.beta;
+    let mut best_error = f32::INFINITY;
+    for _ in 0..params.iters {
+        let w_q = w
+            .broadcast_mul(scale)?
+            .broadcast_add(&zero)?
+            .round()?
+            .clamp(0., max_v)?;
+        let w_r = w_q.broadcast_sub(&zero)?.broadcast_div(scale)?;
+        let w_e = shrink_lp(&(w - &w_r)?, beta, params.lp_norm)?;
+        zero = (w_q - (w - w_e)?.broadcast_mul(scale)?)?.mean_keepdim(axis)?;
+        beta *= params.kappa;
+
+        let error = (w - &w_r)?.abs()?.mean_all()?.to_scalar::<f32>()?;
+        if error < best_error {
+            best_error = error;
+        } else {
+            break;
+        }
+    }
+    Ok(zero)
+}
+
+impl HqqLayer {
+    /// Quantize `w` with half-quadratic quantization, which needs no calibration data. The layer is created on
+    /// the device of `w`.
+    pub fn quantize(w: &Tensor, bias: Option<Tensor>, cfg: HqqConfig) -> Result<Self> {
+        let shape = w.shape().clone();
+        let n = shape.elem_count();
+        if !n.is_multiple_of(cfg.group_size) {
+            diffusion_rs_common::bail!(
+                "Cannot quantize {n} elements into HQQ groups of size {}.",
+                cfg.group_size
+            );
+        }
+        let w = w.to_dtype(DType::F32)?;
+        let w = match cfg.axis {
+            HqqAxis::Zero => w.reshape((cfg.group_size, ()))?,
+            HqqAxis::One => w.reshape(((), cfg.group_size))?,
+        };
+        let rows = w.dim(0)?;
+        if !rows.is_multiple_of(cfg.bits.values_per_byte()) {
+            diffusion_rs_common::bail!(
+                "Cannot pack {rows} rows of HQQ groups with {} bits.",
+                cfg.bits as usize
+            );
+        }
+
+        // Use the range of each group, avoiding infinite scales for constant groups.
+        let axis = cfg.axis as usize;
+        let min = w.min_keepdim(axis)?;
+        let max = w.max_keepdim(axis)?;
+        let max_v = cfg.bits.max_value() as f64;
+        let scale = (max_v / (max - &min)?)?.minimum(2e4)?;
+        let mut zero = (min.neg()? * &scale)?;
+        if cfg.round_zeros {
+            zero = zero.round()?;
+        }
+        if let Some(iters) = cfg.optimization_steps {
+            zero = optimize_zeros(&w, &scale, zero, max_v, axis, OptParams::new(iters))?;
+        }
+
+        let w_q = w
+            .broadcast_mul(&scale)?
+            .broadcast_add(&zero)?
+            .round()?
+            .clamp(0., max_v)?;
+        let w_q = cfg.bits.pack(&w_q)?;
+
+        Ok(Self {
+            w_q,
+            scales: scale.recip()?,
+            zeros: zero,
+            bias,
+            w_shape: shape,
+            cfg,
+        })
+    }
+}
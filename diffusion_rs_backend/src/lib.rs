@@ -1,5 +1,6 @@
 use std::{
     fmt::{Debug, Display},
+    str::FromStr,
     sync::Arc,
 };
 
@@ -13,12 +14,20 @@ mod metal_kernels;
 
 mod bitsandbytes;
 mod cublaslt;
+mod fp8;
 mod gguf;
+mod hqq;
+mod lora;
 pub mod ops;
+#[cfg(test)]
+mod test_utils;
 mod unquantized;
 
 pub use bitsandbytes::{BnbLinear, BnbQuantParmas, BnbQuantType};
+pub use fp8::Fp8Linear;
 pub use gguf::GgufMatMul;
+pub use hqq::{HqqAxis, HqqBits, HqqConfig, HqqLayer};
+pub use lora::{LoraAdapter, LoraLinear};
 pub use unquantized::UnquantLinear;
 
 use diffusion_rs_common::nn::{Linear, Module};
@@ -32,12 +41,15 @@ pub enum QuantMethodType {
     #[default]
     #[serde(rename = "bitsandbytes")]
     Bitsandbytes,
+    #[serde(rename = "hqq")]
+    Hqq,
 }
 
 impl Display for QuantMethodType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Bitsandbytes => write!(f, "bnb"),
+            Self::Hqq => write!(f, "hqq"),
             Self::Unreachable => write!(f, "unreachable",),
         }
     }
@@ -83,6 +95,29 @@ pub enum QuantMethodConfig {
         params: BnbQuantParmas,
         quant_ty: BnbQuantType,
     },
+    Lora {
+        base: Arc<dyn QuantMethod>,
+        adapters: Vec<LoraAdapter>,
+        fused: Vec<LoraAdapter>,
+        unfused_base: Option<Arc<dyn QuantMethod>>,
+    },
+    Hqq {
+        tensor: Tensor,
+        bias: Option<Tensor>,
+        cfg: HqqConfig,
+    },
+    Fp8 {
+        tensor: Tensor,
+        bias: Option<Tensor>,
+    },
+}
+
+/// A tensor of a layer serialized with [`QuantMethod::serialize`].
+#[derive(Debug, Clone)]
+pub enum SerializedTensor {
+    Tensor(Tensor),
+    /// A GGUF-quantized tensor, which can only be saved to a GGUF file.
+    Quantized(Arc<QTensor>),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Hash, Eq)]
@@ -148,6 +183,31 @@ impl TryFrom<IsqType> for GgmlDType {
     }
 }
 
+impl FromStr for IsqType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "q4_0" => Ok(Self::Q4_0),
+            "q4_1" => Ok(Self::Q4_1),
+            "q5_0" => Ok(Self::Q5_0),
+            "q5_1" => Ok(Self::Q5_1),
+            "q8_0" => Ok(Self::Q8_0),
+            "q8_1" => Ok(Self::Q8_1),
+            "q2k" => Ok(Self::Q2K),
+            "q3k" => Ok(Self::Q3K),
+            "q4k" => Ok(Self::Q4K),
+            "q5k" => Ok(Self::Q5K),
+            "q6k" => Ok(Self::Q6K),
+            "q8k" => Ok(Self::Q8K),
+            "hqq8" => Ok(Self::HQQ8),
+            "hqq4" => Ok(Self::HQQ4),
+            "f8e4m3" => Ok(Self::F8E4M3),
+            other => Err(format!("ISQ type `{other}` is unknown, expected one of `q4_0`, `q4_1`, `q5_0`, `q5_1`, `q8_0`, `q8_1`, `q2k`, `q3k`, `q4k`, `q5k`, `q6k`, `q8k`, `hqq8`, `hqq4`, `f8e4m3`.")),
+        }
+    }
+}
+
 /// Quantized method for a quantized matmul.
 pub trait QuantMethod: Send + Sync + Debug {
     fn new(method: QuantMethodConfig) -> Result<Self>
@@ -186,6 +246,26 @@ pub trait QuantMethod: Send + Sync + Debug {
     fn device(&self) -> Device;
 
     fn size_in_bytes(&self) -> Result<usize>;
+
+    /// The tensors of this layer, relative to the layer, such as `weight`, in the format loaded back by [`linear`].
+    fn serialize(&self) -> Result<Vec<(String, SerializedTensor)>>;
+
+    /// If LoRA adapters are applied to this layer, return it as a [`LoraLinear`].
+    fn as_lora(&self) -> Option<&LoraLinear> {
+        None
+    }
+
+    /// Return this layer with `delta` added to its weight, if the weight can be modified. Quantized layers
+    /// return `None`.
+    fn add_delta_w(&self, _delta: &Tensor) -> Result<Option<Arc<dyn QuantMethod>>> {
+        Ok(None)
+    }
+
+    /// Quantize this layer in-situ to `dtype`, onto `dev`. Layers which are already quantized are only moved to
+    /// `dev`.
+    fn apply_isq(&self, _dtype: IsqType, dev: &Device) -> Result<Arc<dyn QuantMethod>> {
+        self.to_device(dev)
+    }
 }
 
 impl Module for dyn QuantMethod {
@@ -198,18 +278,55 @@ fn vb_contains_quant(vb: &VarBuilder) -> bool {
     vb.contains_tensor("weight.absmax") || vb.contains_tensor("SCB")
 }
 
+/// The GGUF weight of a linear layer, if the `VarBuilder` stores it with a quantized GGML dtype.
+fn vb_gguf_weight(in_dim: usize, out_dim: usize, vb: &VarBuilder) -> Result<Option<Arc<QTensor>>> {
+    let Some(q_weight) = vb.get_qtensor("weight") else {
+        return Ok(None);
+    };
+    if matches!(
+        q_weight.dtype(),
+        GgmlDType::F32 | GgmlDType::F16 | GgmlDType::BF16
+    ) {
+        return Ok(None);
+    }
+    if q_weight.shape().dims() != [out_dim, in_dim] {
+        diffusion_rs_common::bail!(
+            "Expected GGUF weight `{}.weight` of shape {:?}, got {:?}.",
+            vb.prefix(),
+            (out_dim, in_dim),
+            q_weight.shape()
+        );
+    }
+    Ok(Some(q_weight))
+}
+
 pub fn linear_no_bias(
     in_dim: usize,
     out_dim: usize,
     config: &Option<QuantizedConfig>,
     vb: VarBuilder,
 ) -> Result<Arc<dyn QuantMethod>> {
+    if let Some(q_weight) = vb_gguf_weight(in_dim, out_dim, &vb)? {
+        let layer =
+            <GgufMatMul as QuantMethod>::new(QuantMethodConfig::Gguf { q_weight, b: None })?;
+        return Ok(Arc::new(layer));
+    }
+    if vb.get_dtype("weight") == Some(DType::F8E4M3) {
+        return Ok(Arc::new(Fp8Linear::linear_b(in_dim, out_dim, false, vb)?));
+    }
+    // HQQ layers describe their quantization in their `meta` tensor.
+    if vb.contains_tensor("W_q") {
+        return Ok(Arc::new(HqqLayer::linear_b(in_dim, out_dim, false, vb)?));
+    }
     if vb_contains_quant(&vb) {
         if let Some(quant_conf) = &config {
             let layer = match quant_conf.quant_method {
                 QuantMethodType::Bitsandbytes => {
                     Arc::new(BnbLinear::linear_b(in_dim, out_dim, false, vb)?) as Arc<_>
                 }
+                QuantMethodType::Hqq => {
+                    Arc::new(HqqLayer::linear_b(in_dim, out_dim, false, vb)?) as Arc<_>
+                }
                 QuantMethodType::Unreachable => unreachable!(),
             };
             return Ok(layer);
@@ -230,12 +347,27 @@ pub fn linear(
     config: &Option<QuantizedConfig>,
     vb: VarBuilder,
 ) -> Result<Arc<dyn QuantMethod>> {
+    if let Some(q_weight) = vb_gguf_weight(in_dim, out_dim, &vb)? {
+        let b = Some(vb.get(out_dim, "bias")?);
+        let layer = <GgufMatMul as QuantMethod>::new(QuantMethodConfig::Gguf { q_weight, b })?;
+        return Ok(Arc::new(layer));
+    }
+    if vb.get_dtype("weight") == Some(DType::F8E4M3) {
+        return Ok(Arc::new(Fp8Linear::linear_b(in_dim, out_dim, true, vb)?));
+    }
+    // HQQ layers describe their quantization in their `meta` tensor.
+    if vb.contains_tensor("W_q") {
+        return Ok(Arc::new(HqqLayer::linear_b(in_dim, out_dim, true, vb)?));
+    }
     if vb_contains_quant(&vb) {
         if let Some(quant_conf) = &config {
             let layer = match quant_conf.quant_method {
                 QuantMethodType::Bitsandbytes => {
                     Arc::new(BnbLinear::linear_b(in_dim, out_dim, true, vb)?) as Arc<_>
                 }
+                QuantMethodType::Hqq => {
+                    Arc::new(HqqLayer::linear_b(in_dim, out_dim, true, vb)?) as Arc<_>
+                }
                 QuantMethodType::Unreachable => unreachable!(),
             };
             return Ok(layer);
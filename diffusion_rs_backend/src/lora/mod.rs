@@ -0,0 +1,354 @@
+use std::sync::Arc;
+
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+
+use crate::{IsqType, QuantMethod, QuantMethodConfig, SerializedTensor};
+
+/// A named low-rank adapter for a linear layer, adding `alpha_scale * scale * b @ a` to its weight.
+#[derive(Debug, Clone)]
+pub struct LoraAdapter {
+    pub name: String,
+    /// Down projection, of shape `(rank, in_dim)`.
+    pub a: Tensor,
+    /// Up projection, of shape `(out_dim, rank)`.
+    pub b: Tensor,
+    /// The `alpha / rank` factor of this module.
+    pub alpha_scale: f64,
+    /// The scale of the adapter, set by the user. An adapter with a scale of 0 is inactive.
+    pub scale: f64,
+}
+
+impl LoraAdapter {
+    fn is_active(&self) -> bool {
+        self.scale != 0.
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = xs.to_dtype(self.a.dtype())?;
+        let ys = xs
+            .broadcast_matmul(&self.a.t()?)?
+            .broadcast_matmul(&self.b.t()?)?;
+        ys * (self.alpha_scale * self.scale)
+    }
+
+    /// The weight delta of this adapter, of shape `(out_dim, in_dim)`.
+    fn delta_w(&self, out_ty: DType) -> Result<Tensor> {
+        (self.b.matmul(&self.a)? * (self.alpha_scale * self.scale))?.to_dtype(out_ty)
+    }
+
+    fn to_device(&self, dev: &Device) -> Result<Self> {
+        Ok(Self {
+            a: self.a.to_device(dev)?,
+            b: self.b.to_device(dev)?,
+            ..self.clone()
+        })
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        [&self.a, &self.b]
+            .iter()
+            .map(|t| t.dtype().size_in_bytes() * t.elem_count())
+            .sum()
+    }
+}
+
+/// Sum of the weight deltas of some adapters, in F32.
+fn sum_delta_w<'a>(adapters: impl Iterator<Item = &'a LoraAdapter>) -> Result<Option<Tensor>> {
+    let mut sum: Option<Tensor> = None;
+    for adapter in adapters {
+        let delta = adapter.delta_w(DType::F32)?;
+        sum = Some(match sum {
+            Some(sum) => (sum + delta)?,
+            None => delta,
+        });
+    }
+    Ok(sum)
+}
+
+fn adapters_to_device(adapters: &[LoraAdapter], dev: &Device) -> Result<Vec<LoraAdapter>> {
+    adapters
+        .iter()
+        .map(|adapter| adapter.to_device(dev))
+        .collect()
+}
+
+/// A linear layer with LoRA adapters applied as a runtime low-rank delta.
+///
+/// The base layer is left untouched, so this works on top of any quantized layer without dequantizing it.
+/// Adapters may instead be fused into the weight of an unquantized base layer with [`LoraLinear::fuse`].
+#[derive(Debug)]
+pub struct LoraLinear {
+    base: Arc<dyn QuantMethod>,
+    adapters: Vec<LoraAdapter>,
+    /// Adapters whose delta has been added to the weight of `base`.
+    fused: Vec<LoraAdapter>,
+    /// The base layer before the adapters were fused, kept on the CPU so that unfusing restores it exactly.
+    unfused_base: Option<Arc<dyn QuantMethod>>,
+}
+
+impl LoraLinear {
+    /// Apply an adapter on top of `layer`, in addition to any adapters already applied to it. An adapter with the
+    /// same name is replaced.
+    pub fn apply(
+        layer: &Arc<dyn QuantMethod>,
+        adapter: LoraAdapter,
+    ) -> Result<Arc<dyn QuantMethod>> {
+        let (base, mut adapters, fused, unfused_base) = match layer.as_lora() {
+            Some(lora) => (
+                lora.base.clone(),
+                lora.adapters
+                    .iter()
+                    .filter(|x| x.name != adapter.name)
+                    .cloned()
+                    .collect(),
+                lora.fused.clone(),
+                lora.unfused_base.clone(),
+            ),
+            None => (layer.clone(), Vec::new(), Vec::new(), None),
+        };
+        adapters.push(adapter);
+        Ok(Arc::new(<Self as QuantMethod>::new(
+            QuantMethodConfig::Lora {
+                base,
+                adapters,
+                fused,
+                unfused_base,
+            },
+        )?))
+    }
+
+    /// Update or remove the unfused adapters of `layer`. If no adapters remain, the base layer is returned.
+    pub fn map_adapters(
+        layer: &Arc<dyn QuantMethod>,
+        f: impl FnMut(LoraAdapter) -> Option<LoraAdapter>,
+    ) -> Arc<dyn QuantMethod> {
+        let Some(lora) = layer.as_lora() else {
+            return layer.clone();
+        };
+        let adapters = lora
+            .adapters
+            .iter()
+            .cloned()
+            .filter_map(f)
+            .collect::<Vec<_>>();
+        if adapters.is_empty() && lora.fused.is_empty() {
+            return lora.base.clone();
+        }
+        Arc::new(Self {
+            base: lora.base.clone(),
+            adapters,
+            fused: lora.fused.clone(),
+            unfused_base: lora.unfused_base.clone(),
+        })
+    }
+
+    /// Fuse the active adapters of `layer` into the weight of its base layer, at their current scales. This
+    /// removes the runtime cost of the adapters.
+    ///
+    /// Only base layers which support modifying their weight, such as [`crate::UnquantLinear`], can be fused.
+    /// Other layers are returned unchanged.
+    pub fn fuse(layer: &Arc<dyn QuantMethod>) -> Result<Arc<dyn QuantMethod>> {
+        let Some(lora) = layer.as_lora() else {
+            return Ok(layer.clone());
+        };
+        let Some(delta) = sum_delta_w(lora.adapters.iter().filter(|x| x.is_active()))? else {
+            return Ok(layer.clone());
+        };
+        let Some(base) = lora.base.add_delta_w(&delta)? else {
+            return Ok(layer.clone());
+        };
+        let unfused_base = match &lora.unfused_base {
+            Some(unfused_base) => unfused_base.clone(),
+            None => lora.base.to_device(&Device::Cpu)?,
+        };
+        let (active, inactive): (Vec<_>, Vec<_>) =
+            lora.adapters.iter().cloned().partition(|x| x.is_active());
+        Ok(Arc::new(Self {
+            base,
+            adapters: inactive,
+            fused: lora.fused.iter().cloned().chain(active).collect(),
+            unfused_base: Some(unfused_base),
+        }))
+    }
+
+    /// Restore the base layer of `layer` from before [`LoraLinear::fuse`], applying the fused adapters at runtime
+    /// again.
+    pub fn unfuse(layer: &Arc<dyn QuantMethod>) -> Result<Arc<dyn QuantMethod>> {
+        let Some(lora) = layer.as_lora() else {
+            return Ok(layer.clone());
+        };
+        let Some(unfused_base) = &lora.unfused_base else {
+            return Ok(layer.clone());
+        };
+        Ok(Arc::new(Self {
+            base: unfused_base.to_device(&lora.base.device())?,
+            adapters: lora.adapters.iter().chain(&lora.fused).cloned().collect(),
+            fused: Vec::new(),
+            unfused_base: None,
+        }))
+    }
+
+    /// The unfused adapters of this layer.
+    pub fn adapters(&self) -> &[LoraAdapter] {
+        &self.adapters
+    }
+
+    /// The adapters fused into the weight of the base layer.
+    pub fn fused_adapters(&self) -> &[LoraAdapter] {
+        &self.fused
+    }
+
+    fn add_adapters(&self, a: &Tensor, mut ys: Tensor) -> Result<Tensor> {
+        for adapter in self.adapters.iter().filter(|x| x.is_active()) {
+            let delta = adapter.forward(a)?.to_dtype(ys.dtype())?;
+            ys = (ys + delta)?;
+        }
+        Ok(ys)
+    }
+}
+
+impl QuantMethod for LoraLinear {
+    fn new(method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Bnb4bit { .. }
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Fp8 { .. } => unreachable!(),
+            QuantMethodConfig::Lora {
+                base,
+                adapters,
+                fused,
+                unfused_base,
+            } => Ok(Self {
+                base,
+                adapters,
+                fused,
+                unfused_base,
+            }),
+        }
+    }
+
+    fn dequantize_w(&self, out_ty: DType) -> Result<Tensor> {
+        let w = self.base.dequantize_w(out_ty)?;
+        match sum_delta_w(self.adapters.iter().filter(|x| x.is_active()))? {
+            Some(delta) => w.clone() + delta.to_dtype(out_ty)?.to_device(w.device())?,
+            None => Ok(w),
+        }
+    }
+
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        self.add_adapters(a, self.base.forward(a)?)
+    }
+
+    fn forward_via_half(&self, a: &Tensor) -> Result<Tensor> {
+        self.add_adapters(a, self.base.forward_via_half(a)?)
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        self.base.quantized_act_type()
+    }
+
+    fn to_device(&self, dev: &Device) -> Result<Arc<dyn QuantMethod>> {
+        Ok(Arc::new(Self {
+            base: self.base.to_device(dev)?,
+            adapters: adapters_to_device(&self.adapters, dev)?,
+            fused: adapters_to_device(&self.fused, dev)?,
+            unfused_base: self.unfused_base.clone(),
+        }))
+    }
+
+    fn device(&self) -> Device {
+        self.base.device()
+    }
+
+    fn size_in_bytes(&self) -> Result<usize> {
+        Ok(self.base.size_in_bytes()?
+            + self
+                .adapters
+                .iter()
+                .chain(&self.fused)
+                .map(LoraAdapter::size_in_bytes)
+                .sum::<usize>())
+    }
+
+    fn serialize(&self) -> Result<Vec<(String, SerializedTensor)>> {
+        if self.adapters.iter().any(LoraAdapter::is_active) {
+            diffusion_rs_common::bail!(
+                "Cannot serialize a layer with LoRA adapters applied at runtime, fuse or unload them first."
+            );
+        }
+        self.base.serialize()
+    }
+
+    fn as_lora(&self) -> Option<&LoraLinear> {
+        Some(self)
+    }
+
+    fn apply_isq(&self, dtype: IsqType, dev: &Device) -> Result<Arc<dyn QuantMethod>> {
+        Ok(Arc::new(Self {
+            base: self.base.apply_isq(dtype, dev)?,
+            adapters: adapters_to_device(&self.adapters, dev)?,
+            fused: adapters_to_device(&self.fused, dev)?,
+            unfused_base: self.unfused_base.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_common::core::test_utils::max_abs_diff;
+    use diffusion_rs_common::nn::Linear;
+
+    use super::*;
+    use crate::UnquantLinear;
+
+    #[test]
+    fn fuse_then_unfuse_restores_weights() {
+        let dev = Device::Cpu;
+        let w = Tensor::randn(0f32, 1., (16, 8), &dev)
+            .unwrap()
+            .to_dtype(DType::BF16)
+            .unwrap();
+        let layer: Arc<dyn QuantMethod> = Arc::new(
+            <UnquantLinear as QuantMethod>::new(QuantMethodConfig::Unquantized(Linear::new(
+                w.clone(),
+                None,
+            )))
+            .unwrap(),
+        );
+        let adapter = LoraAdapter {
+            name: "adapter".to_string(),
+            a: Tensor::randn(0f32, 1., (4, 8), &dev).unwrap(),
+            b: Tensor::randn(0f32, 1., (16, 4), &dev).unwrap(),
+            alpha_scale: 0.5,
+            scale: 0.3,
+        };
+        let layer = LoraLinear::apply(&layer, adapter).unwrap();
+        let runtime = layer.dequantize_w(DType::F32).unwrap();
+
+        let fused = LoraLinear::fuse(&layer).unwrap();
+        let lora = fused.as_lora().unwrap();
+        assert!(lora.adapters().is_empty());
+        assert_eq!(lora.fused_adapters().len(), 1);
+        let diff = max_abs_diff(&fused.dequantize_w(DType::F32).unwrap(), &runtime).unwrap();
+        assert!(diff < 0.1, "{diff}");
+
+        let unfused = LoraLinear::unfuse(&fused).unwrap();
+        let lora = unfused.as_lora().unwrap();
+        assert_eq!(lora.adapters().len(), 1);
+        assert!(lora.fused_adapters().is_empty());
+        let restored = lora.base.dequantize_w(DType::BF16).unwrap();
+        assert_eq!(
+            restored
+                .to_dtype(DType::F32)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap(),
+            w.to_dtype(DType::F32).unwrap().to_vec2::<f32>().unwrap()
+        );
+    }
+}
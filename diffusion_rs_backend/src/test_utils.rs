@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+
+use diffusion_rs_common::core::{DType, Device};
+use diffusion_rs_common::VarBuilder;
+
+use crate::{QuantMethod, SerializedTensor};
+
+/// The serialized tensors of `layer`, in a `VarBuilder` to load it back.
+pub(crate) fn serialized_vb(layer: &dyn QuantMethod) -> VarBuilder<'static> {
+    let tensors = layer
+        .serialize()
+        .unwrap()
+        .into_iter()
+        .map(|(name, t)| match t {
+            SerializedTensor::Tensor(t) => (name, t),
+            SerializedTensor::Quantized(_) => panic!("unexpected GGUF tensor `{name}`"),
+        })
+        .collect::<HashMap<_, _>>();
+    VarBuilder::from_tensors(tensors, DType::F32, &Device::Cpu)
+}
+
+/// The largest absolute difference between the dequantized weights of two layers.
+pub(crate) fn weight_diff(a: &dyn QuantMethod, b: &dyn QuantMethod) -> f32 {
+    diffusion_rs_common::core::test_utils::max_abs_diff(
+        &a.dequantize_w(DType::F32).unwrap(),
+        &b.dequantize_w(DType::F32).unwrap(),
+    )
+    .unwrap()
+}
@@ -1,10 +1,14 @@
 use std::sync::Arc;
 
-use diffusion_rs_common::core::{DType, Device, DeviceLocation, Result, Shape, Tensor, D};
+use diffusion_rs_common::core::{
+    quantized::{GgmlDType, QTensor},
+    DType, Device, DeviceLocation, Result, Shape, Tensor, D,
+};
 
 use crate::{
     cublaslt::{maybe_init_cublas_lt_wrapper, CUBLASLT_HANDLE},
-    QuantMethod, QuantMethodConfig,
+    Fp8Linear, GgufMatMul, HqqBits, HqqConfig, HqqLayer, IsqType, QuantMethod, QuantMethodConfig,
+    SerializedTensor,
 };
 
 #[derive(Debug)]
@@ -19,7 +23,11 @@ impl QuantMethod for UnquantLinear {
         Self: Sized,
     {
         match method {
-            QuantMethodConfig::Gguf { .. } | QuantMethodConfig::Bnb4bit { .. } => unreachable!(),
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Bnb4bit { .. }
+            | QuantMethodConfig::Lora { .. }
+            | QuantMethodConfig::Hqq { .. }
+            | QuantMethodConfig::Fp8 { .. } => unreachable!(),
             QuantMethodConfig::Unquantized(l) => Ok(Self {
                 w: l.weight().clone(),
                 b: l.bias().cloned(),
@@ -103,4 +111,73 @@ impl QuantMethod for UnquantLinear {
     fn device(&self) -> Device {
         self.w.device().clone()
     }
+
+    fn serialize(&self) -> Result<Vec<(String, SerializedTensor)>> {
+        let mut tensors = vec![(
+            "weight".to_string(),
+            SerializedTensor::Tensor(self.w.clone()),
+        )];
+        if let Some(b) = &self.b {
+            tensors.push(("bias".to_string(), SerializedTensor::Tensor(b.clone())));
+        }
+        Ok(tensors)
+    }
+
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Option<Arc<dyn QuantMethod>>> {
+        let w = (self.w.to_dtype(DType::F32)? + delta.to_device(self.w.device())?)?
+            .to_dtype(self.w.dtype())?;
+        Ok(Some(Arc::new(Self {
+            w,
+            b: self.b.clone(),
+        })))
+    }
+
+    fn apply_isq(&self, dtype: IsqType, dev: &Device) -> Result<Arc<dyn QuantMethod>> {
+        let hqq_bits = match dtype {
+            IsqType::HQQ8 => Some(HqqBits::Eight),
+            IsqType::HQQ4 => Some(HqqBits::Four),
+            _ => None,
+        };
+        if let Some(bits) = hqq_bits {
+            let cfg = HqqConfig::new(bits);
+            if !cfg.supports(self.w.elem_count()) {
+                return self.to_device(dev);
+            }
+            // Quantize where the weight is, the solver needs several copies of it.
+            let layer = <HqqLayer as QuantMethod>::new(QuantMethodConfig::Hqq {
+                tensor: self.w.clone(),
+                bias: self.b.clone(),
+                cfg,
+            })?;
+            return layer.to_device(dev);
+        }
+        if dtype == IsqType::F8E4M3 {
+            let layer = <Fp8Linear as QuantMethod>::new(QuantMethodConfig::Fp8 {
+                tensor: self.w.clone(),
+                bias: self.b.clone(),
+            })?;
+            return layer.to_device(dev);
+        }
+
+        let ggml_dtype = GgmlDType::try_from(dtype)?;
+        // Rows which are not made of whole blocks cannot be quantized, such layers are kept unquantized.
+        if self.w.dim(D::Minus1)? % ggml_dtype.block_size() != 0 {
+            return self.to_device(dev);
+        }
+        let q_weight = if self.w.device().is_cpu() {
+            QTensor::quantize_onto(&self.w, ggml_dtype, dev)?
+        } else {
+            QTensor::quantize(&self.w.to_device(dev)?, ggml_dtype)?
+        };
+        let b = match &self.b {
+            Some(b) => Some(b.to_device(dev)?),
+            None => None,
+        };
+        Ok(Arc::new(<GgufMatMul as QuantMethod>::new(
+            QuantMethodConfig::Gguf {
+                q_weight: Arc::new(q_weight),
+                b,
+            },
+        )?))
+    }
 }
@@ -1,9 +1,10 @@
 use cliclack::input;
-use std::{path::PathBuf, time::Instant};
+use std::{path::PathBuf, sync::Arc, time::Instant};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use diffusion_rs_core::{
-    DiffusionGenerationParams, ModelDType, ModelSource, Offloading, Pipeline, TokenSource,
+    pack_dduf, validate_dduf, DiffusionGenerationParams, IsqType, LoadOptions, ModelDType,
+    ModelSource, Offloading, Pipeline, SamplerType, SchedulerOverrides, SigmaSchedule, TokenSource,
 };
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
@@ -27,16 +28,145 @@ pub enum SourceCommand {
     },
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Sampler {
+    Euler,
+    Heun,
+    #[value(name = "dpmpp-2m")]
+    DpmPp2M,
+    EulerAncestral,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Schedule {
+    Linear,
+    Karras,
+    Exponential,
+    Beta,
+    Simple,
+}
+
 #[derive(Parser)]
-struct Args {
+struct Cli {
     #[clap(subcommand)]
-    source: SourceCommand,
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Load a model and generate images interactively.
+    Generate(GenerateArgs),
+
+    /// Load a model and save it, keeping the quantization of its layers and fusing the LoRA adapter, if any.
+    Save(SaveArgs),
+
+    /// Pack a model in the diffusers layout into a DDUF file, without loading it.
+    PackDduf {
+        /// Directory of the model
+        #[arg(short, long)]
+        dir: PathBuf,
+
+        /// DDUF file path to write
+        #[arg(short, long)]
+        file: PathBuf,
+    },
 
-    /// Hugging Face token. Useful for accessing gated repositories.
+    /// Check a DDUF file without loading the model, listing the problems found.
+    ValidateDduf {
+        /// DDUF file path
+        #[arg(short, long)]
+        file: PathBuf,
+    },
+}
+
+/// Options for loading a model, shared by the commands which load one.
+#[derive(clap::Args)]
+struct LoadArgs {
+    /// Hugging Face token. Useful for accessing gated repositories, including LoRA adapters.
     /// By default, the Hugging Face token at ~/.cache/huggingface/token is used.
     #[arg(long)]
     token: Option<String>,
 
+    /// Offloading setting to use for this model
+    #[arg(short, long)]
+    offloading: Option<Offloading>,
+
+    /// DType for the model. The default is to use an automatic strategy with a fallback pattern: BF16 -> F16 -> F32
+    #[arg(short, long, default_value = "auto")]
+    dtype: ModelDType,
+
+    /// LoRA adapter to apply: a .safetensors file, a directory or a Hugging Face model ID.
+    #[arg(long)]
+    lora: Option<String>,
+
+    /// Scale of the LoRA adapter.
+    #[arg(long, default_value_t = 1.0)]
+    lora_scale: f64,
+
+    /// Load the weights of a component from a single GGUF or safetensors file (such as an FP8 checkpoint), as
+    /// `<component>=<file>`. For example, `transformer=city96/FLUX.1-dev-gguf/flux1-dev-Q4_K_S.gguf`. The file is
+    /// a local path or a Hugging Face model ID followed by the file name, read from the `main` revision. May be
+    /// repeated.
+    #[arg(long)]
+    component_file: Vec<String>,
+
+    /// Quantize the linear layers of unquantized models in-situ while loading, for example `q4k` or `q8_0`.
+    #[arg(long)]
+    isq: Option<IsqType>,
+
+    /// Load the model and LoRA adapter from the local Hugging Face cache only, without accessing the network. This
+    /// is also enabled by setting `HF_HUB_OFFLINE=1`.
+    #[arg(long)]
+    offline: bool,
+}
+
+impl LoadArgs {
+    /// Load the model from `source`, with the LoRA adapter if any.
+    fn load(self, source: SourceCommand, allow_saving: bool) -> anyhow::Result<Pipeline> {
+        let mut source = match source {
+            SourceCommand::Dduf { file } => ModelSource::dduf(file)?,
+            SourceCommand::ModelId { model_id } => ModelSource::from_model_id(model_id),
+        };
+        for component_file in &self.component_file {
+            let Some((component, file)) = component_file.split_once('=') else {
+                anyhow::bail!(
+                    "Expected `<component>=<file>` for `--component-file`, got `{component_file}`."
+                );
+            };
+            source = source.override_component_file(component, file);
+        }
+        let token = self
+            .token
+            .map(TokenSource::Literal)
+            .unwrap_or(TokenSource::CacheToken);
+
+        let pipeline = Pipeline::load(
+            source,
+            LoadOptions {
+                token,
+                offloading_type: self.offloading,
+                dtype: self.dtype,
+                isq: self.isq,
+                offline: self.offline,
+                allow_saving,
+                ..Default::default()
+            },
+        )?;
+        if let Some(lora) = self.lora {
+            pipeline.load_lora(lora, self.lora_scale)?;
+        }
+        Ok(pipeline)
+    }
+}
+
+#[derive(clap::Args)]
+struct GenerateArgs {
+    #[clap(subcommand)]
+    source: SourceCommand,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
     /// Guidance scale to use. This is model specific. If not specified, defaults to 0.0.
     #[arg(short, long)]
     scale: Option<f64>,
@@ -45,33 +175,136 @@ struct Args {
     #[arg(short, long)]
     num_steps: usize,
 
-    /// Offloading setting to use for this model
-    #[arg(short, long)]
-    offloading: Option<Offloading>,
+    /// Seed for the initial noise. If not specified, a random seed is used for each image.
+    #[arg(long)]
+    seed: Option<u64>,
 
-    /// DType for the model. The default is to use an automatic strategy with a fallback pattern: BF16 -> F16 -> F32
-    #[arg(short, long, default_value = "auto")]
-    dtype: ModelDType,
+    /// Negative prompt, used with true classifier-free guidance.
+    #[arg(long)]
+    negative_prompt: Option<String>,
+
+    /// True classifier-free guidance scale. Values greater than 1 enable it, at the cost of two model evaluations
+    /// per step.
+    #[arg(long, default_value_t = 1.0)]
+    true_cfg_scale: f64,
+
+    /// Sampler to use. If not specified, the sampler is chosen from the model's scheduler.
+    #[arg(long)]
+    sampler: Option<Sampler>,
+
+    /// Amount of noise added by the `euler-ancestral` sampler at every step, from 0 to 1.
+    #[arg(long, default_value_t = 1.0)]
+    eta: f64,
+
+    /// Spacing of the sigmas over the steps. If not specified, the spacing is chosen from the model's scheduler.
+    #[arg(long)]
+    sigma_schedule: Option<Schedule>,
+
+    /// Override the static shift of the scheduler.
+    #[arg(long)]
+    shift: Option<f64>,
+
+    /// Override whether the scheduler shifts the sigmas depending on the image size.
+    #[arg(long)]
+    use_dynamic_shifting: Option<bool>,
+
+    /// Override the dynamic shift of the scheduler for small images.
+    #[arg(long)]
+    base_shift: Option<f64>,
+
+    /// Override the dynamic shift of the scheduler for large images.
+    #[arg(long)]
+    max_shift: Option<f64>,
+}
+
+#[derive(clap::Args)]
+struct SaveArgs {
+    #[clap(subcommand)]
+    source: SourceCommand,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Save the model to a DDUF file. Models with GGUF-quantized layers cannot be saved to DDUF, see `--dir`.
+    #[arg(long, conflicts_with = "dir", required_unless_present = "dir")]
+    dduf: Option<PathBuf>,
+
+    /// Save the model to a directory in the diffusers layout.
+    #[arg(long)]
+    dir: Option<PathBuf>,
 }
 
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
     let filter = EnvFilter::builder()
         .with_default_directive(LevelFilter::INFO.into())
         .from_env_lossy();
     tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    let source = match args.source {
-        SourceCommand::Dduf { file } => ModelSource::dduf(file)?,
-        SourceCommand::ModelId { model_id } => ModelSource::from_model_id(model_id),
-    };
-    let token = args
-        .token
-        .map(TokenSource::Literal)
-        .unwrap_or(TokenSource::CacheToken);
+    match cli.command {
+        Command::Generate(args) => generate(args),
+        Command::Save(args) => save(args),
+        Command::PackDduf { dir, file } => {
+            pack_dduf(dir, &file)?;
+            println!("Packed the model into `{}`.", file.display());
+            Ok(())
+        }
+        Command::ValidateDduf { file } => {
+            let report = validate_dduf(file)?;
+            if !report.is_valid() {
+                anyhow::bail!("{report}");
+            }
+            println!("{report}");
+            Ok(())
+        }
+    }
+}
+
+fn save(args: SaveArgs) -> anyhow::Result<()> {
+    let pipeline = args.load.load(args.source, true)?;
+    if !pipeline.lora_adapters().is_empty() {
+        pipeline.fuse_loras()?;
+    }
+    if let Some(file) = args.dduf {
+        pipeline.save_dduf(&file)?;
+        println!("Saved the model to `{}`.", file.display());
+    }
+    if let Some(dir) = args.dir {
+        pipeline.save_pretrained(&dir)?;
+        println!("Saved the model to `{}`.", dir.display());
+    }
+    Ok(())
+}
+
+fn generate(args: GenerateArgs) -> anyhow::Result<()> {
+    let sampler = args.sampler.map(|sampler| {
+        Arc::new(match sampler {
+            Sampler::Euler => SamplerType::Euler,
+            Sampler::Heun => SamplerType::Heun,
+            Sampler::DpmPp2M => SamplerType::DpmPp2M,
+            Sampler::EulerAncestral => SamplerType::EulerAncestral { eta: args.eta },
+        }) as Arc<dyn diffusion_rs_core::Sampler>
+    });
+
+    let sigma_schedule = args.sigma_schedule.map(|schedule| match schedule {
+        Schedule::Linear => SigmaSchedule::Linear,
+        Schedule::Karras => SigmaSchedule::karras(),
+        Schedule::Exponential => SigmaSchedule::Exponential,
+        Schedule::Beta => SigmaSchedule::beta(),
+        Schedule::Simple => SigmaSchedule::Simple,
+    });
 
-    let pipeline = Pipeline::load(source, false, token, None, args.offloading, &args.dtype)?;
+    let pipeline = args
+        .load
+        .load(args.source, false)?
+        .with_scheduler_overrides(SchedulerOverrides {
+            shift: args.shift,
+            use_dynamic_shifting: args.use_dynamic_shifting,
+            base_shift: args.base_shift,
+            max_shift: args.max_shift,
+            ..Default::default()
+        });
 
     let height: usize = input("Height:")
         .default_input("720")
@@ -107,20 +340,27 @@ fn main() -> anyhow::Result<()> {
 
         let start = Instant::now();
 
-        let images = pipeline.forward(
+        let output = pipeline.forward(
             vec![prompt],
             DiffusionGenerationParams {
                 height,
                 width,
                 num_steps: args.num_steps,
                 guidance_scale: args.scale.unwrap_or(GUIDANCE_SCALE_DEFAULT),
+                seed: args.seed,
+                negative_prompts: args.negative_prompt.clone().map(|prompt| vec![prompt]),
+                true_cfg_scale: args.true_cfg_scale,
+                sampler: sampler.clone(),
+                sigma_schedule: sigma_schedule.clone(),
+                ..Default::default()
             },
         )?;
 
         let end = Instant::now();
         println!(
-            "Image generation took: {:.2}s",
-            end.duration_since(start).as_secs_f32()
+            "Image generation took: {:.2}s (seed {})",
+            end.duration_since(start).as_secs_f32(),
+            output.seeds[0]
         );
 
         let out_file: String = input("Save image to:")
@@ -139,6 +379,6 @@ fn main() -> anyhow::Result<()> {
             })
             .interact()?;
 
-        images[0].save(out_file)?;
+        output.images[0].save(out_file)?;
     }
 }
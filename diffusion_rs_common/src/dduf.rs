@@ -0,0 +1,62 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// Alignment of the data of the files of a DDUF archive, so that tensors can be used directly from a memory map.
+pub const DDUF_ALIGNMENT: u16 = 64;
+
+/// A file of a DDUF archive, as stored in it.
+#[derive(Debug, Clone)]
+pub struct DdufEntry {
+    pub name: String,
+    /// Whether the file is stored uncompressed, as required to read it in place from the archive.
+    pub stored: bool,
+    /// Offset of the data of the file in the archive.
+    pub data_start: u64,
+    pub size: u64,
+}
+
+/// Writer of DDUF files: ZIP archives of the files of a model in the diffusers layout, such as
+/// `model_index.json` and `transformer/config.json`. Files are stored uncompressed and aligned, as required by
+/// the format.
+pub struct DdufWriter {
+    zip: ZipWriter<File>,
+}
+
+impl DdufWriter {
+    /// Create the DDUF file at `path`, overwriting it if it exists.
+    pub fn create(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(Self {
+            zip: ZipWriter::new(File::create(path)?),
+        })
+    }
+
+    /// Add a file of `size` bytes read from `reader`, under `name`, relative to the root of the model.
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        reader: &mut impl Read,
+        size: u64,
+    ) -> anyhow::Result<()> {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .with_alignment(DDUF_ALIGNMENT)
+            .large_file(size >= u32::MAX as u64);
+        self.zip.start_file(name, options)?;
+        let copied = io::copy(reader, &mut self.zip)?;
+        if copied != size {
+            anyhow::bail!("Expected {size} bytes for `{name}`, got {copied}.");
+        }
+        Ok(())
+    }
+
+    /// Write the central directory of the archive, completing it.
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.zip.finish()?;
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+mod dduf;
 mod model_source;
 mod nn_wrap;
 mod progress;
@@ -15,11 +16,12 @@ pub mod cuda_kernels;
 #[cfg(feature = "metal")]
 pub mod metal_kernels;
 
+pub use dduf::{DdufEntry, DdufWriter, DDUF_ALIGNMENT};
 pub use model_source::*;
 pub use nn_wrap::*;
 pub use progress::NiceProgressBar;
 pub use tokenizer::load_bpe_tokenizer;
 pub use tokens::get_token;
 pub use tokens::TokenSource;
-pub use varbuilder::VarBuilder;
+pub use varbuilder::{TensorRecord, VarBuilder};
 pub use varbuilder_loading::from_mmaped_safetensors;
@@ -1,22 +1,25 @@
 use std::{
+    env,
     ffi::OsStr,
     fmt::{Debug, Display},
     fs::{self, File},
     io::Cursor,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use crate::{get_token, TokenSource};
+use crate::{get_token, DdufEntry, TokenSource};
 use hf_hub::{
     api::sync::{ApiBuilder, ApiRepo},
-    Repo, RepoType,
+    Cache, Repo, RepoType,
 };
 use memmap2::Mmap;
-use zip::ZipArchive;
+use zip::{CompressionMethod, ZipArchive};
 
 /// Source from which to load the model. This is easiest to create with the various constructor functions.
 pub enum ModelSource {
     ModelId(String),
+    /// A local directory in the diffusers layout, read without the Hugging Face API.
+    LocalDir(PathBuf),
     ModelIdWithTransformer {
         model_id: String,
         transformer_model_id: String,
@@ -25,6 +28,11 @@ pub enum ModelSource {
         file: Cursor<Mmap>,
         name: String,
     },
+    WithComponentFiles {
+        source: Box<ModelSource>,
+        /// Pairs of a component name, such as `transformer`, and the single file to load its weights from.
+        files: Vec<(String, String)>,
+    },
 }
 
 impl Display for ModelSource {
@@ -32,6 +40,7 @@ impl Display for ModelSource {
         match self {
             Self::Dduf { file: _, name } => write!(f, "dduf file: {name}"),
             Self::ModelId(model_id) => write!(f, "model id: {model_id}"),
+            Self::LocalDir(dir) => write!(f, "local directory: {}", dir.display()),
             Self::ModelIdWithTransformer {
                 model_id,
                 transformer_model_id,
@@ -39,17 +48,31 @@ impl Display for ModelSource {
                 f,
                 "model id: {model_id}, transformer override: {transformer_model_id}"
             ),
+            Self::WithComponentFiles { source, files } => {
+                write!(f, "{source}")?;
+                for (component, file) in files {
+                    write!(f, ", {component} file override: {file}")?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
 impl ModelSource {
-    /// Load the model from a Hugging Face model ID or a local path.
+    /// Load the model from a Hugging Face model ID or a local path. A local directory is read without the Hugging
+    /// Face API, like with [`ModelSource::local_dir`].
     pub fn from_model_id<S: ToString>(model_id: S) -> Self {
         Self::ModelId(model_id.to_string())
     }
 
-    /// Load the transformer part of this model from a Hugging Face model ID or a local path.
+    /// Load the model from a local directory in the diffusers layout, without accessing the network.
+    pub fn local_dir<P: AsRef<Path>>(dir: P) -> Self {
+        Self::LocalDir(dir.as_ref().to_path_buf())
+    }
+
+    /// Load the transformer part of this model from a Hugging Face model ID or a local directory. The model itself
+    /// must also be loaded from a model ID or a local directory.
     ///
     /// For example, this enables loading a quantized transformer model (for instance, [this](https://huggingface.co/sayakpaul/flux.1-dev-nf4-with-bnb-integration))
     /// with the same [base model](https://huggingface.co/black-forest-labs/FLUX.1-dev) as the original model ID.
@@ -63,8 +86,10 @@ impl ModelSource {
     /// # Ok::<(), anyhow::Error>(())
     /// ```
     pub fn override_transformer_model_id<S: ToString>(self, model_id: S) -> anyhow::Result<Self> {
-        let Self::ModelId(base_id) = self else {
-            anyhow::bail!("Expected model ID for the model source")
+        let base_id = match self {
+            Self::ModelId(base_id) => base_id,
+            Self::LocalDir(dir) => dir.to_string_lossy().to_string(),
+            _ => anyhow::bail!("Expected model ID or local directory for the model source"),
         };
         Ok(Self::ModelIdWithTransformer {
             model_id: base_id,
@@ -72,6 +97,57 @@ impl ModelSource {
         })
     }
 
+    /// Load the weights of a component of this model, such as `transformer` or `text_encoder_2`, from a single
+    /// file: a GGUF file, or a `.safetensors` checkpoint. The config of the component is still read from this
+    /// model source.
+    ///
+    /// The file is either a local path, or a Hugging Face model ID followed by the file name. Files of Hugging Face
+    /// repositories are always read from their `main` revision, including offline: the revision given when loading
+    /// the pipeline only applies to the model itself. For example, this
+    /// enables loading a [quantized FLUX transformer](https://huggingface.co/city96/FLUX.1-dev-gguf),
+    /// [T5 encoder](https://huggingface.co/city96/t5-v1_1-xxl-encoder-gguf) or
+    /// [FP8 FLUX transformer](https://huggingface.co/Kijai/flux-fp8) with the original model ID.
+    ///
+    /// ```rust
+    /// use diffusion_rs_common::ModelSource;
+    ///
+    /// let _ = ModelSource::from_model_id("black-forest-labs/FLUX.1-dev")
+    ///     .override_component_file("transformer", "city96/FLUX.1-dev-gguf/flux1-dev-Q4_K_S.gguf")
+    ///     .override_component_file(
+    ///         "text_encoder_2",
+    ///         "city96/t5-v1_1-xxl-encoder-gguf/t5-v1_1-xxl-encoder-Q8_0.gguf",
+    ///     );
+    ///
+    /// let _ = ModelSource::from_model_id("black-forest-labs/FLUX.1-dev")
+    ///     .override_component_file("transformer", "Kijai/flux-fp8/flux1-dev-fp8.safetensors");
+    /// ```
+    pub fn override_component_file<C: ToString, F: ToString>(self, component: C, file: F) -> Self {
+        let component = component.to_string();
+        let (source, mut files) = match self {
+            Self::WithComponentFiles { source, files } => (source, files),
+            other => (Box::new(other), Vec::new()),
+        };
+        files.retain(|(x, _)| *x != component);
+        files.push((component, file.to_string()));
+        Self::WithComponentFiles { source, files }
+    }
+
+    /// The components whose weights are loaded from single files, with the files.
+    pub fn component_files(&self) -> &[(String, String)] {
+        match self {
+            Self::WithComponentFiles { source: _, files } => files,
+            _ => &[],
+        }
+    }
+
+    /// The source of the model files, without the component file overrides.
+    pub fn base_source(&self) -> &Self {
+        match self {
+            Self::WithComponentFiles { source, files: _ } => source.base_source(),
+            other => other,
+        }
+    }
+
     /// Load a DDUF model from a .dduf file.
     pub fn dduf<S: ToString>(filename: S) -> anyhow::Result<Self> {
         let file = File::open(filename.to_string())?;
@@ -84,73 +160,147 @@ impl ModelSource {
     }
 }
 
+/// Whether the Hugging Face Hub is disabled with the `HF_HUB_OFFLINE` environment variable, as in the Python
+/// libraries.
+pub fn hf_hub_offline() -> bool {
+    env::var("HF_HUB_OFFLINE")
+        .is_ok_and(|x| matches!(x.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+}
+
+/// Error returned when a model is not in the local Hugging Face cache while loading it offline.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error(
+    "Model `{model_id}` at revision `{revision}` is not in the Hugging Face cache at `{}`, it must be downloaded before loading it offline.",
+    cache.display()
+)]
+pub struct NotCachedError {
+    pub model_id: String,
+    pub revision: String,
+    pub cache: PathBuf,
+}
+
+/// The snapshot of `model_id` at `revision` in the local Hugging Face cache, the directory of the downloaded files
+/// of the model, resolved without accessing the network.
+pub fn cached_snapshot(model_id: &str, revision: &str) -> Result<PathBuf, NotCachedError> {
+    cached_snapshot_in(&Cache::default(), model_id, revision)
+}
+
+/// The snapshot of `model_id` at `revision` in the Hugging Face cache `cache`, see [`cached_snapshot`].
+pub fn cached_snapshot_in(
+    cache: &Cache,
+    model_id: &str,
+    revision: &str,
+) -> Result<PathBuf, NotCachedError> {
+    let repo = Repo::with_revision(model_id.to_string(), RepoType::Model, revision.to_string());
+    let repo_dir = cache.path().join(repo.folder_name());
+    // A branch or tag is resolved to a commit with its ref, and a commit hash is used as is.
+    let commit = match fs::read_to_string(repo_dir.join("refs").join(revision)) {
+        Ok(commit) => commit.trim().to_string(),
+        Err(_) => revision.to_string(),
+    };
+    let snapshot = repo_dir.join("snapshots").join(commit);
+    if !snapshot.is_dir() {
+        return Err(NotCachedError {
+            model_id: model_id.to_string(),
+            revision: revision.to_string(),
+            cache: cache.path().clone(),
+        });
+    }
+    Ok(snapshot)
+}
+
 pub enum FileLoader<'a> {
     Api(Box<ApiRepo>),
-    ApiWithTransformer {
-        base: Box<ApiRepo>,
-        transformer: Box<ApiRepo>,
+    /// A local directory in the diffusers layout, read without the Hugging Face API.
+    Local(PathBuf),
+    /// A model whose transformer files come from another source, such as a quantized transformer.
+    WithTransformer {
+        base: Box<FileLoader<'a>>,
+        transformer: Box<FileLoader<'a>>,
     },
     Dduf(ZipArchive<&'a mut Cursor<Mmap>>),
 }
 
 impl<'a> FileLoader<'a> {
+    /// Load the files of a model. If `offline`, models from the Hugging Face Hub are read from the snapshot of the
+    /// revision in the local cache, without accessing the network.
     pub fn from_model_source(
         source: &'a mut ModelSource,
         silent: bool,
         token: TokenSource,
         revision: Option<String>,
+        offline: bool,
     ) -> anyhow::Result<Self> {
         match source {
             ModelSource::ModelId(model_id) => {
-                let api_builder = ApiBuilder::new()
-                    .with_progress(!silent)
-                    .with_token(get_token(&token)?)
-                    .build()?;
-                let revision = revision.unwrap_or("main".to_string());
-                let api = api_builder.repo(Repo::with_revision(
-                    model_id.clone(),
-                    RepoType::Model,
-                    revision.clone(),
-                ));
-
-                Ok(Self::Api(Box::new(api)))
+                Self::from_model_id(model_id, silent, &token, revision, offline)
             }
+            ModelSource::LocalDir(dir) => Self::local(dir.clone()),
             ModelSource::Dduf { file, name: _ } => Ok(Self::Dduf(ZipArchive::new(file)?)),
+            ModelSource::WithComponentFiles { source, files: _ } => {
+                Self::from_model_source(source, silent, token, revision, offline)
+            }
             ModelSource::ModelIdWithTransformer {
                 model_id,
                 transformer_model_id,
-            } => {
-                let api_builder = ApiBuilder::new()
-                    .with_progress(!silent)
-                    .with_token(get_token(&token)?)
-                    .build()?;
-                let revision = revision.unwrap_or("main".to_string());
-                let api = api_builder.repo(Repo::with_revision(
-                    model_id.clone(),
-                    RepoType::Model,
-                    revision.clone(),
-                ));
-                let transformer_api = api_builder.repo(Repo::with_revision(
-                    transformer_model_id.clone(),
-                    RepoType::Model,
+            } => Ok(Self::WithTransformer {
+                base: Box::new(Self::from_model_id(
+                    model_id,
+                    silent,
+                    &token,
                     revision.clone(),
-                ));
+                    offline,
+                )?),
+                transformer: Box::new(Self::from_model_id(
+                    transformer_model_id,
+                    silent,
+                    &token,
+                    revision,
+                    offline,
+                )?),
+            }),
+        }
+    }
 
-                Ok(Self::ApiWithTransformer {
-                    base: Box::new(api),
-                    transformer: Box::new(transformer_api),
-                })
-            }
+    /// Load the files of a model from a local directory if `model_id` is one, or else from the Hugging Face Hub,
+    /// or its local cache if `offline`.
+    fn from_model_id(
+        model_id: &str,
+        silent: bool,
+        token: &TokenSource,
+        revision: Option<String>,
+        offline: bool,
+    ) -> anyhow::Result<Self> {
+        if Path::new(model_id).is_dir() {
+            return Self::local(PathBuf::from(model_id));
         }
+        if offline {
+            let revision = revision.as_deref().unwrap_or("main");
+            return Self::local(cached_snapshot(model_id, revision)?);
+        }
+        let api_builder = ApiBuilder::new()
+            .with_progress(!silent)
+            .with_token(get_token(token)?)
+            .build()?;
+        let revision = revision.unwrap_or("main".to_string());
+        let api = api_builder.repo(Repo::with_revision(
+            model_id.to_string(),
+            RepoType::Model,
+            revision,
+        ));
+        Ok(Self::Api(Box::new(api)))
+    }
+
+    fn local(dir: PathBuf) -> anyhow::Result<Self> {
+        if !dir.is_dir() {
+            anyhow::bail!("Model directory `{}` does not exist.", dir.display());
+        }
+        Ok(Self::Local(dir))
     }
 
     pub fn list_files(&mut self) -> anyhow::Result<Vec<String>> {
         match self {
-            Self::Api(api)
-            | Self::ApiWithTransformer {
-                base: api,
-                transformer: _,
-            } => api
+            Self::Api(api) => api
                 .info()
                 .map(|repo| {
                     repo.siblings
@@ -159,6 +309,16 @@ impl<'a> FileLoader<'a> {
                         .collect::<Vec<String>>()
                 })
                 .map_err(|e| anyhow::Error::msg(e.to_string())),
+            Self::Local(dir) => {
+                let mut files = Vec::new();
+                list_local_files(dir, "", &mut files)?;
+                files.sort();
+                Ok(files)
+            }
+            Self::WithTransformer {
+                base,
+                transformer: _,
+            } => base.list_files(),
             Self::Dduf(dduf) => (0..dduf.len())
                 .map(|i| {
                     dduf.by_index(i)
@@ -169,23 +329,13 @@ impl<'a> FileLoader<'a> {
         }
     }
 
-    pub fn list_transformer_files(&self) -> anyhow::Result<Option<Vec<String>>> {
+    pub fn list_transformer_files(&mut self) -> anyhow::Result<Option<Vec<String>>> {
         match self {
-            Self::Api(_) | Self::Dduf(_) => Ok(None),
-
-            Self::ApiWithTransformer {
+            Self::Api(_) | Self::Local(_) | Self::Dduf(_) => Ok(None),
+            Self::WithTransformer {
                 base: _,
-                transformer: api,
-            } => api
-                .info()
-                .map(|repo| {
-                    repo.siblings
-                        .iter()
-                        .map(|x| x.rfilename.clone())
-                        .collect::<Vec<String>>()
-                })
-                .map(Some)
-                .map_err(|e| anyhow::Error::msg(e.to_string())),
+                transformer,
+            } => transformer.list_files().map(Some),
         }
     }
 
@@ -195,35 +345,40 @@ impl<'a> FileLoader<'a> {
     /// - For non-DDUF model sources, a path is returned
     /// - File data should be read with `read_to_string`
     pub fn read_file(&mut self, name: &str, from_transformer: bool) -> anyhow::Result<FileData> {
-        if from_transformer && !matches!(self, Self::ApiWithTransformer { .. }) {
-            anyhow::bail!("This model source has no transformer files.")
-        }
-
         match (self, from_transformer) {
-            (Self::Api(api), false)
-            | (
-                Self::ApiWithTransformer {
-                    base: api,
-                    transformer: _,
+            (
+                Self::WithTransformer {
+                    base: _,
+                    transformer,
                 },
-                false,
-            ) => Ok(FileData::Path(
-                api.get(name)
-                    .map_err(|e| anyhow::Error::msg(e.to_string()))?,
-            )),
+                true,
+            ) => transformer.read_file(name, false),
+            (_, true) => anyhow::bail!("This model source has no transformer files."),
             (
-                Self::ApiWithTransformer {
-                    base: api,
+                Self::WithTransformer {
+                    base,
                     transformer: _,
                 },
-                true,
-            ) => Ok(FileData::Path(
+                false,
+            ) => base.read_file(name, false),
+            (Self::Api(api), false) => Ok(FileData::Path(
                 api.get(name)
                     .map_err(|e| anyhow::Error::msg(e.to_string()))?,
             )),
-            (Self::Api(_), true) => anyhow::bail!("This model source has no transformer files."),
-            (Self::Dduf(dduf), _) => {
+            (Self::Local(dir), false) => {
+                let path = dir.join(name);
+                if !path.is_file() {
+                    anyhow::bail!("File `{name}` does not exist in `{}`.", dir.display());
+                }
+                Ok(FileData::Path(path))
+            }
+            (Self::Dduf(dduf), false) => {
                 let file = dduf.by_name(name)?;
+                if file.compression() != CompressionMethod::Stored {
+                    anyhow::bail!(
+                        "File `{name}` is compressed in the DDUF file, DDUF files must store files uncompressed."
+                    );
+                }
                 let start = file.data_start() as usize;
                 let len = file.size() as usize;
                 let end = start + len;
@@ -233,6 +388,24 @@ impl<'a> FileLoader<'a> {
         }
     }
 
+    /// The files of a DDUF archive, as stored in it.
+    pub fn dduf_entries(&mut self) -> anyhow::Result<Vec<DdufEntry>> {
+        let Self::Dduf(dduf) = self else {
+            anyhow::bail!("expected dduf model source!");
+        };
+        (0..dduf.len())
+            .map(|i| {
+                let file = dduf.by_index_raw(i)?;
+                Ok(DdufEntry {
+                    name: file.name().to_string(),
+                    stored: file.compression() == CompressionMethod::Stored,
+                    data_start: file.data_start(),
+                    size: file.compressed_size(),
+                })
+            })
+            .collect()
+    }
+
     /// Read a file, always returning owned data.
     ///
     /// - If loading from a DDUF file, this copies the file data.
@@ -243,7 +416,7 @@ impl<'a> FileLoader<'a> {
         name: &str,
         from_transformer: bool,
     ) -> anyhow::Result<FileData> {
-        if matches!(self, Self::Api(_) | Self::ApiWithTransformer { .. }) {
+        if !matches!(self, Self::Dduf(_)) {
             return self.read_file(name, from_transformer);
         }
 
@@ -258,6 +431,25 @@ impl<'a> FileLoader<'a> {
     }
 }
 
+/// List the files of `dir` recursively, relative to the root of the model with `/` separators, skipping hidden
+/// files and directories such as `.git` or `.cache`.
+fn list_local_files(dir: &Path, prefix: &str, files: &mut Vec<String>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        // Follow symlinks, as in snapshots of the Hugging Face cache.
+        if entry.path().is_dir() {
+            list_local_files(&entry.path(), &format!("{prefix}{name}/"), files)?;
+        } else {
+            files.push(format!("{prefix}{name}"));
+        }
+    }
+    Ok(())
+}
+
 pub enum FileData {
     Path(PathBuf),
     Dduf {
@@ -294,7 +486,7 @@ impl FileData {
                 start,
                 end,
             } => {
-                let ModelSource::Dduf { file, name: _ } = src else {
+                let ModelSource::Dduf { file, name: _ } = src.base_source() else {
                     anyhow::bail!("expected dduf model source!");
                 };
                 Ok(String::from_utf8(file.get_ref()[*start..*end].to_vec())?)
@@ -303,6 +495,24 @@ impl FileData {
         }
     }
 
+    /// Read the bytes of the file, for example to copy it.
+    pub fn read_bytes(&self, src: &ModelSource) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::Path(p) => Ok(fs::read(p)?),
+            Self::Dduf {
+                name: _,
+                start,
+                end,
+            } => {
+                let ModelSource::Dduf { file, name: _ } = src.base_source() else {
+                    anyhow::bail!("expected dduf model source!");
+                };
+                Ok(file.get_ref()[*start..*end].to_vec())
+            }
+            Self::DdufOwned { name: _, data } => Ok(data.clone()),
+        }
+    }
+
     pub fn read_to_string_owned(&self) -> anyhow::Result<String> {
         match self {
             Self::Path(p) => Ok(fs::read_to_string(p)?),
@@ -325,3 +535,128 @@ impl FileData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new empty directory for the test `name`, to be removed by the test.
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("diffusion-rs-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_files(dir: &Path, files: &[&str]) {
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "{}").unwrap();
+        }
+    }
+
+    #[test]
+    fn local_list_files() {
+        let dir = tmp_dir("list_files");
+        write_files(
+            &dir,
+            &[
+                "vae/config.json",
+                "model_index.json",
+                "transformer/diffusion_pytorch_model.safetensors",
+                ".gitattributes",
+                ".cache/huggingface/download/model_index.json.metadata",
+                "vae/.hidden",
+            ],
+        );
+        let mut loader = FileLoader::local(dir.clone()).unwrap();
+        assert_eq!(
+            loader.list_files().unwrap(),
+            [
+                "model_index.json",
+                "transformer/diffusion_pytorch_model.safetensors",
+                "vae/config.json",
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn local_read_file() {
+        let dir = tmp_dir("read_file");
+        write_files(&dir, &["vae/config.json"]);
+        let mut loader = FileLoader::local(dir.clone()).unwrap();
+        let FileData::Path(path) = loader.read_file("vae/config.json", false).unwrap() else {
+            panic!("expected a path");
+        };
+        assert_eq!(path, dir.join("vae/config.json"));
+
+        let err = loader.read_file("vae/missing.json", false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "File `vae/missing.json` does not exist in `{}`.",
+                dir.display()
+            )
+        );
+        assert!(loader.read_file("vae/config.json", true).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn model_id_of_local_dir() {
+        let dir = tmp_dir("model_id");
+        // A local directory is read as is, without the API even when online.
+        let token = TokenSource::None;
+        for offline in [false, true] {
+            let loader =
+                FileLoader::from_model_id(&dir.display().to_string(), true, &token, None, offline)
+                    .unwrap();
+            assert!(matches!(loader, FileLoader::Local(ref path) if *path == dir));
+        }
+
+        let mut source = ModelSource::from_model_id(dir.display());
+        let loader = FileLoader::from_model_source(&mut source, true, token, None, false).unwrap();
+        assert!(matches!(loader, FileLoader::Local(ref path) if *path == dir));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn override_transformer_of_local_dir() {
+        let dir = tmp_dir("override_transformer");
+        let transformer = dir.join("transformer_model");
+        fs::create_dir_all(&transformer).unwrap();
+        let mut source = ModelSource::local_dir(&dir)
+            .override_transformer_model_id(transformer.display())
+            .unwrap();
+        let ModelSource::ModelIdWithTransformer {
+            model_id,
+            transformer_model_id,
+        } = &source
+        else {
+            panic!("expected a transformer override");
+        };
+        assert_eq!(model_id, &dir.display().to_string());
+        assert_eq!(transformer_model_id, &transformer.display().to_string());
+
+        let loader =
+            FileLoader::from_model_source(&mut source, true, TokenSource::None, None, false)
+                .unwrap();
+        let FileLoader::WithTransformer {
+            base,
+            transformer: transformer_loader,
+        } = loader
+        else {
+            panic!("expected a loader with a transformer");
+        };
+        assert!(matches!(*base, FileLoader::Local(ref path) if *path == dir));
+        assert!(matches!(*transformer_loader, FileLoader::Local(ref path) if *path == transformer));
+
+        assert!(ModelSource::local_dir(&dir)
+            .override_component_file("transformer", "model.gguf")
+            .override_transformer_model_id(transformer.display())
+            .is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,10 @@
 //! A `VarBuilder` is used to retrieve variables used by a model. These variables can either come
 //! from a pre-trained checkpoint, e.g. using `VarBuilder::from_mmaped_safetensors`, or initialized
 //! for training, e.g. using `VarBuilder::from_varmap`.
+use crate::core::quantized::QTensor;
 use crate::core::{DType, Device, Error, Result, Shape, Tensor};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 /// A structure used to retrieve variables, these variables can either come from storage or be
 /// generated via some form of initialization.
@@ -60,6 +61,16 @@ pub trait Backend: Send + Sync {
     fn get_unchecked(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor>;
 
     fn contains_tensor(&self, name: &str) -> bool;
+
+    /// Retrieve a quantized tensor based on the name, if the backend stores it quantized.
+    fn get_qtensor(&self, _name: &str) -> Option<Arc<QTensor>> {
+        None
+    }
+
+    /// The dtype a tensor is stored with, if known.
+    fn get_dtype(&self, _name: &str) -> Option<DType> {
+        None
+    }
 }
 
 pub trait SimpleBackend: Send + Sync {
@@ -77,6 +88,16 @@ pub trait SimpleBackend: Send + Sync {
     fn get_unchecked(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor>;
 
     fn contains_tensor(&self, name: &str) -> bool;
+
+    /// Retrieve a quantized tensor based on the name, if the backend stores it quantized.
+    fn get_qtensor(&self, _name: &str) -> Option<Arc<QTensor>> {
+        None
+    }
+
+    /// The dtype a tensor is stored with, if known.
+    fn get_dtype(&self, _name: &str) -> Option<DType> {
+        None
+    }
 }
 
 impl Backend for Box<dyn SimpleBackend + '_> {
@@ -99,6 +120,14 @@ impl Backend for Box<dyn SimpleBackend + '_> {
     fn contains_tensor(&self, name: &str) -> bool {
         self.as_ref().contains_tensor(name)
     }
+
+    fn get_qtensor(&self, name: &str) -> Option<Arc<QTensor>> {
+        self.as_ref().get_qtensor(name)
+    }
+
+    fn get_dtype(&self, name: &str) -> Option<DType> {
+        self.as_ref().get_dtype(name)
+    }
 }
 
 impl<B: Backend> VarBuilderArgs<'_, B> {
@@ -195,6 +224,20 @@ impl<B: Backend> VarBuilderArgs<'_, B> {
         self.data.backend.contains_tensor(&path)
     }
 
+    /// Retrieve the quantized tensor associated with the given name at the current path, if the backend stores
+    /// it quantized. Its device is not changed.
+    pub fn get_qtensor(&self, name: &str) -> Option<Arc<QTensor>> {
+        let path = self.path(name);
+        self.data.backend.get_qtensor(&path)
+    }
+
+    /// The dtype the tensor associated with the given name at the current path is stored with, if the backend
+    /// knows it. Tensors are converted to the dtype of the `VarBuilder` when retrieved.
+    pub fn get_dtype(&self, name: &str) -> Option<DType> {
+        let path = self.path(name);
+        self.data.backend.get_dtype(&path)
+    }
+
     /// Retrieve the tensor associated with the given name at the current path.
     pub fn get_with_hints<S: Into<Shape>>(
         &self,
@@ -325,6 +368,50 @@ impl SimpleBackend for HashMap<String, Tensor> {
     fn contains_tensor(&self, name: &str) -> bool {
         self.contains_key(name)
     }
+
+    fn get_dtype(&self, name: &str) -> Option<DType> {
+        self.get(name).map(Tensor::dtype)
+    }
+}
+
+impl SimpleBackend for HashMap<String, Arc<QTensor>> {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        _: crate::nn::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let tensor = self.get_unchecked(name, dtype, dev)?;
+        if tensor.shape() != &s {
+            Err(crate::core::Error::UnexpectedShape {
+                msg: format!("shape mismatch for {name}"),
+                expected: s,
+                got: tensor.shape().clone(),
+            }
+            .bt())?
+        }
+        Ok(tensor)
+    }
+
+    fn get_unchecked(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor> {
+        let qtensor = self.get(name).ok_or_else(|| {
+            Error::CannotFindTensor {
+                path: name.to_string(),
+            }
+            .bt()
+        })?;
+        qtensor.dequantize(dev)?.to_dtype(dtype)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.contains_key(name)
+    }
+
+    fn get_qtensor(&self, name: &str) -> Option<Arc<QTensor>> {
+        self.get(name).cloned()
+    }
 }
 
 impl SimpleBackend for crate::core::safetensors::MmapedSafetensors {
@@ -355,6 +442,10 @@ impl SimpleBackend for crate::core::safetensors::MmapedSafetensors {
     fn contains_tensor(&self, name: &str) -> bool {
         self.get(name).is_ok()
     }
+
+    fn get_dtype(&self, name: &str) -> Option<DType> {
+        DType::try_from(self.get(name).ok()?.dtype()).ok()
+    }
 }
 
 impl SimpleBackend for crate::core::safetensors::BufferedSafetensors {
@@ -385,6 +476,10 @@ impl SimpleBackend for crate::core::safetensors::BufferedSafetensors {
     fn contains_tensor(&self, name: &str) -> bool {
         self.get(name).is_ok()
     }
+
+    fn get_dtype(&self, name: &str) -> Option<DType> {
+        DType::try_from(self.get(name).ok()?.dtype()).ok()
+    }
 }
 
 impl SimpleBackend for crate::core::safetensors::SliceSafetensors<'_> {
@@ -415,6 +510,78 @@ impl SimpleBackend for crate::core::safetensors::SliceSafetensors<'_> {
     fn contains_tensor(&self, name: &str) -> bool {
         self.get(name).is_ok()
     }
+
+    fn get_dtype(&self, name: &str) -> Option<DType> {
+        DType::try_from(self.get(name).ok()?.dtype()).ok()
+    }
+}
+
+/// The tensors retrieved through a `VarBuilder` returned by [`VarBuilder::recording`], by name.
+#[derive(Clone, Default)]
+pub struct TensorRecord(Arc<Mutex<HashMap<String, Tensor>>>);
+
+impl TensorRecord {
+    fn insert(&self, name: &str, tensor: &Tensor) {
+        self.0
+            .lock()
+            .expect("Could not lock tensor record!")
+            .insert(name.to_string(), tensor.clone());
+    }
+
+    /// Forget the tensors for which `f` returns false, for example those of layers which are saved separately.
+    pub fn retain(&self, mut f: impl FnMut(&str) -> bool) {
+        self.0
+            .lock()
+            .expect("Could not lock tensor record!")
+            .retain(|name, _| f(name));
+    }
+
+    /// The recorded tensors, by name.
+    pub fn tensors(&self) -> HashMap<String, Tensor> {
+        self.0
+            .lock()
+            .expect("Could not lock tensor record!")
+            .clone()
+    }
+}
+
+/// A backend recording the tensors retrieved from another backend.
+struct Recording<'a> {
+    inner: Arc<Box<dyn SimpleBackend + 'a>>,
+    record: TensorRecord,
+}
+
+impl SimpleBackend for Recording<'_> {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::nn::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let tensor = self.inner.get(s, name, h, dtype, dev)?;
+        self.record.insert(name, &tensor);
+        Ok(tensor)
+    }
+
+    fn get_unchecked(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor> {
+        let tensor = self.inner.get_unchecked(name, dtype, dev)?;
+        self.record.insert(name, &tensor);
+        Ok(tensor)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.inner.contains_tensor(name)
+    }
+
+    fn get_qtensor(&self, name: &str) -> Option<Arc<QTensor>> {
+        self.inner.get_qtensor(name)
+    }
+
+    fn get_dtype(&self, name: &str) -> Option<DType> {
+        self.inner.get_dtype(name)
+    }
 }
 
 impl<'a> VarBuilder<'a> {
@@ -441,6 +608,27 @@ impl<'a> VarBuilder<'a> {
         }
     }
 
+    /// Returns a `VarBuilder` retrieving tensors like this one, recording the tensors it retrieves so that the
+    /// model can be saved after loading it. The record holds references to the tensors, which are shared with the
+    /// model as long as it does not copy them. Quantized tensors retrieved with [`VarBuilder::get_qtensor`] are
+    /// not recorded.
+    pub fn recording(&self) -> (Self, TensorRecord) {
+        let record = TensorRecord::default();
+        let backend = Recording {
+            inner: self.data.backend.clone(),
+            record: record.clone(),
+        };
+        let vb = Self::from_backend(Box::new(backend), self.data.dtype, self.data.device.clone());
+        (
+            Self {
+                path: self.path.clone(),
+                dtype: self.dtype,
+                ..vb
+            },
+            record,
+        )
+    }
+
     /// Initializes a `VarBuilder` that uses zeros for any tensor.
     pub fn zeros(dtype: DType, dev: &Device) -> Self {
         Self::from_backend(Box::new(Zeros), dtype, dev.clone())
@@ -452,6 +640,13 @@ impl<'a> VarBuilder<'a> {
         Self::from_backend(Box::new(ts), dtype, dev.clone())
     }
 
+    /// Initializes a `VarBuilder` that retrieves quantized tensors stored in a hashtable, for example from a GGUF
+    /// file. The tensors are dequantized by [`VarBuilder::get`], or can be retrieved quantized with
+    /// [`VarBuilder::get_qtensor`].
+    pub fn from_qtensors(ts: HashMap<String, Arc<QTensor>>, dtype: DType, dev: &Device) -> Self {
+        Self::from_backend(Box::new(ts), dtype, dev.clone())
+    }
+
     /// Initializes a `VarBuilder` that retrieves tensors stored in a collection of safetensors
     /// files.
     ///
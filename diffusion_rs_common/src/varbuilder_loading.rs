@@ -110,7 +110,7 @@ trait LoadTensors {
         {
             "safetensors" => match path {
                 FileData::Dduf { name: _, start, end } => {
-                    let ModelSource::Dduf { file, name: _ } = &*src else {
+                    let ModelSource::Dduf { file, name: _ } = src.base_source() else {
                         crate::bail!("expected dduf model source!");
                     };
                     Box::new(BytesSafetensorBackend(BytesSafetensors::new(&file.get_ref()[*start..*end])?))
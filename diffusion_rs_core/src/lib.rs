@@ -5,41 +5,52 @@
 //! ```rust,no_run
 //! use std::time::Instant;
 //!
-//! use diffusion_rs_core::{DiffusionGenerationParams, ModelSource, ModelDType, Offloading, Pipeline, TokenSource};
+//! use diffusion_rs_core::{DiffusionGenerationParams, LoadOptions, ModelSource, Pipeline};
 //!
 //! let pipeline = Pipeline::load(
 //!     ModelSource::dduf("FLUX.1-dev-Q4-bnb.dduf")?,
-//!     true,
-//!     TokenSource::CacheToken,
-//!     None,
-//!     None,
-//!     &ModelDType::Auto,
+//!     LoadOptions {
+//!         silent: true,
+//!         ..Default::default()
+//!     },
 //! )?;
 //!
 //! let start = Instant::now();
 //!
-//! let images = pipeline.forward(
+//! let output = pipeline.forward(
 //!     vec!["Draw a picture of a sunrise.".to_string()],
 //!     DiffusionGenerationParams {
 //!         height: 720,
 //!         width: 1280,
 //!         num_steps: 50,
 //!         guidance_scale: 3.5,
+//!         ..Default::default()
 //!     },
 //! )?;
 //!
 //! let end = Instant::now();
 //! println!("Took: {:.2}s", end.duration_since(start).as_secs_f32());
 //!
-//! images[0].save("image.png")?;
+//! println!("Seed: {}", output.seeds[0]);
+//!
+//! output.images[0].save("image.png")?;
 //!
 //! # Ok::<(), anyhow::Error>(())
 //! ```
 
 mod models;
 mod pipelines;
+#[cfg(test)]
+mod test_utils;
 mod util;
 
+pub use diffusion_rs_backend::IsqType;
 pub use diffusion_rs_common::{ModelSource, TokenSource};
-pub use pipelines::{DiffusionGenerationParams, Offloading, Pipeline};
+pub use pipelines::{
+    pack_dduf, validate_dduf, CancellationToken, DdufReport, DenoiseFn, DiffusionGenerationOutput,
+    DiffusionGenerationParams, GenerationCancelled, LoadOptions, ModelFileError, Offloading,
+    OutpaintPadding, OutputType, Pipeline, Preview, PreviewCallback, PreviewMode,
+    ProgressBarCallback, PromptEmbeds, SampleContext, Sampler, SamplerType, SchedulerOverrides,
+    SigmaSchedule, StepCallback,
+};
 pub use util::{ModelDType, TryIntoDType};
@@ -1,9 +1,14 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
+use std::sync::Arc;
+
+use diffusion_rs_backend::QuantMethod;
 use diffusion_rs_common::core::{DType, Device, IndexOp, Result, Tensor, D};
-use diffusion_rs_common::nn::{ops::sigmoid, Module};
+use diffusion_rs_common::nn::{ops::sigmoid, Embedding, Module};
 use serde::Deserialize;
 
+use crate::models::{QuantizedModel, QuantizedModelLayer};
+
 #[derive(Debug, Clone, Copy, Deserialize)]
 pub enum Activation {
     #[serde(rename = "quick_gelu")]
@@ -33,8 +38,8 @@ pub struct ClipTextConfig {
 // TODO rewrite to be more similar to https://github.com/huggingface/transformers/blob/f6fa0f0bf0796ac66f201f23bdb8585de1609add/src/transformers/models/clip/modeling_clip.py#L142
 #[derive(Clone, Debug)]
 struct ClipTextEmbeddings {
-    token_embedding: diffusion_rs_common::nn::Embedding,
-    position_embedding: diffusion_rs_common::nn::Embedding,
+    token_embedding: Embedding,
+    position_embedding: Embedding,
     position_ids: Tensor,
 }
 
@@ -72,10 +77,10 @@ impl Module for ClipTextEmbeddings {
 
 #[derive(Clone, Debug)]
 struct ClipAttention {
-    k_proj: diffusion_rs_common::nn::Linear,
-    v_proj: diffusion_rs_common::nn::Linear,
-    q_proj: diffusion_rs_common::nn::Linear,
-    out_proj: diffusion_rs_common::nn::Linear,
+    k_proj: Arc<dyn QuantMethod>,
+    v_proj: Arc<dyn QuantMethod>,
+    q_proj: Arc<dyn QuantMethod>,
+    out_proj: Arc<dyn QuantMethod>,
     head_dim: usize,
     scale: f64,
     num_attention_heads: usize,
@@ -85,11 +90,14 @@ impl ClipAttention {
     fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
         let projection_dim = c.projection_dim;
         let num_attention_heads = c.num_attention_heads;
-        let k_proj = diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("k_proj"))?;
-        let v_proj = diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("v_proj"))?;
-        let q_proj = diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("q_proj"))?;
+        let k_proj =
+            diffusion_rs_backend::linear(projection_dim, projection_dim, &None, vs.pp("k_proj"))?;
+        let v_proj =
+            diffusion_rs_backend::linear(projection_dim, projection_dim, &None, vs.pp("v_proj"))?;
+        let q_proj =
+            diffusion_rs_backend::linear(projection_dim, projection_dim, &None, vs.pp("q_proj"))?;
         let out_proj =
-            diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("out_proj"))?;
+            diffusion_rs_backend::linear(projection_dim, projection_dim, &None, vs.pp("out_proj"))?;
         let head_dim = projection_dim / num_attention_heads;
         let scale = (head_dim as f64).powf(-0.5);
 
@@ -114,18 +122,18 @@ impl ClipAttention {
         let in_dtype = xs.dtype();
         let (bsz, seq_len, projection_dim) = xs.dims3()?;
 
-        let query_states = (self.q_proj.forward(xs)? * self.scale)?;
+        let query_states = (self.q_proj.forward_autocast(xs)? * self.scale)?;
         let proj_shape = (bsz * self.num_attention_heads, seq_len, self.head_dim);
         let query_states = self
             .shape(&query_states, seq_len, bsz)?
             .reshape(proj_shape)?
             .to_dtype(DType::F32)?;
         let key_states = self
-            .shape(&self.k_proj.forward(xs)?, seq_len, bsz)?
+            .shape(&self.k_proj.forward_autocast(xs)?, seq_len, bsz)?
             .reshape(proj_shape)?
             .to_dtype(DType::F32)?;
         let value_states = self
-            .shape(&self.v_proj.forward(xs)?, seq_len, bsz)?
+            .shape(&self.v_proj.forward_autocast(xs)?, seq_len, bsz)?
             .reshape(proj_shape)?
             .to_dtype(DType::F32)?;
         let attn_weights = query_states.matmul(&key_states.transpose(1, 2)?)?;
@@ -148,21 +156,31 @@ impl ClipAttention {
             .reshape((bsz, self.num_attention_heads, seq_len, self.head_dim))?
             .transpose(1, 2)?
             .reshape((bsz, seq_len, projection_dim))?;
-        self.out_proj.forward(&attn_output)
+        self.out_proj.forward_autocast(&attn_output)
     }
 }
 
 #[derive(Clone, Debug)]
 struct ClipMlp {
-    fc1: diffusion_rs_common::nn::Linear,
-    fc2: diffusion_rs_common::nn::Linear,
+    fc1: Arc<dyn QuantMethod>,
+    fc2: Arc<dyn QuantMethod>,
     activation: Activation,
 }
 
 impl ClipMlp {
     fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
-        let fc1 = diffusion_rs_common::linear(c.projection_dim, c.intermediate_size, vs.pp("fc1"))?;
-        let fc2 = diffusion_rs_common::linear(c.intermediate_size, c.projection_dim, vs.pp("fc2"))?;
+        let fc1 = diffusion_rs_backend::linear(
+            c.projection_dim,
+            c.intermediate_size,
+            &None,
+            vs.pp("fc1"),
+        )?;
+        let fc2 = diffusion_rs_backend::linear(
+            c.intermediate_size,
+            c.projection_dim,
+            &None,
+            vs.pp("fc2"),
+        )?;
 
         Ok(ClipMlp {
             fc1,
@@ -174,8 +192,8 @@ impl ClipMlp {
 
 impl ClipMlp {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
-        let xs = self.fc1.forward(xs)?;
-        self.fc2.forward(&self.activation.forward(&xs)?)
+        let xs = self.fc1.forward_autocast(xs)?;
+        self.fc2.forward_autocast(&self.activation.forward(&xs)?)
     }
 }
 
@@ -316,3 +334,71 @@ impl Module for ClipTextTransformer {
         Tensor::cat(&indices, 0)
     }
 }
+
+impl QuantizedModel for ClipTextTransformer {
+    fn match_devices_all_layers(&mut self, dev: &Device) -> Result<()> {
+        let embeddings = &mut self.embeddings;
+        embeddings.token_embedding = Embedding::new(
+            embeddings.token_embedding.embeddings().to_device(dev)?,
+            embeddings.token_embedding.hidden_size(),
+        );
+        embeddings.position_embedding = Embedding::new(
+            embeddings.position_embedding.embeddings().to_device(dev)?,
+            embeddings.position_embedding.hidden_size(),
+        );
+        embeddings.position_ids = embeddings.position_ids.to_device(dev)?;
+        for layer in &mut self.encoder.layers {
+            layer.layer_norm1 = layer.layer_norm1.to_device(dev)?;
+            layer.layer_norm2 = layer.layer_norm2.to_device(dev)?;
+        }
+        self.final_layer_norm = self.final_layer_norm.to_device(dev)?;
+        self.device = dev.clone();
+        Ok(())
+    }
+
+    fn aggregate_layers(&mut self) -> Result<Vec<QuantizedModelLayer<'_>>> {
+        Ok(self
+            .encoder
+            .layers
+            .iter_mut()
+            .map(|layer| {
+                QuantizedModelLayer(vec![
+                    &mut layer.self_attn.q_proj,
+                    &mut layer.self_attn.k_proj,
+                    &mut layer.self_attn.v_proj,
+                    &mut layer.self_attn.out_proj,
+                    &mut layer.mlp.fc1,
+                    &mut layer.mlp.fc2,
+                ])
+            })
+            .collect())
+    }
+
+    fn named_layers(&mut self) -> Result<Vec<(String, &mut Arc<dyn QuantMethod>)>> {
+        let mut layers = Vec::new();
+        for (i, layer) in self.encoder.layers.iter_mut().enumerate() {
+            let prefix = format!("encoder.layers.{i}");
+            layers.extend([
+                (
+                    format!("{prefix}.self_attn.q_proj"),
+                    &mut layer.self_attn.q_proj,
+                ),
+                (
+                    format!("{prefix}.self_attn.k_proj"),
+                    &mut layer.self_attn.k_proj,
+                ),
+                (
+                    format!("{prefix}.self_attn.v_proj"),
+                    &mut layer.self_attn.v_proj,
+                ),
+                (
+                    format!("{prefix}.self_attn.out_proj"),
+                    &mut layer.self_attn.out_proj,
+                ),
+                (format!("{prefix}.mlp.fc1"), &mut layer.mlp.fc1),
+                (format!("{prefix}.mlp.fc2"), &mut layer.mlp.fc2),
+            ]);
+        }
+        Ok(layers)
+    }
+}
@@ -926,4 +926,88 @@ impl QuantizedModel for Flux {
         }
         Ok(layers)
     }
+
+    fn named_layers(&mut self) -> Result<Vec<(String, &mut Arc<dyn QuantMethod>)>> {
+        let mut layers = vec![
+            ("x_embedder".to_string(), &mut self.img_in),
+            ("context_embedder".to_string(), &mut self.txt_in),
+            (
+                "time_text_embed.timestep_embedder.linear_1".to_string(),
+                &mut self.time_in.in_layer,
+            ),
+            (
+                "time_text_embed.timestep_embedder.linear_2".to_string(),
+                &mut self.time_in.out_layer,
+            ),
+            (
+                "time_text_embed.text_embedder.linear_1".to_string(),
+                &mut self.vector_in.in_layer,
+            ),
+            (
+                "time_text_embed.text_embedder.linear_2".to_string(),
+                &mut self.vector_in.out_layer,
+            ),
+            (
+                "norm_out.linear".to_string(),
+                &mut self.final_layer.ada_ln_modulation,
+            ),
+            ("proj_out".to_string(), &mut self.final_layer.linear),
+        ];
+
+        if let Some(layer) = &mut self.guidance_in {
+            layers.push((
+                "time_text_embed.guidance_embedder.linear_1".to_string(),
+                &mut layer.in_layer,
+            ));
+            layers.push((
+                "time_text_embed.guidance_embedder.linear_2".to_string(),
+                &mut layer.out_layer,
+            ));
+        }
+
+        for (i, block) in self.double_blocks.iter_mut().enumerate() {
+            let prefix = format!("transformer_blocks.{i}");
+            layers.extend([
+                (format!("{prefix}.attn.to_q"), &mut block.img_attn.q),
+                (format!("{prefix}.attn.to_k"), &mut block.img_attn.k),
+                (format!("{prefix}.attn.to_v"), &mut block.img_attn.v),
+                (format!("{prefix}.attn.to_out.0"), &mut block.img_attn.proj),
+                (format!("{prefix}.ff.net.0.proj"), &mut block.img_mlp.lin1),
+                (format!("{prefix}.ff.net.2"), &mut block.img_mlp.lin2),
+                (format!("{prefix}.norm1.linear"), &mut block.img_mod.lin),
+                (format!("{prefix}.attn.add_q_proj"), &mut block.txt_attn.q),
+                (format!("{prefix}.attn.add_k_proj"), &mut block.txt_attn.k),
+                (format!("{prefix}.attn.add_v_proj"), &mut block.txt_attn.v),
+                (
+                    format!("{prefix}.attn.to_add_out"),
+                    &mut block.txt_attn.proj,
+                ),
+                (
+                    format!("{prefix}.ff_context.net.0.proj"),
+                    &mut block.txt_mlp.lin1,
+                ),
+                (
+                    format!("{prefix}.ff_context.net.2"),
+                    &mut block.txt_mlp.lin2,
+                ),
+                (
+                    format!("{prefix}.norm1_context.linear"),
+                    &mut block.txt_mod.lin,
+                ),
+            ]);
+        }
+
+        for (i, block) in self.single_blocks.iter_mut().enumerate() {
+            let prefix = format!("single_transformer_blocks.{i}");
+            layers.extend([
+                (format!("{prefix}.attn.to_q"), &mut block.q),
+                (format!("{prefix}.attn.to_k"), &mut block.k),
+                (format!("{prefix}.attn.to_v"), &mut block.v),
+                (format!("{prefix}.norm.linear"), &mut block.modulation.lin),
+                (format!("{prefix}.proj_mlp"), &mut block.proj_mlp),
+                (format!("{prefix}.proj_out"), &mut block.linear2),
+            ]);
+        }
+        Ok(layers)
+    }
 }
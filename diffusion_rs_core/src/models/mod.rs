@@ -6,9 +6,11 @@ mod vaes;
 use std::sync::Arc;
 
 pub use clip::{ClipTextConfig, ClipTextTransformer};
-use diffusion_rs_backend::QuantMethod;
+use diffusion_rs_backend::{IsqType, QuantMethod};
 use diffusion_rs_common::core::{Device, Result};
+use diffusion_rs_common::NiceProgressBar;
 pub use flux::{FluxConfig, FluxModel};
+use rayon::iter::{ParallelBridge, ParallelIterator};
 pub use t5::{T5Config, T5EncoderModel};
 
 pub(crate) use vaes::{dispatch_load_vae_model, VAEModel};
@@ -21,6 +23,8 @@ pub trait QuantizedModel {
     fn match_devices_all_layers(&mut self, dev: &Device) -> Result<()>;
     /// Return all linear layers.
     fn aggregate_layers(&mut self) -> Result<Vec<QuantizedModelLayer>>;
+    /// Return all linear layers along with their names in the model's checkpoint, without the `.weight` suffix.
+    fn named_layers(&mut self) -> Result<Vec<(String, &mut Arc<dyn QuantMethod>)>>;
     /// Cast all linear layers to the given device.
     fn to_device(&mut self, dev: &Device) -> Result<()> {
         let layers = self.aggregate_layers()?;
@@ -32,6 +36,25 @@ pub trait QuantizedModel {
         self.match_devices_all_layers(dev)?;
         Ok(())
     }
+    /// Quantize all linear layers in-situ to `dtype` in parallel, onto `dev`, and move the other weights to `dev`.
+    ///
+    /// Loading the model on the CPU and quantizing it onto the GPU avoids ever holding the unquantized linear
+    /// layers in GPU memory.
+    fn quantize(&mut self, dtype: IsqType, dev: &Device) -> Result<()> {
+        let layers = self
+            .aggregate_layers()?
+            .into_iter()
+            .flat_map(|layer| layer.0)
+            .collect::<Vec<_>>();
+        NiceProgressBar::<_, 'b'>(layers.into_iter(), "Quantizing layers")
+            .into_iter()
+            .par_bridge()
+            .try_for_each(|layer| -> Result<()> {
+                *layer = layer.apply_isq(dtype, dev)?;
+                Ok(())
+            })?;
+        self.match_devices_all_layers(dev)
+    }
     #[allow(unused)]
     fn total_size_in_bytes(&mut self) -> Result<usize> {
         let layers = self.aggregate_layers()?;
@@ -41,7 +41,7 @@ fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor>
 
 #[derive(Debug, Deserialize, Default, Clone, PartialEq)]
 pub struct ActivationWithOptionalGating {
-    gated: bool,
+    pub(crate) gated: bool,
     activation: diffusion_rs_common::nn::Activation,
 }
 
@@ -722,4 +722,44 @@ impl QuantizedModel for T5EncoderModel {
         }
         Ok(layers)
     }
+
+    fn named_layers(&mut self) -> Result<Vec<(String, &mut Arc<dyn QuantMethod>)>> {
+        let mut layers = Vec::new();
+        for (i, block) in self.encoder.block.iter_mut().enumerate() {
+            let prefix = format!("encoder.block.{i}.layer");
+            // Attention
+            let attn = &mut block.self_attn.self_attention;
+            layers.extend([
+                (format!("{prefix}.0.SelfAttention.q"), &mut attn.q),
+                (format!("{prefix}.0.SelfAttention.k"), &mut attn.k),
+                (format!("{prefix}.0.SelfAttention.v"), &mut attn.v),
+                (format!("{prefix}.0.SelfAttention.o"), &mut attn.o),
+            ]);
+            let ff_i = if let Some(layer) = &mut block.cross_attn {
+                let attn = &mut layer.cross_attention;
+                layers.extend([
+                    (format!("{prefix}.1.EncDecAttention.q"), &mut attn.q),
+                    (format!("{prefix}.1.EncDecAttention.k"), &mut attn.k),
+                    (format!("{prefix}.1.EncDecAttention.v"), &mut attn.v),
+                    (format!("{prefix}.1.EncDecAttention.o"), &mut attn.o),
+                ]);
+                2
+            } else {
+                1
+            };
+
+            // FF
+            let prefix = format!("{prefix}.{ff_i}.DenseReluDense");
+            if let Some(layer) = &mut block.ff.dense_act {
+                layers.push((format!("{prefix}.wi"), &mut layer.wi));
+                layers.push((format!("{prefix}.wo"), &mut layer.wo));
+            }
+            if let Some(layer) = &mut block.ff.gated_dense_act {
+                layers.push((format!("{prefix}.wi_0"), &mut layer.wi_0));
+                layers.push((format!("{prefix}.wi_1"), &mut layer.wi_1));
+                layers.push((format!("{prefix}.wo"), &mut layer.wo));
+            }
+        }
+        Ok(layers)
+    }
 }
@@ -98,17 +98,28 @@ impl AutoEncoderKl {
     }
 }
 
-impl VAEModel for AutoEncoderKl {
-    fn encode(&self, xs: &Tensor) -> Result<Tensor> {
+impl AutoEncoderKl {
+    /// The mean and log-variance of the latent distribution, concatenated along the channels.
+    fn moments(&self, xs: &Tensor) -> Result<Tensor> {
         let mut z = xs.apply(&self.encoder)?;
         if let Some(conv) = &self.quant_conv {
             z = z.apply(conv)?;
         }
-        z = z.apply(&self.reg)?;
+        Ok(z)
+    }
+}
+
+impl VAEModel for AutoEncoderKl {
+    fn encode(&self, xs: &Tensor) -> Result<Tensor> {
+        let z = self.moments(xs)?.apply(&self.reg)?;
         // (z - self.shift_factor)? * self.scale_factor
         Ok(z)
     }
 
+    fn encode_mode(&self, xs: &Tensor) -> Result<Tensor> {
+        Ok(self.moments(xs)?.chunk(2, 1)?[0].clone())
+    }
+
     fn decode(&self, xs: &Tensor) -> Result<Tensor> {
         // let xs = ((xs / self.scale_factor)? + self.shift_factor)?;
         let mut z = xs.apply(&self.decoder)?;
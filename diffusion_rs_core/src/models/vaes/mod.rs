@@ -7,7 +7,9 @@ use diffusion_rs_common::{
 };
 use serde::Deserialize;
 
-use diffusion_rs_common::{from_mmaped_safetensors, FileData, VarBuilder};
+use crate::pipelines::recording;
+
+use diffusion_rs_common::{from_mmaped_safetensors, FileData, TensorRecord, VarBuilder};
 
 mod autoencoder_kl;
 mod vae;
@@ -18,6 +20,10 @@ pub(crate) trait VAEModel: Send + Sync {
     /// `(x - vae.shift_factor())? * self.scale_factor()`
     fn encode(&self, xs: &Tensor) -> Result<Tensor>;
 
+    /// Like `encode`, but returns the mode of the latent distribution instead of a sample of it, so that the
+    /// result is deterministic.
+    fn encode_mode(&self, xs: &Tensor) -> Result<Tensor>;
+
     /// This function *does not* handle scaling the tensor! If you want to do this, apply the following to the input:
     /// `(x / vae.scale_factor())? + self.shift_factor()`
     fn decode(&self, xs: &Tensor) -> Result<Tensor>;
@@ -42,6 +48,7 @@ fn load_autoencoder_kl(
     Ok(Arc::new(AutoEncoderKl::new(&cfg, vb)?))
 }
 
+/// Load the VAE, with a record of its tensors to save it if `allow_saving`.
 pub(crate) fn dispatch_load_vae_model(
     cfg_json: &FileData,
     safetensor_files: Vec<FileData>,
@@ -49,7 +56,8 @@ pub(crate) fn dispatch_load_vae_model(
     dtype: DType,
     silent: bool,
     source: Arc<ModelSource>,
-) -> anyhow::Result<Arc<dyn VAEModel>> {
+    allow_saving: bool,
+) -> anyhow::Result<(Arc<dyn VAEModel>, Option<TensorRecord>)> {
     let vb = from_mmaped_safetensors(
         safetensor_files,
         Some(dtype),
@@ -57,10 +65,12 @@ pub(crate) fn dispatch_load_vae_model(
         silent,
         source.clone(),
     )?;
+    let (vb, record) = recording(vb, allow_saving);
 
     let VaeConfigShim { name } = serde_json::from_str(&cfg_json.read_to_string(&source)?)?;
-    match name.as_str() {
-        "AutoencoderKL" => load_autoencoder_kl(cfg_json, vb, source),
+    let model = match name.as_str() {
+        "AutoencoderKL" => load_autoencoder_kl(cfg_json, vb, source)?,
         other => anyhow::bail!("Unexpected VAE type `{other:?}`."),
-    }
+    };
+    Ok((model, record))
 }
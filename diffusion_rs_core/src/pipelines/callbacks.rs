@@ -0,0 +1,174 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use diffusion_rs_common::core::{Error, Tensor};
+use image::DynamicImage;
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// Observer of the denoising loop, called after every step.
+///
+/// This is implemented for closures with a matching signature.
+pub trait StepCallback: Send + Sync {
+    /// - `step`: the step which just finished, starting at 1.
+    /// - `total`: the number of steps which will be run.
+    /// - `timestep`: the timestep (sigma) which the latents are now at.
+    /// - `latents`: the current latents, in the model's internal layout (packed for FLUX).
+    fn on_step(&self, step: usize, total: usize, timestep: f64, latents: &Tensor);
+}
+
+impl<F> StepCallback for F
+where
+    F: Fn(usize, usize, f64, &Tensor) + Send + Sync,
+{
+    fn on_step(&self, step: usize, total: usize, timestep: f64, latents: &Tensor) {
+        self(step, total, timestep, latents)
+    }
+}
+
+impl Debug for dyn StepCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StepCallback")
+    }
+}
+
+/// Renders a progress bar for the denoising loop. This is the default step callback.
+#[derive(Default)]
+pub struct ProgressBarCallback {
+    bar: Mutex<Option<ProgressBar>>,
+}
+
+impl StepCallback for ProgressBarCallback {
+    fn on_step(&self, step: usize, total: usize, _timestep: f64, _latents: &Tensor) {
+        let mut guard = self.bar.lock().expect("Could not lock progress bar!");
+        if step == 1 {
+            // Drop the bar of a previous, cancelled generation.
+            *guard = None;
+        }
+        let bar = guard.get_or_insert_with(|| {
+            let bar = ProgressBar::new(total as u64);
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "Denoise loop: [{elapsed_precise}] [{bar:40.green/green}] {pos}/{len} ({eta})",
+                    )
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            bar
+        });
+        bar.set_position(step as u64);
+        if step == total {
+            bar.finish();
+            *guard = None;
+        }
+    }
+}
+
+/// Receives preview images during denoising.
+///
+/// This is implemented for closures with a matching signature.
+pub trait PreviewCallback: Send + Sync {
+    /// - `step`: the step which just finished, starting at 1.
+    /// - `total`: the number of steps which will be run.
+    /// - `images`: one preview per image in the batch.
+    fn on_preview(&self, step: usize, total: usize, images: Vec<DynamicImage>);
+}
+
+impl<F> PreviewCallback for F
+where
+    F: Fn(usize, usize, Vec<DynamicImage>) + Send + Sync,
+{
+    fn on_preview(&self, step: usize, total: usize, images: Vec<DynamicImage>) {
+        self(step, total, images)
+    }
+}
+
+impl Debug for dyn PreviewCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PreviewCallback")
+    }
+}
+
+/// How preview images are computed from the intermediate latents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PreviewMode {
+    /// Project the latent channels to RGB with a fixed linear map. The previews are at the latent resolution
+    /// (1/8 of the image size for FLUX) and are nearly free to compute.
+    #[default]
+    LatentRgb,
+}
+
+/// Opt-in live previews during denoising.
+#[derive(Debug, Clone)]
+pub struct Preview {
+    pub mode: PreviewMode,
+    /// Produce previews every `every_n_steps` steps. Must be nonzero.
+    pub every_n_steps: usize,
+    pub callback: Arc<dyn PreviewCallback>,
+}
+
+/// Token to cancel a running generation from another thread. It is checked between denoising steps.
+///
+/// A cancelled generation returns a [`GenerationCancelled`] error.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation of every generation using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Error returned when a generation was stopped with a [`CancellationToken`].
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("Generation was cancelled.")]
+pub struct GenerationCancelled;
+
+impl GenerationCancelled {
+    /// Whether `err` is the error returned by [`crate::SampleContext::check_cancelled`], as opposed to a failure
+    /// which happened while the generation was being cancelled.
+    pub(crate) fn is_cancellation(err: &Error) -> bool {
+        match err {
+            Error::Wrapped(err) => err.is::<Self>(),
+            Error::WithBacktrace { inner, .. } | Error::WithPath { inner, .. } => {
+                Self::is_cancellation(inner)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancellation_errors() {
+        assert!(GenerationCancelled::is_cancellation(&Error::wrap(
+            GenerationCancelled
+        )));
+        assert!(GenerationCancelled::is_cancellation(
+            &Error::wrap(GenerationCancelled).with_path("model.safetensors")
+        ));
+        assert!(!GenerationCancelled::is_cancellation(&Error::msg(
+            "Generation was cancelled."
+        )));
+        assert!(!GenerationCancelled::is_cancellation(&Error::wrap(
+            std::io::Error::other("out of memory")
+        )));
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    fs::{self, File},
+    path::Path,
+};
+
+use anyhow::Result;
+use diffusion_rs_common::core::safetensors::SliceSafetensors;
+use diffusion_rs_common::{
+    DdufWriter, FileLoader, ModelSource, NiceProgressBar, TokenSource, DDUF_ALIGNMENT,
+};
+use tracing::info;
+
+use super::{ModelFileError, ModelIndex};
+
+/// Extensions of the files allowed in DDUF files. Other files, such as READMEs or PyTorch weights, are skipped
+/// when packing, except for GGUF weights, which cannot be skipped without losing the model.
+const DDUF_EXTENSIONS: &[&str] = &["json", "safetensors", "txt", "model"];
+
+/// The configs of which every component directory of a DDUF file must have one.
+const COMPONENT_CONFIGS: &[&str] = &[
+    "config.json",
+    "tokenizer_config.json",
+    "scheduler_config.json",
+    "preprocessor_config.json",
+];
+
+/// Pack a model in the diffusers layout, in the directory `dir`, into the DDUF file `file`.
+///
+/// The archive contains `model_index.json` and the files of the component directories, stored uncompressed
+/// and aligned so that they can be memory-mapped. The model is checked to have the components required by its
+/// pipeline first, so that no archive is written which could not be loaded with [`ModelSource::dduf`]. Models
+/// with GGUF files cannot be packed, since DDUF files may not contain them.
+///
+/// [`ModelSource::dduf`]: crate::ModelSource::dduf
+pub fn pack_dduf(dir: impl AsRef<Path>, file: impl AsRef<Path>) -> Result<()> {
+    let dir = dir.as_ref();
+    let files = list_dduf_files(dir)?;
+    let issues = check_model_files(&files, |name| Ok(Some(fs::read(dir.join(name))?)))?;
+    if let Some(issue) = issues.into_iter().next() {
+        return Err(issue.into());
+    }
+
+    let mut writer = DdufWriter::create(file)?;
+    for name in NiceProgressBar::<_, 'g'>(files.into_iter(), "Packing files") {
+        let mut file = File::open(dir.join(&name))?;
+        let size = file.metadata()?.len();
+        writer.add_file(&name, &mut file, size)?;
+    }
+    writer.finish()
+}
+
+/// The files of the model to pack, relative to `dir` with `/` separators: `model_index.json` first, then the
+/// files of each component directory, sorted.
+fn list_dduf_files(dir: &Path) -> Result<Vec<String>> {
+    if !dir.join("model_index.json").is_file() {
+        anyhow::bail!(
+            "Expected `model_index.json` file present in `{}`.",
+            dir.display()
+        );
+    }
+    let mut files = Vec::new();
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let component = entry.file_name().to_string_lossy().to_string();
+        // Follow symlinks, as in snapshots of the Hugging Face cache.
+        if !entry.path().is_dir() || component.starts_with('.') {
+            continue;
+        }
+        for entry in entry.path().read_dir()? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if Path::new(&name)
+                .extension()
+                .is_some_and(|ext| ext == "gguf")
+            {
+                return Err(ModelFileError::UnsupportedFile(format!("{component}/{name}")).into());
+            }
+            let packed = entry.path().is_file()
+                && Path::new(&name)
+                    .extension()
+                    .is_some_and(|ext| DDUF_EXTENSIONS.iter().any(|x| ext == *x));
+            if packed {
+                files.push(format!("{component}/{name}"));
+            } else {
+                info!("skipping `{component}/{name}`, which is not packed into DDUF files");
+            }
+        }
+    }
+    files.sort();
+    files.insert(0, "model_index.json".to_string());
+    Ok(files)
+}
+
+/// The result of [`validate_dduf`]: the files of a DDUF file, and the problems which would prevent loading it or
+/// which do not follow the DDUF format.
+#[derive(Debug, Clone)]
+pub struct DdufReport {
+    pub files: Vec<String>,
+    pub issues: Vec<ModelFileError>,
+}
+
+impl DdufReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl Display for DdufReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_valid() {
+            return write!(f, "Valid DDUF file with {} files.", self.files.len());
+        }
+        write!(f, "Invalid DDUF file:")?;
+        for issue in &self.issues {
+            write!(f, "\n- {issue}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Check a DDUF file without loading the model: that its files are allowed in DDUF files, stored uncompressed
+/// and aligned, that the JSON files and the headers of the safetensors files are valid, and that the components
+/// and files required by the pipeline are present.
+///
+/// An error is returned only if the file cannot be read as a ZIP archive. Other problems are listed in the report.
+pub fn validate_dduf(file: impl AsRef<Path>) -> Result<DdufReport> {
+    let path = file.as_ref();
+    let mut source = ModelSource::dduf(path.display())?;
+    let entries = FileLoader::from_model_source(&mut source, true, TokenSource::None, None, false)
+        .and_then(|mut loader| loader.dduf_entries())
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "`{}` could not be read as a ZIP archive: {e}",
+                path.display()
+            )
+        })?;
+    let ModelSource::Dduf { file, name: _ } = &source else {
+        anyhow::bail!("expected dduf model source!");
+    };
+    let archive: &[u8] = file.get_ref();
+
+    let mut issues = Vec::new();
+    let mut data = HashMap::new();
+    for entry in &entries {
+        let allowed = Path::new(&entry.name)
+            .extension()
+            .is_some_and(|ext| DDUF_EXTENSIONS.iter().any(|x| ext == *x));
+        if !allowed {
+            issues.push(ModelFileError::UnsupportedFile(entry.name.clone()));
+        }
+        if !entry.stored {
+            issues.push(ModelFileError::Compressed(entry.name.clone()));
+            continue;
+        }
+        if !entry.data_start.is_multiple_of(DDUF_ALIGNMENT as u64) {
+            issues.push(ModelFileError::Unaligned {
+                file: entry.name.clone(),
+                offset: entry.data_start,
+                alignment: DDUF_ALIGNMENT,
+            });
+        }
+        let start = entry.data_start as usize;
+        let Some(bytes) = archive.get(start..start.saturating_add(entry.size as usize)) else {
+            issues.push(ModelFileError::OutOfBounds(entry.name.clone()));
+            continue;
+        };
+        if entry.name.ends_with(".safetensors") {
+            if let Err(e) = SliceSafetensors::new(bytes) {
+                issues.push(ModelFileError::InvalidSafetensors {
+                    file: entry.name.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+        data.insert(entry.name.as_str(), bytes);
+    }
+
+    let files = entries
+        .iter()
+        .map(|entry| entry.name.clone())
+        .collect::<Vec<_>>();
+    issues.extend(check_model_files(&files, |name| {
+        // Compressed and out of bounds files are already reported.
+        Ok(data.get(name).map(|bytes| bytes.to_vec()))
+    })?);
+    Ok(DdufReport { files, issues })
+}
+
+/// Check that the files make up a model which can be loaded: that the JSON files are valid, that every component
+/// directory has a config, and that every component and file required by the pipeline is present, with a
+/// `config.json` for components with weights. `read` returns the data of a file, if it can be read.
+fn check_model_files(
+    files: &[String],
+    mut read: impl FnMut(&str) -> Result<Option<Vec<u8>>>,
+) -> Result<Vec<ModelFileError>> {
+    let mut issues = Vec::new();
+    for name in files
+        .iter()
+        .filter(|name| name.ends_with(".json") && *name != "model_index.json")
+    {
+        if let Some(data) = read(name)? {
+            if let Err(e) = serde_json::from_slice::<serde_json::Value>(&data) {
+                issues.push(ModelFileError::InvalidJson {
+                    file: name.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    let components = files
+        .iter()
+        .filter_map(|name| name.split_once('/'))
+        .map(|(component, _)| component)
+        .collect::<BTreeSet<_>>();
+    for component in components {
+        let has_config = COMPONENT_CONFIGS
+            .iter()
+            .any(|config| files.contains(&format!("{component}/{config}")));
+        if !has_config {
+            issues.push(ModelFileError::MissingConfig(component.to_string()));
+        }
+    }
+
+    if !files.iter().any(|name| name == "model_index.json") {
+        issues.push(ModelFileError::MissingFile("model_index.json".to_string()));
+        return Ok(issues);
+    }
+    let Some(model_index) = read("model_index.json")? else {
+        return Ok(issues);
+    };
+    let loader = match ModelIndex::from_slice(&model_index).and_then(|index| index.loader()) {
+        Ok(loader) => loader,
+        Err(e) => {
+            issues.push(e);
+            return Ok(issues);
+        }
+    };
+    for component in loader.required_component_names() {
+        let prefix = format!("{component}/");
+        let component_files = files
+            .iter()
+            .filter_map(|name| name.strip_prefix(&prefix))
+            .collect::<Vec<_>>();
+        if component_files.is_empty() {
+            issues.push(ModelFileError::MissingComponent {
+                component: component.to_string(),
+                pipeline: loader.name().to_string(),
+            });
+            continue;
+        }
+        // Components without any config are already reported.
+        let has_weights = component_files
+            .iter()
+            .any(|name| name.ends_with(".safetensors"));
+        let has_config = COMPONENT_CONFIGS
+            .iter()
+            .any(|config| component_files.contains(config));
+        if has_weights && has_config && !component_files.contains(&"config.json") {
+            issues.push(ModelFileError::MissingFile(format!("{prefix}config.json")));
+        }
+    }
+    for name in loader.required_files() {
+        if !files.iter().any(|file| file == name) {
+            issues.push(ModelFileError::MissingFile(name.to_string()));
+        }
+    }
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use diffusion_rs_common::core::{safetensors, DType, Device, Tensor};
+    use diffusion_rs_common::{from_mmaped_safetensors, FileData};
+
+    use super::*;
+    use crate::test_utils::TmpDir;
+
+    /// Write a tiny FLUX model in the diffusers layout to `dir`, with a weights file for each model component, and
+    /// return the names of its files.
+    fn write_flux_model(dir: &Path) -> Vec<String> {
+        let mut files = vec![
+            ("model_index.json", r#"{"_class_name": "FluxPipeline"}"#),
+            (
+                "scheduler/scheduler_config.json",
+                r#"{"_class_name": "FlowMatchEulerDiscreteScheduler"}"#,
+            ),
+            ("tokenizer/tokenizer_config.json", "{}"),
+            ("tokenizer/vocab.json", r#"{"a": 0}"#),
+            ("tokenizer/merges.txt", "#version: 0.2\n"),
+            ("tokenizer_2/tokenizer_config.json", "{}"),
+            ("tokenizer_2/tokenizer.json", "{}"),
+        ]
+        .into_iter()
+        .map(|(name, data)| (name.to_string(), data))
+        .collect::<Vec<_>>();
+        for component in ["text_encoder", "text_encoder_2", "transformer", "vae"] {
+            files.push((format!("{component}/config.json"), "{}"));
+        }
+        for (name, data) in &files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+
+        let mut names = files.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        for (i, component) in ["text_encoder", "text_encoder_2", "transformer", "vae"]
+            .into_iter()
+            .enumerate()
+        {
+            let name = format!("{component}/model.safetensors");
+            let weight = Tensor::arange(i as f32, i as f32 + 8., &Device::Cpu).unwrap();
+            safetensors::save(&HashMap::from([("weight", weight)]), dir.join(&name)).unwrap();
+            names.push(name);
+        }
+        names
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let tmp = TmpDir::new("pack_dduf");
+        let dir = tmp.path().join("model");
+        let mut files = write_flux_model(&dir);
+        fs::write(dir.join("README.md"), "# Model").unwrap();
+        fs::write(dir.join("transformer/model.bin"), [0u8; 8]).unwrap();
+
+        let path = tmp.path().join("model.dduf");
+        pack_dduf(&dir, &path).unwrap();
+        let report = validate_dduf(&path).unwrap();
+        assert!(report.is_valid(), "{report}");
+        files.sort_by(|a, b| (a != "model_index.json", a).cmp(&(b != "model_index.json", b)));
+        assert_eq!(report.files, files);
+
+        let mut source = ModelSource::dduf(path.display()).unwrap();
+        let mut loader =
+            FileLoader::from_model_source(&mut source, true, TokenSource::None, None, false)
+                .unwrap();
+        for name in &files {
+            let FileData::DdufOwned { data, .. } = loader.read_file_copied(name, false).unwrap()
+            else {
+                panic!("expected the data of `{name}`");
+            };
+            assert_eq!(data, fs::read(dir.join(name)).unwrap(), "`{name}`");
+        }
+        let weights = loader
+            .read_file("transformer/model.safetensors", false)
+            .unwrap();
+        let vb = from_mmaped_safetensors(
+            vec![weights],
+            Some(DType::F32),
+            &Device::Cpu,
+            true,
+            Arc::new(source),
+        )
+        .unwrap();
+        assert_eq!(
+            vb.get(8, "weight").unwrap().to_vec1::<f32>().unwrap(),
+            [2., 3., 4., 5., 6., 7., 8., 9.]
+        );
+    }
+
+    #[test]
+    fn pack_rejects_incomplete_models() {
+        let tmp = TmpDir::new("pack_dduf_incomplete");
+        let dir = tmp.path().join("model");
+        write_flux_model(&dir);
+        fs::remove_dir_all(dir.join("vae")).unwrap();
+        fs::remove_file(dir.join("transformer/config.json")).unwrap();
+
+        let path = tmp.path().join("model.dduf");
+        let err = pack_dduf(&dir, &path).unwrap_err();
+        assert!(err.downcast_ref::<ModelFileError>().is_some(), "{err}");
+        assert!(!path.exists());
+        let files = list_dduf_files(&dir).unwrap();
+        let issues = check_model_files(&files, |name| Ok(Some(fs::read(dir.join(name))?))).unwrap();
+        assert_eq!(issues.len(), 2, "{issues:?}");
+        assert!(issues.iter().any(|issue| matches!(
+            issue,
+            ModelFileError::MissingComponent { component, .. } if component == "vae"
+        )));
+        assert!(issues.iter().any(|issue| matches!(
+            issue,
+            ModelFileError::MissingConfig(component) if component == "transformer"
+        )));
+    }
+
+    #[test]
+    fn pack_rejects_gguf_files() {
+        let tmp = TmpDir::new("pack_dduf_gguf");
+        let dir = tmp.path().join("model");
+        write_flux_model(&dir);
+        fs::write(dir.join("transformer/model.gguf"), [0u8; 8]).unwrap();
+
+        let path = tmp.path().join("model.dduf");
+        let err = pack_dduf(&dir, &path).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ModelFileError>(),
+            Some(&ModelFileError::UnsupportedFile(
+                "transformer/model.gguf".to_string()
+            ))
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn validate_reports_unsupported_files_and_missing_configs() {
+        let tmp = TmpDir::new("validate_dduf");
+        let dir = tmp.path().join("model");
+        let files = write_flux_model(&dir);
+        fs::remove_file(dir.join("tokenizer_2/tokenizer_config.json")).unwrap();
+        fs::write(dir.join("transformer/model.gguf"), [0u8; 8]).unwrap();
+
+        // Write the archive directly, since `pack_dduf` refuses such models.
+        let path = tmp.path().join("model.dduf");
+        let mut writer = DdufWriter::create(&path).unwrap();
+        for name in files
+            .iter()
+            .map(String::as_str)
+            .filter(|name| *name != "tokenizer_2/tokenizer_config.json")
+            .chain(["transformer/model.gguf"])
+        {
+            let data = fs::read(dir.join(name)).unwrap();
+            writer
+                .add_file(name, &mut &data[..], data.len() as u64)
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let report = validate_dduf(&path).unwrap();
+        assert_eq!(
+            report.issues,
+            [
+                ModelFileError::UnsupportedFile("transformer/model.gguf".to_string()),
+                ModelFileError::MissingConfig("tokenizer_2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_reports_out_of_bounds_files() {
+        let tmp = TmpDir::new("validate_dduf_out_of_bounds");
+        let dir = tmp.path().join("model");
+        write_flux_model(&dir);
+        let path = tmp.path().join("model.dduf");
+        pack_dduf(&dir, &path).unwrap();
+
+        // Make the size of `vae/model.safetensors` in the central directory larger than the archive, as if the
+        // archive had been truncated.
+        let mut archive = fs::read(&path).unwrap();
+        let name = b"vae/model.safetensors";
+        let header = (0..archive.len() - 46)
+            .rev()
+            .find(|&i| {
+                archive[i..i + 4] == [0x50, 0x4b, 0x01, 0x02] && archive[i + 46..].starts_with(name)
+            })
+            .unwrap();
+        for size in [header + 20, header + 24] {
+            archive[size..size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        }
+        fs::write(&path, archive).unwrap();
+
+        let report = validate_dduf(&path).unwrap();
+        assert_eq!(
+            report.issues,
+            [ModelFileError::OutOfBounds(
+                "vae/model.safetensors".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Mapping of LoRA module names onto the layers of the FLUX pipeline.
+//!
+//! Three naming schemes are supported:
+//! - diffusers: `transformer.transformer_blocks.0.attn.to_q`, `text_encoder.text_model.encoder.layers.0.mlp.fc1`, ...
+//! - kohya: `lora_unet_double_blocks_0_img_attn_qkv`, `lora_te1_text_model_encoder_layers_0_mlp_fc1`, ...
+//! - BFL: `diffusion_model.double_blocks.0.img_attn.qkv`, ...
+//!
+//! Kohya and BFL use the module names of the original FLUX implementation, where some layers are fused.
+
+use std::ops::Range;
+
+use diffusion_rs_common::core::{Result, Tensor};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Component {
+    Transformer,
+    ClipText,
+    T5,
+}
+
+/// How the up projection of a LoRA module maps onto the target layers.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Split {
+    None,
+    /// A fused layer: the rows are split into chunks with these relative sizes, one per target layer.
+    Chunks(&'static [usize]),
+    /// The halves of the rows are swapped, for the final modulation of BFL which has shift and scale reversed.
+    SwapHalves,
+}
+
+impl Split {
+    /// The rows of the fused layer making up each target layer, as ranges to concatenate.
+    #[allow(clippy::single_range_in_vec_init)]
+    pub(super) fn row_ranges(self, rows: usize) -> Result<Vec<Vec<Range<usize>>>> {
+        match self {
+            Self::None => Ok(vec![vec![0..rows]]),
+            Self::Chunks(sizes) => {
+                let total = sizes.iter().sum::<usize>();
+                if !rows.is_multiple_of(total) {
+                    diffusion_rs_common::bail!(
+                        "Cannot split {rows} rows into chunks of relative sizes {sizes:?}."
+                    );
+                }
+                let unit = rows / total;
+                let mut start = 0;
+                let mut chunks = Vec::new();
+                for size in sizes {
+                    chunks.push(vec![start..start + size * unit]);
+                    start += size * unit;
+                }
+                Ok(chunks)
+            }
+            Self::SwapHalves => {
+                let half = rows / 2;
+                Ok(vec![vec![half..rows, 0..half]])
+            }
+        }
+    }
+
+    /// Split the up projection into one tensor per target layer.
+    pub(super) fn apply(self, b: &Tensor) -> Result<Vec<Tensor>> {
+        self.row_ranges(b.dim(0)?)?
+            .into_iter()
+            .map(|ranges| {
+                let parts = ranges
+                    .into_iter()
+                    .map(|range| b.narrow(0, range.start, range.len()))
+                    .collect::<Result<Vec<_>>>()?;
+                Tensor::cat(&parts, 0)
+            })
+            .collect()
+    }
+}
+
+/// The layers targeted by a LoRA module.
+#[derive(Debug)]
+pub(super) struct Target {
+    pub(super) component: Component,
+    /// Layer names, as returned by `QuantizedModel::named_layers`. If `underscored`, the dots are replaced by
+    /// underscores.
+    pub(super) layers: Vec<String>,
+    pub(super) underscored: bool,
+    pub(super) split: Split,
+}
+
+impl Target {
+    fn single(component: Component, layer: &str, underscored: bool) -> Self {
+        Self {
+            component,
+            layers: vec![layer.to_string()],
+            underscored,
+            split: Split::None,
+        }
+    }
+}
+
+pub(super) const BFL_PREFIXES: &[&str] = &[
+    "double_blocks.",
+    "single_blocks.",
+    "img_in",
+    "txt_in",
+    "time_in.",
+    "vector_in.",
+    "guidance_in.",
+    "final_layer.",
+];
+
+/// Resolve the layers targeted by a LoRA module, or `None` if the name is not recognized.
+pub(super) fn resolve(module: &str) -> Option<Target> {
+    if let Some(name) = module.strip_prefix("lora_unet_") {
+        return resolve_bfl(name);
+    }
+    if let Some(name) = module
+        .strip_prefix("lora_te1_")
+        .or_else(|| module.strip_prefix("lora_te_"))
+    {
+        let name = name.strip_prefix("text_model_")?;
+        return Some(Target::single(Component::ClipText, name, true));
+    }
+    if let Some(name) = module.strip_prefix("lora_te2_") {
+        return Some(Target::single(Component::T5, name, true));
+    }
+    if let Some(name) = module.strip_prefix("lora_transformer_") {
+        return Some(Target::single(Component::Transformer, name, true));
+    }
+    if let Some(name) = module.strip_prefix("text_encoder.") {
+        let name = name.strip_prefix("text_model.")?;
+        return Some(Target::single(Component::ClipText, name, false));
+    }
+    if let Some(name) = module.strip_prefix("text_encoder_2.") {
+        return Some(Target::single(Component::T5, name, false));
+    }
+
+    let name = ["transformer.", "base_model.model.", "diffusion_model."]
+        .iter()
+        .find_map(|prefix| module.strip_prefix(prefix))
+        .unwrap_or(module);
+    if BFL_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+        resolve_bfl(&name.replace('.', "_"))
+    } else {
+        Some(Target::single(Component::Transformer, name, false))
+    }
+}
+
+/// Resolve a module of the original FLUX implementation, with dots replaced by underscores.
+pub(super) fn resolve_bfl(name: &str) -> Option<Target> {
+    let (prefix, layers, split): (String, &[&str], Split) = if let Some(name) =
+        name.strip_prefix("double_blocks_")
+    {
+        let (i, module) = name.split_once('_')?;
+        let i = i.parse::<usize>().ok()?;
+        let (layers, split): (&[&str], _) = match module {
+            "img_attn_qkv" => (
+                &["attn.to_q", "attn.to_k", "attn.to_v"],
+                Split::Chunks(&[1, 1, 1]),
+            ),
+            "img_attn_proj" => (&["attn.to_out.0"], Split::None),
+            "img_mlp_0" => (&["ff.net.0.proj"], Split::None),
+            "img_mlp_2" => (&["ff.net.2"], Split::None),
+            "img_mod_lin" => (&["norm1.linear"], Split::None),
+            "txt_attn_qkv" => (
+                &["attn.add_q_proj", "attn.add_k_proj", "attn.add_v_proj"],
+                Split::Chunks(&[1, 1, 1]),
+            ),
+            "txt_attn_proj" => (&["attn.to_add_out"], Split::None),
+            "txt_mlp_0" => (&["ff_context.net.0.proj"], Split::None),
+            "txt_mlp_2" => (&["ff_context.net.2"], Split::None),
+            "txt_mod_lin" => (&["norm1_context.linear"], Split::None),
+            _ => return None,
+        };
+        (format!("transformer_blocks.{i}."), layers, split)
+    } else if let Some(name) = name.strip_prefix("single_blocks_") {
+        let (i, module) = name.split_once('_')?;
+        let i = i.parse::<usize>().ok()?;
+        let (layers, split): (&[&str], _) = match module {
+            // Fused attention and MLP input projections. The MLP is 4 times the hidden size.
+            "linear1" => (
+                &["attn.to_q", "attn.to_k", "attn.to_v", "proj_mlp"],
+                Split::Chunks(&[1, 1, 1, 4]),
+            ),
+            "linear2" => (&["proj_out"], Split::None),
+            "modulation_lin" => (&["norm.linear"], Split::None),
+            _ => return None,
+        };
+        (format!("single_transformer_blocks.{i}."), layers, split)
+    } else {
+        let (layers, split): (&[&str], _) = match name {
+            "img_in" => (&["x_embedder"], Split::None),
+            "txt_in" => (&["context_embedder"], Split::None),
+            "time_in_in_layer" => (&["time_text_embed.timestep_embedder.linear_1"], Split::None),
+            "time_in_out_layer" => (&["time_text_embed.timestep_embedder.linear_2"], Split::None),
+            "vector_in_in_layer" => (&["time_text_embed.text_embedder.linear_1"], Split::None),
+            "vector_in_out_layer" => (&["time_text_embed.text_embedder.linear_2"], Split::None),
+            "guidance_in_in_layer" => {
+                (&["time_text_embed.guidance_embedder.linear_1"], Split::None)
+            }
+            "guidance_in_out_layer" => {
+                (&["time_text_embed.guidance_embedder.linear_2"], Split::None)
+            }
+            "final_layer_linear" => (&["proj_out"], Split::None),
+            "final_layer_adaLN_modulation_1" => (&["norm_out.linear"], Split::SwapHalves),
+            _ => return None,
+        };
+        (String::new(), layers, split)
+    };
+
+    Some(Target {
+        component: Component::Transformer,
+        layers: layers
+            .iter()
+            .map(|layer| format!("{prefix}{layer}"))
+            .collect(),
+        underscored: false,
+        split,
+    })
+}
@@ -2,8 +2,10 @@ use std::sync::Mutex;
 use std::{cmp::Ordering, collections::HashMap, sync::Arc};
 
 use anyhow::Result;
-use diffusion_rs_common::core::{DType, Device, Tensor, D};
+use diffusion_rs_backend::IsqType;
+use diffusion_rs_common::core::{DType, Device, Tensor};
 use diffusion_rs_common::nn::Module;
+use rand::{rngs::StdRng, SeedableRng};
 use tokenizers::Tokenizer;
 use tracing::info;
 
@@ -15,13 +17,29 @@ use crate::{
     },
     pipelines::ComponentName,
 };
-use diffusion_rs_common::{from_mmaped_safetensors, ModelSource};
-
-use super::sampling::Sampler;
+use diffusion_rs_common::{from_mmaped_safetensors, FileData, ModelSource};
+
+use self::preview::PreviewStepCallback;
+use self::single_file::{load_flux_gguf_tensor, load_flux_safetensors};
+use super::lora::{apply_adapter, group_modules, lora_layers, remove_adapter, LoraRegistry};
+use super::prompt_cache::{PromptCache, DEFAULT_PROMPT_CACHE_SIZE};
+use super::sampling::{run_sampler, SampleHooks, SamplerType};
+use super::save::{recording, ComponentRecord, ModelWriter};
 use super::scheduler::SchedulerConfig;
-use super::{ComponentElem, DiffusionGenerationParams, Loader, ModelPipeline, Offloading};
+use super::{
+    component_file, images_to_tensor, masks_to_tensor, take_component, ComponentElem,
+    DiffusionGenerationParams, InitImage, Loader, ModelPipeline, Offloading, OutputType,
+    PromptEmbeds,
+};
+use super::{
+    gguf::{load_gguf, t5_tensor_name},
+    single_file::load_safetensors,
+};
 
+mod lora;
+mod preview;
 mod sampling;
+mod single_file;
 
 pub struct FluxLoader;
 
@@ -42,6 +60,15 @@ impl Loader for FluxLoader {
         ]
     }
 
+    fn required_files(&self) -> Vec<&'static str> {
+        vec![
+            "scheduler/scheduler_config.json",
+            "tokenizer/vocab.json",
+            "tokenizer/merges.txt",
+            "tokenizer_2/tokenizer.json",
+        ]
+    }
+
     fn load_from_components(
         &self,
         mut components: HashMap<ComponentName, ComponentElem>,
@@ -50,121 +77,238 @@ impl Loader for FluxLoader {
         silent: bool,
         offloading_type: Option<Offloading>,
         source: Arc<ModelSource>,
+        isq: Option<IsqType>,
+        allow_saving: bool,
     ) -> Result<Arc<Mutex<dyn ModelPipeline>>> {
-        let scheduler = components.remove(&ComponentName::Scheduler).unwrap();
-        let clip_component = components.remove(&ComponentName::TextEncoder(1)).unwrap();
-        let t5_component = components.remove(&ComponentName::TextEncoder(2)).unwrap();
-        let clip_tok_component = components.remove(&ComponentName::Tokenizer(1)).unwrap();
-        let t5_tok_component = components.remove(&ComponentName::Tokenizer(2)).unwrap();
-        let flux_component = components.remove(&ComponentName::Transformer).unwrap();
-        let vae_component = components.remove(&ComponentName::Vae).unwrap();
+        for (name, component) in &components {
+            if matches!(component, ComponentElem::SingleFile { .. })
+                && !matches!(
+                    name,
+                    ComponentName::Transformer
+                        | ComponentName::TextEncoder(1)
+                        | ComponentName::TextEncoder(2)
+                )
+            {
+                anyhow::bail!(
+                    "Single-file weights are not supported for the `{name}` component of FLUX."
+                );
+            }
+        }
+
+        let scheduler = take_component(&mut components, ComponentName::Scheduler, self)?;
+        let clip_component = take_component(&mut components, ComponentName::TextEncoder(1), self)?;
+        let t5_component = take_component(&mut components, ComponentName::TextEncoder(2), self)?;
+        let clip_tok_component =
+            take_component(&mut components, ComponentName::Tokenizer(1), self)?;
+        let t5_tok_component = take_component(&mut components, ComponentName::Tokenizer(2), self)?;
+        let flux_component = take_component(&mut components, ComponentName::Transformer, self)?;
+        let vae_component = take_component(&mut components, ComponentName::Vae, self)?;
 
         let t5_flux_device = match offloading_type {
             Some(Offloading::Full) => Device::Cpu,
             None => device.clone(),
         };
+        // With ISQ, the unquantized weights are loaded on the CPU and quantized onto their device.
+        let load_device = |device: &Device| match isq {
+            Some(_) => Device::Cpu,
+            None => device.clone(),
+        };
+
+        // The files of the scheduler and tokenizers are kept to save the model.
+        let mut files_to_save = Vec::new();
+        let mut keep_files = |files: &HashMap<String, FileData>| -> Result<()> {
+            if allow_saving {
+                for (name, file) in files {
+                    files_to_save.push((name.clone(), file.read_bytes(&source)?));
+                }
+            }
+            Ok(())
+        };
 
         let scheduler_config = if let ComponentElem::Config { files } = scheduler {
+            keep_files(&files)?;
             serde_json::from_str::<SchedulerConfig>(
-                &files["scheduler/scheduler_config.json"].read_to_string(&source)?,
+                &component_file(&files, "scheduler/scheduler_config.json")?
+                    .read_to_string(&source)?,
             )?
         } else {
             anyhow::bail!("expected scheduler config")
         };
         let clip_tokenizer = if let ComponentElem::Other { files } = clip_tok_component {
-            let vocab_file = &files["tokenizer/vocab.json"];
-            let merges_file = &files["tokenizer/merges.txt"];
+            keep_files(&files)?;
+            let vocab_file = component_file(&files, "tokenizer/vocab.json")?;
+            let merges_file = component_file(&files, "tokenizer/merges.txt")?;
 
             diffusion_rs_common::load_bpe_tokenizer(vocab_file, merges_file, &source)?
         } else {
             anyhow::bail!("incorrect storage of clip tokenizer")
         };
         let t5_tokenizer = if let ComponentElem::Other { files } = t5_tok_component {
-            Tokenizer::from_bytes(files["tokenizer_2/tokenizer.json"].read_to_string(&source)?)
-                .map_err(anyhow::Error::msg)?
+            keep_files(&files)?;
+            Tokenizer::from_bytes(
+                component_file(&files, "tokenizer_2/tokenizer.json")?.read_to_string(&source)?,
+            )
+            .map_err(anyhow::Error::msg)?
         } else {
             anyhow::bail!("incorrect storage of t5 tokenizer")
         };
         if !silent {
             info!("loading CLIP model");
         }
-        let clip_component = if let ComponentElem::Model {
-            safetensors,
-            config,
-        } = clip_component
-        {
-            let cfg: ClipTextConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
-
-            let vb = from_mmaped_safetensors(
-                safetensors.into_values().collect(),
-                Some(dtype),
-                device,
-                silent,
-                source.clone(),
-            )?;
-            ClipTextTransformer::new(vb.pp("text_model"), &cfg)?
-        } else {
-            anyhow::bail!("incorrect storage of clip model")
+        let (clip_vb, clip_config) = match clip_component {
+            ComponentElem::Model {
+                safetensors,
+                config,
+            } => {
+                let vb = from_mmaped_safetensors(
+                    safetensors.into_values().collect(),
+                    Some(dtype),
+                    &load_device(device),
+                    silent,
+                    source.clone(),
+                )?;
+                (vb, config)
+            }
+            ComponentElem::SingleFile { file, config } => {
+                let vb = if file.extension().is_some_and(|ext| ext == "gguf") {
+                    load_gguf(&file, &source, dtype, device, |name, tensor| {
+                        Ok(vec![(name.to_string(), tensor.load(device)?)])
+                    })?
+                } else {
+                    load_safetensors(&file, dtype, &load_device(device), |name, _| {
+                        Ok(vec![(name.to_string(), None)])
+                    })?
+                };
+                (vb, config)
+            }
+            _ => anyhow::bail!("incorrect storage of clip model"),
         };
+        let clip_config = clip_config.read_bytes(&source)?;
+        let cfg: ClipTextConfig = serde_json::from_slice(&clip_config)?;
+        let (clip_vb, record) = recording(clip_vb, allow_saving);
+        let mut clip_component = ClipTextTransformer::new(clip_vb.pp("text_model"), &cfg)?;
+        let clip_record = record
+            .map(|record| {
+                ComponentRecord::new(
+                    clip_config,
+                    record,
+                    "text_model.",
+                    Some(&mut clip_component),
+                )
+            })
+            .transpose()?;
+        if let Some(isq) = isq {
+            clip_component.quantize(isq, device)?;
+        }
         if !silent {
             info!("loading T5 model");
         }
-        let t5_component = if let ComponentElem::Model {
-            safetensors,
-            config,
-        } = t5_component
-        {
-            let cfg: T5Config = serde_json::from_str(&config.read_to_string(&source)?)?;
-            let vb = from_mmaped_safetensors(
-                safetensors.into_values().collect(),
-                Some(dtype),
-                &t5_flux_device,
-                silent,
-                source.clone(),
-            )?;
-            T5EncoderModel::new(vb, &cfg)?
-        } else {
-            anyhow::bail!("incorrect storage of t5 model")
+        let (t5_vb, t5_config) = match t5_component {
+            ComponentElem::Model {
+                safetensors,
+                config,
+            } => {
+                let vb = from_mmaped_safetensors(
+                    safetensors.into_values().collect(),
+                    Some(dtype),
+                    &load_device(&t5_flux_device),
+                    silent,
+                    source.clone(),
+                )?;
+                (vb, config.read_bytes(&source)?)
+            }
+            ComponentElem::SingleFile { file, config } => {
+                let config = config.read_bytes(&source)?;
+                let cfg: T5Config = serde_json::from_slice(&config)?;
+                let gated = cfg.feed_forward_proj.gated;
+                let vb = if file.extension().is_some_and(|ext| ext == "gguf") {
+                    load_gguf(&file, &source, dtype, &t5_flux_device, |name, tensor| {
+                        Ok(vec![(
+                            t5_tensor_name(name, gated),
+                            tensor.load(&t5_flux_device)?,
+                        )])
+                    })?
+                } else {
+                    load_safetensors(&file, dtype, &load_device(&t5_flux_device), |name, _| {
+                        Ok(vec![(t5_tensor_name(name, gated), None)])
+                    })?
+                };
+                (vb, config)
+            }
+            _ => anyhow::bail!("incorrect storage of t5 model"),
         };
+        let cfg: T5Config = serde_json::from_slice(&t5_config)?;
+        let (t5_vb, record) = recording(t5_vb, allow_saving);
+        let mut t5_component = T5EncoderModel::new(t5_vb, &cfg)?;
+        let t5_record = record
+            .map(|record| ComponentRecord::new(t5_config, record, "", Some(&mut t5_component)))
+            .transpose()?;
+        if let Some(isq) = isq {
+            t5_component.quantize(isq, &t5_flux_device)?;
+        }
         if !silent {
             info!("loading VAE model");
         }
-        let vae_component = if let ComponentElem::Model {
+        let (vae_component, vae_record) = if let ComponentElem::Model {
             safetensors,
             config,
         } = vae_component
         {
-            dispatch_load_vae_model(
+            let (model, record) = dispatch_load_vae_model(
                 &config,
                 safetensors.into_values().collect(),
                 device,
                 dtype,
                 silent,
                 source.clone(),
-            )?
+                allow_saving,
+            )?;
+            let record = record
+                .map(|record| ComponentRecord::new(config.read_bytes(&source)?, record, "", None))
+                .transpose()?;
+            (model, record)
         } else {
             anyhow::bail!("incorrect storage of vae model")
         };
         if !silent {
             info!("loading FLUX model");
         }
-        let flux_component = if let ComponentElem::Model {
-            safetensors,
-            config,
-        } = flux_component
-        {
-            let cfg: FluxConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
-            let vb = from_mmaped_safetensors(
-                safetensors.into_values().collect(),
-                Some(dtype),
-                &t5_flux_device,
-                silent,
-                source,
-            )?;
-            FluxModel::new(&cfg, vb)?
-        } else {
-            anyhow::bail!("incorrect storage of flux model")
+        let (flux_vb, flux_config) = match flux_component {
+            ComponentElem::Model {
+                safetensors,
+                config,
+            } => {
+                let vb = from_mmaped_safetensors(
+                    safetensors.into_values().collect(),
+                    Some(dtype),
+                    &load_device(&t5_flux_device),
+                    silent,
+                    source.clone(),
+                )?;
+                (vb, config)
+            }
+            ComponentElem::SingleFile { file, config } => {
+                let vb = if file.extension().is_some_and(|ext| ext == "gguf") {
+                    load_gguf(&file, &source, dtype, &t5_flux_device, |name, tensor| {
+                        load_flux_gguf_tensor(name, tensor, &t5_flux_device)
+                    })?
+                } else {
+                    load_flux_safetensors(&file, dtype, &load_device(&t5_flux_device))?
+                };
+                (vb, config)
+            }
+            _ => anyhow::bail!("incorrect storage of flux model"),
         };
+        let flux_config = flux_config.read_bytes(&source)?;
+        let cfg: FluxConfig = serde_json::from_slice(&flux_config)?;
+        let (flux_vb, record) = recording(flux_vb, allow_saving);
+        let mut flux_component = FluxModel::new(&cfg, flux_vb)?;
+        let flux_record = record
+            .map(|record| ComponentRecord::new(flux_config, record, "", Some(&mut flux_component)))
+            .transpose()?;
+        if let Some(isq) = isq {
+            flux_component.quantize(isq, &t5_flux_device)?;
+        }
 
         if !silent {
             info!(
@@ -182,12 +326,36 @@ impl Loader for FluxLoader {
             flux_model: flux_component,
             scheduler_config,
             device: device.clone(),
+            dtype,
+            rng: StdRng::from_entropy(),
+            prompt_cache: PromptCache::new(DEFAULT_PROMPT_CACHE_SIZE),
+            loras: LoraRegistry::default(),
+            record: match (clip_record, t5_record, flux_record, vae_record) {
+                (Some(clip), Some(t5), Some(flux), Some(vae)) => Some(FluxRecord {
+                    files: files_to_save,
+                    clip,
+                    t5,
+                    flux,
+                    vae,
+                }),
+                _ => None,
+            },
         };
 
         Ok(Arc::new(Mutex::new(pipeline)))
     }
 }
 
+/// The files and tensors of a FLUX model kept to save it.
+struct FluxRecord {
+    /// Files of the scheduler and tokenizers, by their path in the model.
+    files: Vec<(String, Vec<u8>)>,
+    clip: ComponentRecord,
+    t5: ComponentRecord,
+    flux: ComponentRecord,
+    vae: ComponentRecord,
+}
+
 pub struct FluxPipeline {
     clip_tokenizer: Arc<Tokenizer>,
     clip_model: ClipTextTransformer,
@@ -197,37 +365,47 @@ pub struct FluxPipeline {
     flux_model: FluxModel,
     scheduler_config: SchedulerConfig,
     device: Device,
+    dtype: DType,
+    /// Source of seeds for requests which do not specify one.
+    rng: StdRng,
+    prompt_cache: PromptCache,
+    loras: LoraRegistry,
+    /// Only kept if loaded with [`crate::LoadOptions::allow_saving`].
+    record: Option<FluxRecord>,
 }
 
 impl FluxPipeline {
-    fn tokenize_and_pad(
+    fn tokenize(
         prompts: Vec<String>,
         tokenizer: &Tokenizer,
     ) -> diffusion_rs_common::core::Result<Vec<Vec<u32>>> {
-        let mut t5_tokens = Vec::new();
-        let unpadded_t5_tokens = tokenizer
+        Ok(tokenizer
             .encode_batch(prompts, true)
             .map_err(|e| diffusion_rs_common::core::Error::Msg(e.to_string()))?
             .into_iter()
             .map(|e| e.get_ids().to_vec())
-            .collect::<Vec<_>>();
-        let t5_max_tokens = unpadded_t5_tokens.iter().map(|x| x.len()).max().unwrap();
-        for mut tokenization in unpadded_t5_tokens {
-            tokenization.extend(vec![0; t5_max_tokens - tokenization.len()]);
-            t5_tokens.push(tokenization);
-        }
+            .collect::<Vec<_>>())
+    }
 
-        Ok(t5_tokens)
+    /// Pad tokenizations with zeros to `len`.
+    fn pad(tokens: Vec<Vec<u32>>, len: usize) -> Vec<Vec<u32>> {
+        tokens
+            .into_iter()
+            .map(|mut tokenization| {
+                tokenization.extend(vec![0; len - tokenization.len()]);
+                tokenization
+            })
+            .collect()
     }
-}
 
-impl ModelPipeline for FluxPipeline {
-    fn forward(
+    /// Run both text encoders on prompts, padding the T5 tokens to `t5_len`.
+    fn encode_uncached(
         &mut self,
         prompts: Vec<String>,
-        params: DiffusionGenerationParams,
+        t5_tokens: Vec<Vec<u32>>,
+        t5_len: usize,
         offloading_type: Option<Offloading>,
-    ) -> diffusion_rs_common::core::Result<Tensor> {
+    ) -> diffusion_rs_common::core::Result<PromptEmbeds> {
         match offloading_type {
             Some(Offloading::Full) => {
                 self.t5_model.to_device(&self.device)?;
@@ -235,23 +413,7 @@ impl ModelPipeline for FluxPipeline {
             None => (),
         }
 
-        let mut t5_input_ids = Tensor::new(
-            Self::tokenize_and_pad(prompts.clone(), &self.t5_tokenizer)?,
-            &self.device,
-        )?;
-
-        if !self.flux_model.is_guidance() {
-            match t5_input_ids.dim(1)?.cmp(&256) {
-                Ordering::Greater => {
-                    diffusion_rs_common::bail!("T5 embedding length greater than 256, please shrink the prompt or use the -dev (with guidance distillation) version.")
-                }
-                Ordering::Less | Ordering::Equal => {
-                    t5_input_ids =
-                        t5_input_ids.pad_with_zeros(D::Minus1, 0, 256 - t5_input_ids.dim(1)?)?;
-                }
-            }
-        }
-
+        let t5_input_ids = Tensor::new(Self::pad(t5_tokens, t5_len), &self.device)?;
         let t5_embed = self.t5_model.forward(&t5_input_ids)?;
 
         match offloading_type {
@@ -261,35 +423,386 @@ impl ModelPipeline for FluxPipeline {
             None => (),
         }
 
+        let clip_tokens = Self::tokenize(prompts, &self.clip_tokenizer)?;
+        let clip_max_tokens = clip_tokens.iter().map(|x| x.len()).max().unwrap();
         let clip_input_ids = Tensor::new(
-            Self::tokenize_and_pad(prompts, &self.clip_tokenizer)?,
+            Self::pad(clip_tokens, clip_max_tokens),
             self.clip_model.device(),
         )?;
         let clip_embed = self.clip_model.forward(&clip_input_ids)?;
 
-        let mut img = sampling::get_noise(
-            t5_embed.dim(0)?,
-            params.height,
-            params.width,
-            t5_embed.device(),
-        )?
-        .to_dtype(t5_embed.dtype())?;
+        Ok(PromptEmbeds {
+            prompt_embeds: t5_embed,
+            pooled_prompt_embeds: clip_embed,
+        })
+    }
 
-        let state = sampling::State::new(&t5_embed, &clip_embed, &img)?;
+    /// Map the modules of a LoRA onto the layers of the models and apply them.
+    fn apply_lora(
+        &mut self,
+        name: &str,
+        tensors: HashMap<String, Tensor>,
+        scale: f64,
+    ) -> diffusion_rs_common::core::Result<()> {
+        let modules = group_modules(tensors)?;
+
+        let mut flux_layers = self
+            .flux_model
+            .named_layers()?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let mut clip_layers = self
+            .clip_model
+            .named_layers()?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let mut t5_layers = self
+            .t5_model
+            .named_layers()?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let underscored = |layers: &HashMap<String, _>| {
+            layers
+                .keys()
+                .map(|name| (name.replace('.', "_"), name.clone()))
+                .collect::<HashMap<_, _>>()
+        };
+        let flux_names = underscored(&flux_layers);
+        let clip_names = underscored(&clip_layers);
+        let t5_names = underscored(&t5_layers);
+
+        for (module, weights) in modules {
+            let Some(target) = lora::resolve(&module) else {
+                diffusion_rs_common::bail!("Unrecognized LoRA module `{module}`.");
+            };
+            let (layers, names) = match target.component {
+                lora::Component::Transformer => (&mut flux_layers, &flux_names),
+                lora::Component::ClipText => (&mut clip_layers, &clip_names),
+                lora::Component::T5 => (&mut t5_layers, &t5_names),
+            };
+            let bs = target.split.apply(&weights.b)?;
+            for (layer_name, b) in target.layers.iter().zip(bs) {
+                let layer_name = if target.underscored {
+                    names.get(layer_name).unwrap_or(layer_name)
+                } else {
+                    layer_name
+                };
+                let Some(layer) = layers.get_mut(layer_name) else {
+                    diffusion_rs_common::bail!(
+                        "LoRA module `{module}` targets `{layer_name}`, which is not in the {:?} model.",
+                        target.component
+                    );
+                };
+                apply_adapter(layer, name, &weights, &b, scale, self.dtype)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ModelPipeline for FluxPipeline {
+    fn encode_prompt(
+        &mut self,
+        prompts: Vec<String>,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<PromptEmbeds> {
+        let t5_tokens = Self::tokenize(prompts.clone(), &self.t5_tokenizer)?;
+        let t5_max_tokens = t5_tokens.iter().map(|x| x.len()).max().unwrap();
+
+        let t5_len = if self.flux_model.is_guidance() {
+            t5_max_tokens
+        } else {
+            match t5_max_tokens.cmp(&256) {
+                Ordering::Greater => {
+                    diffusion_rs_common::bail!("T5 embedding length greater than 256, please shrink the prompt or use the -dev (with guidance distillation) version.")
+                }
+                Ordering::Less | Ordering::Equal => 256,
+            }
+        };
+
+        // Only encode the prompts which are not cached.
+        let mut embeds = prompts
+            .iter()
+            .map(|prompt| self.prompt_cache.get(prompt, t5_len))
+            .collect::<Vec<_>>();
+        let (missing_prompts, missing_t5_tokens): (Vec<_>, Vec<_>) = prompts
+            .iter()
+            .zip(t5_tokens)
+            .zip(&embeds)
+            .filter(|(_, cached)| cached.is_none())
+            .map(|((prompt, tokens), _)| (prompt.clone(), tokens))
+            .unzip();
+
+        if !missing_prompts.is_empty() {
+            let encoded = self.encode_uncached(
+                missing_prompts.clone(),
+                missing_t5_tokens,
+                t5_len,
+                offloading_type,
+            )?;
+            let mut missing = missing_prompts.into_iter().enumerate();
+            for slot in embeds.iter_mut().filter(|slot| slot.is_none()) {
+                let (i, prompt) = missing.next().unwrap();
+                // Copy the rows so that cached entries do not keep the whole batch alive.
+                let row = PromptEmbeds {
+                    prompt_embeds: encoded.prompt_embeds.narrow(0, i, 1)?.copy()?,
+                    pooled_prompt_embeds: encoded.pooled_prompt_embeds.narrow(0, i, 1)?.copy()?,
+                };
+                self.prompt_cache.insert(prompt, t5_len, row.clone());
+                *slot = Some(row);
+            }
+        }
+
+        let embeds = embeds.into_iter().flatten().collect::<Vec<_>>();
+        Ok(PromptEmbeds {
+            prompt_embeds: Tensor::cat(
+                &embeds.iter().map(|e| &e.prompt_embeds).collect::<Vec<_>>(),
+                0,
+            )?,
+            pooled_prompt_embeds: Tensor::cat(
+                &embeds
+                    .iter()
+                    .map(|e| &e.pooled_prompt_embeds)
+                    .collect::<Vec<_>>(),
+                0,
+            )?,
+        })
+    }
+
+    fn set_prompt_cache_size(&mut self, size_in_bytes: usize) {
+        self.prompt_cache.set_capacity(size_in_bytes);
+    }
+
+    fn load_lora(
+        &mut self,
+        name: String,
+        tensors: HashMap<String, Tensor>,
+        scale: f64,
+    ) -> diffusion_rs_common::core::Result<()> {
+        self.loras.check_unfused()?;
+        // An adapter with the same name is replaced, and may target other layers.
+        if self.loras.contains(&name) {
+            let layers = lora_layers(vec![
+                &mut self.flux_model,
+                &mut self.clip_model,
+                &mut self.t5_model,
+            ])?;
+            self.loras.remove(layers, &name)?;
+        }
+        if let Err(e) = self.apply_lora(&name, tensors, scale) {
+            let layers = lora_layers(vec![
+                &mut self.flux_model,
+                &mut self.clip_model,
+                &mut self.t5_model,
+            ])?;
+            remove_adapter(layers, &name);
+            return Err(e);
+        }
+        self.loras.insert(name, scale);
+
+        // Text encoder adapters change the prompt embeddings.
+        self.prompt_cache.clear();
+        Ok(())
+    }
+
+    fn set_lora_scales(
+        &mut self,
+        scales: &[(String, f64)],
+    ) -> diffusion_rs_common::core::Result<()> {
+        let layers = lora_layers(vec![
+            &mut self.flux_model,
+            &mut self.clip_model,
+            &mut self.t5_model,
+        ])?;
+        self.loras.set_scales(layers, scales)?;
+        self.prompt_cache.clear();
+        Ok(())
+    }
+
+    fn unload_lora(&mut self, name: &str) -> diffusion_rs_common::core::Result<()> {
+        let layers = lora_layers(vec![
+            &mut self.flux_model,
+            &mut self.clip_model,
+            &mut self.t5_model,
+        ])?;
+        self.loras.remove(layers, name)?;
+        self.prompt_cache.clear();
+        Ok(())
+    }
+
+    fn lora_adapters(&self) -> Vec<(String, f64)> {
+        self.loras.adapters()
+    }
+
+    fn fuse_loras(&mut self) -> diffusion_rs_common::core::Result<()> {
+        let layers = lora_layers(vec![
+            &mut self.flux_model,
+            &mut self.clip_model,
+            &mut self.t5_model,
+        ])?;
+        self.loras.fuse(layers)
+    }
+
+    fn unfuse_loras(&mut self) -> diffusion_rs_common::core::Result<()> {
+        let layers = lora_layers(vec![
+            &mut self.flux_model,
+            &mut self.clip_model,
+            &mut self.t5_model,
+        ])?;
+        self.loras.unfuse(layers)
+    }
+
+    fn save(&mut self, writer: &mut ModelWriter) -> Result<()> {
+        let Some(record) = &self.record else {
+            anyhow::bail!(
+                "The model was loaded without `LoadOptions::allow_saving`, so it cannot be saved."
+            );
+        };
+        for (name, data) in &record.files {
+            writer.write_file(name, data)?;
+        }
+        record.clip.save(
+            writer,
+            &ComponentName::TextEncoder(1).to_string(),
+            "model",
+            self.clip_model.named_layers()?,
+        )?;
+        record.t5.save(
+            writer,
+            &ComponentName::TextEncoder(2).to_string(),
+            "model",
+            self.t5_model.named_layers()?,
+        )?;
+        record.flux.save(
+            writer,
+            &ComponentName::Transformer.to_string(),
+            "diffusion_pytorch_model",
+            self.flux_model.named_layers()?,
+        )?;
+        record.vae.save(
+            writer,
+            &ComponentName::Vae.to_string(),
+            "diffusion_pytorch_model",
+            vec![],
+        )
+    }
+
+    fn forward(
+        &mut self,
+        embeds: PromptEmbeds,
+        negative_embeds: Option<PromptEmbeds>,
+        params: DiffusionGenerationParams,
+        init_image: Option<InitImage>,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<(Tensor, Vec<u64>)> {
+        let t5_embed = embeds.prompt_embeds.to_device(&self.device)?;
+        let clip_embed = embeds.pooled_prompt_embeds.to_device(&self.device)?;
+        let negative_embeds = match negative_embeds {
+            Some(embeds) => Some((
+                embeds.prompt_embeds.to_device(&self.device)?,
+                embeds.pooled_prompt_embeds.to_device(&self.device)?,
+            )),
+            None => None,
+        };
+
+        if params.num_images_per_prompt == 0 {
+            diffusion_rs_common::bail!("`num_images_per_prompt` must be nonzero.");
+        }
+        let num_images = t5_embed.dim(0)? * params.num_images_per_prompt;
+        let seeds = sampling::resolve_seeds(
+            params.seeds.as_deref(),
+            params.seed,
+            num_images,
+            &mut self.rng,
+        )?;
+        info!("using seeds {seeds:?}");
+
+        // One generator per image, used for the initial noise and then by stochastic samplers.
+        let mut rngs = seeds
+            .iter()
+            .map(|seed| StdRng::seed_from_u64(*seed))
+            .collect::<Vec<_>>();
+        let noise = sampling::get_noise(&mut rngs, params.height, params.width, t5_embed.device())?
+            .to_dtype(t5_embed.dtype())?;
+
+        let scheduler_config = self
+            .scheduler_config
+            .with_overrides(&params.scheduler_overrides)?;
+        // The shift depends on the number of image tokens, after packing the latents into 2x2 patches.
+        let (_, _, h, w) = noise.dims4()?;
         let mu = sampling::calculate_shift(
-            img.dims()[1],
-            self.scheduler_config.base_image_seq_len,
-            self.scheduler_config.max_image_seq_len,
-            self.scheduler_config.base_shift,
-            self.scheduler_config.max_shift,
+            (h / 2) * (w / 2),
+            scheduler_config.base_image_seq_len,
+            scheduler_config.max_image_seq_len,
+            scheduler_config.base_shift,
+            scheduler_config.max_shift,
         );
-        let timesteps = self
-            .scheduler_config
-            .get_timesteps(params.num_steps, Some(mu))?;
+        let sigma_schedule = params
+            .sigma_schedule
+            .clone()
+            .unwrap_or_else(|| scheduler_config.sigma_schedule());
+        let mut timesteps =
+            scheduler_config.get_timesteps(params.num_steps, Some(mu), &sigma_schedule)?;
+        let num_steps = timesteps.len() - 1;
+
+        // For inpainting, the packed mask along with the packed initial image latents and noise.
+        let mut inpaint = None;
+        let mut img = match init_image {
+            Some(init_image) => {
+                let t_start = sampling::img2img_start_step(num_steps, init_image.strength)?;
+                timesteps = timesteps[t_start..].to_vec();
+
+                let (height, width) = sampling::latent_image_size(params.height, params.width);
+                let image = images_to_tensor(&init_image.images, height, width, &self.device)?
+                    .to_dtype(noise.dtype())?;
+                let mut latents = self.vae_model.encode_mode(&image)?;
+                latents =
+                    ((latents - self.vae_model.shift_factor())? * self.vae_model.scale_factor())?;
+                latents = sampling::repeat_interleave(&latents, noise.dim(0)? / latents.dim(0)?)?;
+
+                if let Some(masks) = &init_image.masks {
+                    let (b, c, h, w) = noise.dims4()?;
+                    let mask = masks_to_tensor(masks, h, w, &self.device)?
+                        .to_dtype(noise.dtype())?
+                        .repeat((1, c, 1, 1))?;
+                    let mask = sampling::repeat_interleave(&mask, b / mask.dim(0)?)?;
+                    inpaint = Some((
+                        sampling::pack(&mask)?,
+                        sampling::pack(&latents)?,
+                        sampling::pack(&noise)?,
+                    ));
+                }
+
+                let sigma = timesteps[0];
+                ((noise * sigma)? + (latents * (1. - sigma))?)?
+            }
+            None => noise,
+        };
+
+        let state = sampling::State::new(&t5_embed, &clip_embed, &img)?;
 
         let bs = img.dim(0)?;
         let dev = img.device();
 
+        // Run the conditional and unconditional inputs as one batch unless offloading to save memory. Embeddings
+        // which were encoded separately may differ in length and cannot be batched.
+        let batch_cfg = offloading_type.is_none()
+            && negative_embeds
+                .as_ref()
+                .is_none_or(|(t5_embed, _)| t5_embed.dims()[1] == state.txt.dims()[1]);
+        let cfg_state = match &negative_embeds {
+            Some((t5_embed, clip_embed)) => {
+                let negative_state = sampling::State::new(t5_embed, clip_embed, &img)?;
+                if batch_cfg {
+                    Some(state.cat(&negative_state)?)
+                } else {
+                    Some(negative_state)
+                }
+            }
+            None => None,
+        };
+
         match offloading_type {
             Some(Offloading::Full) => {
                 self.flux_model.to_device(&self.device)?;
@@ -302,7 +815,15 @@ impl ModelPipeline for FluxPipeline {
         } else {
             None
         };
-        let step = |img: &Tensor, t_vec: &Tensor| -> diffusion_rs_common::core::Result<Tensor> {
+        let cfg_guidance = match &guidance {
+            Some(guidance) if batch_cfg => Some(Tensor::cat(&[guidance, guidance], 0)?),
+            _ => guidance.clone(),
+        };
+        let model_step = |img: &Tensor,
+                          t_vec: &Tensor,
+                          state: &sampling::State,
+                          guidance: Option<&Tensor>|
+         -> diffusion_rs_common::core::Result<Tensor> {
             self.flux_model.forward(
                 img,
                 &state.img_ids,
@@ -310,27 +831,85 @@ impl ModelPipeline for FluxPipeline {
                 &state.txt_ids,
                 t_vec,
                 &state.vec,
-                guidance.as_ref(),
+                guidance,
             )
         };
+        let step = |img: &Tensor, t_vec: &Tensor| -> diffusion_rs_common::core::Result<Tensor> {
+            let Some(cfg_state) = &cfg_state else {
+                return model_step(img, t_vec, &state, guidance.as_ref());
+            };
+            let (cond, uncond) = if batch_cfg {
+                let pred = model_step(
+                    &Tensor::cat(&[img, img], 0)?,
+                    &Tensor::cat(&[t_vec, t_vec], 0)?,
+                    cfg_state,
+                    cfg_guidance.as_ref(),
+                )?;
+                (pred.narrow(0, 0, bs)?, pred.narrow(0, bs, bs)?)
+            } else {
+                (
+                    model_step(img, t_vec, &state, guidance.as_ref())?,
+                    model_step(img, t_vec, cfg_state, guidance.as_ref())?,
+                )
+            };
+            &uncond + ((cond - &uncond)? * params.true_cfg_scale)?
+        };
+
+        // Keep the unmasked regions on the trajectory of the initial image.
+        let post_step = |img: Tensor, t: f64| -> diffusion_rs_common::core::Result<Tensor> {
+            match &inpaint {
+                Some((mask, latents, noise)) => {
+                    let known = ((noise * t)? + (latents * (1. - t))?)?;
+                    (mask * img)? + ((1. - mask)? * known)?
+                }
+                None => Ok(img),
+            }
+        };
 
-        let sampler = Sampler::new(&self.scheduler_config.scheduler_type);
-        img = sampler.sample(&timesteps, &state.img, step)?;
+        let default_sampler = SamplerType::from_scheduler(&self.scheduler_config.scheduler_type);
+        let sampler = params.sampler.as_deref().unwrap_or(&default_sampler);
+        let preview_callback = params.preview.as_ref().map(|preview| PreviewStepCallback {
+            inner: params.callback.as_deref(),
+            preview,
+            height: params.height,
+            width: params.width,
+        });
+        let hooks = SampleHooks {
+            callback: match &preview_callback {
+                Some(preview_callback) => Some(preview_callback),
+                None => params.callback.as_deref(),
+            },
+            cancellation: params.cancellation.as_ref(),
+        };
+        let res = run_sampler(
+            sampler, &timesteps, &state.img, step, post_step, &mut rngs, &hooks,
+        );
 
+        // Offload before propagating errors so a cancelled generation does not leave the model on the device.
         match offloading_type {
             Some(Offloading::Full) => {
                 self.flux_model.to_device(&Device::Cpu)?;
             }
             None => (),
         }
+        img = res?;
+        if params.output_type == OutputType::PackedLatents {
+            return Ok((img, seeds));
+        }
 
         img = sampling::unpack(&img, params.height, params.width)?;
+        if params.output_type == OutputType::Latents {
+            return Ok((img, seeds));
+        }
 
         img = ((img / self.vae_model.scale_factor())? + self.vae_model.shift_factor())?;
         img = self.vae_model.decode(&img)?;
 
-        img = ((img.clamp(-1f32, 1f32)? + 1.0)? * 127.5)?.to_dtype(DType::U8)?;
+        img = match params.output_type {
+            OutputType::Tensor => img.to_dtype(DType::F32)?,
+            _ => ((img.clamp(-1f32, 1f32)? + 1.0)? * 127.5)?.to_dtype(DType::U8)?,
+        };
 
-        Ok(img)
+        Ok((img, seeds))
     }
 }
@@ -0,0 +1,72 @@
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use tracing::warn;
+
+use crate::pipelines::{tensor_to_images, Preview, PreviewMode, StepCallback};
+
+use super::sampling;
+
+/// Linear map from the 16 FLUX latent channels to RGB, as used by ComfyUI for its latent previews.
+const LATENT_RGB_FACTORS: [[f32; 3]; 16] = [
+    [-0.0346, 0.0244, 0.0681],
+    [0.0034, 0.0210, 0.0687],
+    [0.0275, -0.0668, -0.0433],
+    [-0.0174, 0.0160, 0.0617],
+    [0.0859, 0.0721, 0.0329],
+    [0.0004, 0.0383, 0.0115],
+    [0.0405, 0.0861, 0.0915],
+    [-0.0236, -0.0185, -0.0259],
+    [-0.0245, 0.0250, 0.1180],
+    [0.1008, 0.0755, -0.0421],
+    [-0.0515, 0.0201, 0.0011],
+    [0.0428, -0.0012, -0.0036],
+    [0.0817, 0.0765, 0.0749],
+    [-0.1264, -0.0522, -0.1103],
+    [-0.0280, -0.0881, -0.0499],
+    [-0.1262, -0.0982, -0.0778],
+];
+const LATENT_RGB_BIAS: [f32; 3] = [-0.0329, -0.0718, -0.0851];
+
+/// Project unpacked latents of shape `(b, 16, h, w)` to a `(b, 3, h, w)` U8 image tensor.
+fn latents_to_rgb(latents: &Tensor) -> Result<Tensor> {
+    let factors = Tensor::new(&LATENT_RGB_FACTORS, &Device::Cpu)?;
+    let bias = Tensor::new(&LATENT_RGB_BIAS, &Device::Cpu)?;
+    let latents = latents.to_device(&Device::Cpu)?.to_dtype(DType::F32)?;
+    let rgb = latents
+        .permute((0, 2, 3, 1))? // (b, h, w, c)
+        .broadcast_matmul(&factors)?
+        .broadcast_add(&bias)?
+        .permute((0, 3, 1, 2))?; // (b, 3, h, w)
+    ((rgb.clamp(-1f32, 1f32)? + 1.0)? * 127.5)?.to_dtype(DType::U8)
+}
+
+/// Wraps the user's step callback and produces previews from the packed latents.
+pub struct PreviewStepCallback<'a> {
+    pub inner: Option<&'a dyn StepCallback>,
+    pub preview: &'a Preview,
+    pub height: usize,
+    pub width: usize,
+}
+
+impl PreviewStepCallback<'_> {
+    fn preview(&self, latents: &Tensor) -> Result<Vec<image::DynamicImage>> {
+        let latents = sampling::unpack(latents, self.height, self.width)?;
+        let rgb = match self.preview.mode {
+            PreviewMode::LatentRgb => latents_to_rgb(&latents)?,
+        };
+        tensor_to_images(&rgb)
+    }
+}
+
+impl StepCallback for PreviewStepCallback<'_> {
+    fn on_step(&self, step: usize, total: usize, timestep: f64, latents: &Tensor) {
+        if let Some(inner) = self.inner {
+            inner.on_step(step, total, timestep, latents);
+        }
+        if step.is_multiple_of(self.preview.every_n_steps) {
+            match self.preview(latents) {
+                Ok(images) => self.preview.callback.on_preview(step, total, images),
+                Err(e) => warn!("could not compute preview at step {step}: {e}"),
+            }
+        }
+    }
+}
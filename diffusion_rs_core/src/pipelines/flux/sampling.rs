@@ -1,16 +1,79 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 use diffusion_rs_common::core::{Device, Result, Tensor};
+use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
 
+/// Image size in pixels corresponding to the latents used for a `height` x `width` generation.
+pub fn latent_image_size(height: usize, width: usize) -> (usize, usize) {
+    (height.div_ceil(16) * 16, width.div_ceil(16) * 16)
+}
+
+/// Index of the first timestep to run when starting from an image noised with the given `strength`.
+pub fn img2img_start_step(num_steps: usize, strength: f64) -> Result<usize> {
+    let init_steps = ((num_steps as f64 * strength) as usize).min(num_steps);
+    if init_steps == 0 {
+        diffusion_rs_common::bail!(
+            "`strength` of {strength} with {num_steps} steps leaves no denoising steps to run."
+        );
+    }
+    Ok(num_steps - init_steps)
+}
+
+/// The seed of each of the `num_images` images: either the explicit `seeds`, or consecutive seeds starting from
+/// `seed`, which is drawn from `rng` if unset.
+pub fn resolve_seeds(
+    seeds: Option<&[u64]>,
+    seed: Option<u64>,
+    num_images: usize,
+    rng: &mut StdRng,
+) -> Result<Vec<u64>> {
+    match (seeds, seed) {
+        (Some(seeds), _) if seeds.len() != num_images => {
+            diffusion_rs_common::bail!("Expected {num_images} seeds, got {}.", seeds.len())
+        }
+        (Some(seeds), _) => Ok(seeds.to_vec()),
+        (None, seed) => {
+            let seed = seed.unwrap_or_else(|| rng.gen());
+            Ok((0..num_images as u64)
+                .map(|i| seed.wrapping_add(i))
+                .collect())
+        }
+    }
+}
+
+/// Sample the initial latents, drawing each element of the batch from the corresponding generator.
+///
+/// The noise is always drawn on the CPU and then moved to `device`, so a given seed produces
+/// bit-identical latents on every backend and regardless of the other samples in the batch.
 pub fn get_noise(
-    num_samples: usize,
+    rngs: &mut [StdRng],
     height: usize,
     width: usize,
     device: &Device,
 ) -> Result<Tensor> {
-    let height = (height + 15) / 16 * 2;
-    let width = (width + 15) / 16 * 2;
-    Tensor::randn(0f32, 1., (num_samples, 16, height, width), device)
+    let height = height.div_ceil(16) * 2;
+    let width = width.div_ceil(16) * 2;
+    let shape = (rngs.len(), 16, height, width);
+    let data = rngs
+        .iter_mut()
+        .flat_map(|rng| {
+            (0..16 * height * width)
+                .map(|_| rng.sample::<f32, _>(StandardNormal))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    Tensor::from_vec(data, shape, &Device::Cpu)?.to_device(device)
+}
+
+/// Repeat each element of the batch `n` times in place, so that `[a, b]` becomes `[a, a, b, b]` for `n = 2`.
+pub fn repeat_interleave(xs: &Tensor, n: usize) -> Result<Tensor> {
+    if n == 1 {
+        return Ok(xs.clone());
+    }
+    let mut repeats = vec![1; xs.rank() + 1];
+    repeats[1] = n;
+    xs.unsqueeze(1)?.repeat(repeats)?.flatten_to(1)
 }
 
 #[derive(Debug, Clone)]
@@ -25,11 +88,9 @@ pub struct State {
 impl State {
     pub fn new(t5_emb: &Tensor, clip_emb: &Tensor, img: &Tensor) -> Result<Self> {
         let dtype = img.dtype();
-        let (bs, c, h, w) = img.dims4()?;
+        let (bs, _c, h, w) = img.dims4()?;
         let dev = img.device();
-        let img = img.reshape((bs, c, h / 2, 2, w / 2, 2))?; // (b, c, h, ph, w, pw)
-        let img = img.permute((0, 2, 4, 1, 3, 5))?; // (b, h, w, c, ph, pw)
-        let img = img.reshape((bs, h / 2 * w / 2, c * 4))?;
+        let img = pack(img)?;
         let img_ids = Tensor::stack(
             &[
                 Tensor::full(0u32, (h / 2, w / 2), dev)?,
@@ -45,9 +106,10 @@ impl State {
         .to_dtype(dtype)?;
         let img_ids = img_ids.reshape((1, h / 2 * w / 2, 3))?;
         let img_ids = img_ids.repeat((bs, 1, 1))?;
-        let txt = t5_emb.repeat(bs)?;
+        // With several images per prompt, the images of each prompt are contiguous in the batch.
+        let txt = repeat_interleave(t5_emb, bs / t5_emb.dim(0)?)?;
         let txt_ids = Tensor::zeros((bs, txt.dim(1)?, 3), dtype, dev)?;
-        let vec = clip_emb.repeat(bs)?;
+        let vec = repeat_interleave(clip_emb, bs / clip_emb.dim(0)?)?;
         Ok(Self {
             img,
             img_ids,
@@ -56,17 +118,38 @@ impl State {
             vec,
         })
     }
+
+    /// Concatenate the batches of two states, for example to run conditional and unconditional inputs together.
+    pub fn cat(&self, other: &Self) -> Result<Self> {
+        Ok(Self {
+            img: Tensor::cat(&[&self.img, &other.img], 0)?,
+            img_ids: Tensor::cat(&[&self.img_ids, &other.img_ids], 0)?,
+            txt: Tensor::cat(&[&self.txt, &other.txt], 0)?,
+            txt_ids: Tensor::cat(&[&self.txt_ids, &other.txt_ids], 0)?,
+            vec: Tensor::cat(&[&self.vec, &other.vec], 0)?,
+        })
+    }
+}
+
+/// Pack latents of shape `(b, c, h, w)` into 2x2 patches of shape `(b, h/2 * w/2, c * 4)`. Inverse of [`unpack`].
+pub fn pack(xs: &Tensor) -> Result<Tensor> {
+    let (b, c, h, w) = xs.dims4()?;
+    xs.reshape((b, c, h / 2, 2, w / 2, 2))? // (b, c, h, ph, w, pw)
+        .permute((0, 2, 4, 1, 3, 5))? // (b, h, w, c, ph, pw)
+        .reshape((b, h / 2 * w / 2, c * 4))
 }
 
 pub fn unpack(xs: &Tensor, height: usize, width: usize) -> Result<Tensor> {
     let (b, _h_w, c_ph_pw) = xs.dims3()?;
-    let height = (height + 15) / 16;
-    let width = (width + 15) / 16;
+    let height = height.div_ceil(16);
+    let width = width.div_ceil(16);
     xs.reshape((b, height, width, c_ph_pw / 4, 2, 2))? // (b, h, w, c, ph, pw)
         .permute((0, 3, 1, 4, 2, 5))? // (b, c, h, ph, w, pw)
         .reshape((b, c_ph_pw / 4, height * 2, width * 2))
 }
 
+/// The dynamic shift `mu` for an image of `image_seq_len` tokens, interpolated linearly between `base_shift` at
+/// `base_seq_len` tokens and `max_shift` at `max_seq_len` tokens.
 pub fn calculate_shift(
     image_seq_len: usize,
     base_seq_len: usize,
@@ -74,7 +157,87 @@ pub fn calculate_shift(
     base_shift: f64,
     max_shift: f64,
 ) -> f64 {
-    let m = (max_shift - base_shift) / (max_seq_len - base_seq_len) as f64;
+    let m = (max_shift - base_shift) / (max_seq_len as f64 - base_seq_len as f64);
     let b = base_shift - m * base_seq_len as f64;
     image_seq_len as f64 * m + b
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn img2img_start_steps() {
+        assert_eq!(img2img_start_step(10, 1.).unwrap(), 0);
+        assert_eq!(img2img_start_step(10, 0.6).unwrap(), 4);
+        // The number of steps to run is rounded down.
+        assert_eq!(img2img_start_step(10, 0.35).unwrap(), 7);
+        assert_eq!(img2img_start_step(10, 0.1).unwrap(), 9);
+        // A strength above 1 runs all the steps.
+        assert_eq!(img2img_start_step(10, 1.5).unwrap(), 0);
+        // No step would be run.
+        assert!(img2img_start_step(10, 0.).is_err());
+        assert!(img2img_start_step(10, 0.09).is_err());
+        assert!(img2img_start_step(0, 1.).is_err());
+    }
+
+    #[test]
+    fn noise_does_not_depend_on_batch_position() {
+        let seeds = [3, 7, 11];
+        let mut rngs = seeds.map(StdRng::seed_from_u64);
+        let batch = get_noise(&mut rngs, 32, 48, &Device::Cpu).unwrap();
+        assert_eq!(batch.dims(), [3, 16, 4, 6]);
+        for (k, seed) in seeds.into_iter().enumerate() {
+            let single =
+                get_noise(&mut [StdRng::seed_from_u64(seed)], 32, 48, &Device::Cpu).unwrap();
+            assert_eq!(
+                batch
+                    .get(k)
+                    .unwrap()
+                    .flatten_all()
+                    .unwrap()
+                    .to_vec1::<f32>()
+                    .unwrap(),
+                single
+                    .get(0)
+                    .unwrap()
+                    .flatten_all()
+                    .unwrap()
+                    .to_vec1::<f32>()
+                    .unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn seeds_are_consecutive() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            resolve_seeds(None, Some(5), 3, &mut rng).unwrap(),
+            [5, 6, 7]
+        );
+        assert_eq!(
+            resolve_seeds(None, Some(u64::MAX), 2, &mut rng).unwrap(),
+            [u64::MAX, 0]
+        );
+        // Without a seed, the first one is drawn from the generator.
+        let drawn = StdRng::seed_from_u64(0).gen::<u64>();
+        assert_eq!(
+            resolve_seeds(None, None, 2, &mut rng).unwrap(),
+            [drawn, drawn.wrapping_add(1)]
+        );
+    }
+
+    #[test]
+    fn explicit_seeds() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // Explicit seeds take precedence over `seed`.
+        assert_eq!(
+            resolve_seeds(Some(&[9, 2]), Some(5), 2, &mut rng).unwrap(),
+            [9, 2]
+        );
+        assert!(resolve_seeds(Some(&[9, 2]), None, 3, &mut rng).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! Mapping of the tensors of single-file FLUX transformers onto the FLUX model.
+//!
+//! Single-file FLUX transformers, in GGUF or safetensors (such as FP8 checkpoints), usually use the names of the
+//! original FLUX implementation, such as `double_blocks.0.img_attn.qkv.weight`, where some layers are fused. The
+//! fused layers are split by rows, which keeps GGUF tensors quantized. Tensors already named like diffusers are
+//! loaded as is.
+
+use diffusion_rs_common::core::{
+    quantized::QTensor, safetensors::MmapedSafetensors, DType, Device, Result,
+};
+use diffusion_rs_common::{FileData, VarBuilder};
+
+use super::lora::{resolve_bfl, BFL_PREFIXES};
+use crate::pipelines::{
+    gguf::GgufTensor,
+    single_file::{load_safetensors, safetensors_path, Rows},
+};
+
+const PREFIXES: &[&str] = &["model.diffusion_model.", "diffusion_model."];
+
+fn strip_prefix(name: &str) -> Option<&str> {
+    PREFIXES.iter().find_map(|prefix| name.strip_prefix(prefix))
+}
+
+/// The tensors of the FLUX model made up by a tensor of a single-file transformer, with the rows of the tensor
+/// making up each of them. Without `rows`, the tensor is not split, as for per-tensor scales.
+fn flux_tensor_targets(name: &str, rows: Option<usize>) -> Result<Vec<(String, Rows)>> {
+    let name = strip_prefix(name).unwrap_or(name);
+    if !BFL_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+        return Ok(vec![(name.to_string(), None)]);
+    }
+    if let Some(name) = qk_norm_name(name) {
+        return Ok(vec![(name, None)]);
+    }
+
+    let Some((target, param)) = name
+        .rsplit_once('.')
+        .and_then(|(module, param)| Some((resolve_bfl(&module.replace('.', "_"))?, param)))
+    else {
+        diffusion_rs_common::bail!("Unsupported tensor `{name}` in single-file FLUX transformer.");
+    };
+    match rows {
+        Some(rows) => target
+            .layers
+            .iter()
+            .zip(target.split.row_ranges(rows)?)
+            .map(|(layer, rows)| Ok((format!("{layer}.{param}"), Some(rows))))
+            .collect(),
+        None => Ok(target
+            .layers
+            .iter()
+            .map(|layer| (format!("{layer}.{param}"), None))
+            .collect()),
+    }
+}
+
+/// Load a tensor of a GGUF FLUX transformer, under the names of the layers it maps to.
+pub(super) fn load_flux_gguf_tensor(
+    name: &str,
+    tensor: GgufTensor,
+    device: &Device,
+) -> Result<Vec<(String, QTensor)>> {
+    flux_tensor_targets(name, Some(tensor.rows()))?
+        .into_iter()
+        .map(|(name, rows)| {
+            let tensor = match rows {
+                Some(rows) => tensor.load_rows(&rows, device)?,
+                None => tensor.load(device)?,
+            };
+            Ok((name, tensor))
+        })
+        .collect()
+}
+
+/// Load a safetensors FLUX transformer, such as an FP8 checkpoint, keeping the dtype of its tensors so that FP8
+/// weights are loaded as FP8 layers.
+pub(super) fn load_flux_safetensors(
+    file: &FileData,
+    dtype: DType,
+    device: &Device,
+) -> Result<VarBuilder<'static>> {
+    let path = safetensors_path(file)?;
+    // Checkpoints saved with the whole model may also contain other components, such as the VAE, which are
+    // then under other prefixes.
+    let prefixed = unsafe { MmapedSafetensors::new(path)? }
+        .tensors()
+        .iter()
+        .any(|(name, _)| strip_prefix(name).is_some());
+    load_safetensors(file, dtype, device, |name, shape| {
+        if prefixed && strip_prefix(name).is_none() {
+            return Ok(vec![]);
+        }
+        let rows = (shape.iter().product::<usize>() > 1).then(|| shape[0]);
+        flux_tensor_targets(name, rows)
+    })
+}
+
+/// Map the scales of the RMS norms of the queries and keys, which are not linear layers.
+fn qk_norm_name(name: &str) -> Option<String> {
+    if let Some(name) = name.strip_prefix("double_blocks.") {
+        let (i, name) = name.split_once('.')?;
+        let norm = match name {
+            "img_attn.norm.query_norm.scale" => "norm_q",
+            "img_attn.norm.key_norm.scale" => "norm_k",
+            "txt_attn.norm.query_norm.scale" => "norm_added_q",
+            "txt_attn.norm.key_norm.scale" => "norm_added_k",
+            _ => return None,
+        };
+        Some(format!("transformer_blocks.{i}.attn.{norm}.weight"))
+    } else if let Some(name) = name.strip_prefix("single_blocks.") {
+        let (i, name) = name.split_once('.')?;
+        let norm = match name {
+            "norm.query_norm.scale" => "norm_q",
+            "norm.key_norm.scale" => "norm_k",
+            _ => return None,
+        };
+        Some(format!("single_transformer_blocks.{i}.attn.{norm}.weight"))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use diffusion_rs_common::core::{
+        quantized::{gguf_file, GgmlDType},
+        Tensor,
+    };
+    use diffusion_rs_common::ModelSource;
+
+    use super::*;
+    use crate::pipelines::gguf::load_gguf;
+    use crate::test_utils::TmpDir;
+
+    /// Hidden size of the fake transformer, a multiple of the GGUF block size.
+    const HIDDEN: usize = 32;
+
+    /// A weight of `rows` rows, each filled with its index.
+    fn weight(rows: usize) -> Tensor {
+        Tensor::arange(0f32, rows as f32, &Device::Cpu)
+            .unwrap()
+            .reshape((rows, 1))
+            .unwrap()
+            .repeat((1, HIDDEN))
+            .unwrap()
+    }
+
+    fn checkpoint() -> Vec<(&'static str, Tensor)> {
+        vec![
+            (
+                "model.diffusion_model.double_blocks.0.img_attn.qkv.weight",
+                weight(3 * HIDDEN),
+            ),
+            (
+                "model.diffusion_model.single_blocks.0.linear1.weight",
+                weight(7 * HIDDEN),
+            ),
+            (
+                "model.diffusion_model.final_layer.adaLN_modulation.1.weight",
+                weight(2 * HIDDEN),
+            ),
+        ]
+    }
+
+    /// Check the shapes of the split tensors, and which rows of the fused tensors they are made of.
+    fn check_split(vb: &VarBuilder) {
+        let row_ids = |name: &str, rows: usize| {
+            vb.get((rows, HIDDEN), name)
+                .unwrap()
+                .narrow(1, 0, 1)
+                .unwrap()
+                .squeeze(1)
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+                .into_iter()
+                .map(|x| x.round() as usize)
+                .collect::<Vec<_>>()
+        };
+        let range = |start: usize, len: usize| (start..start + len).collect::<Vec<_>>();
+        for (i, layer) in ["to_q", "to_k", "to_v"].iter().enumerate() {
+            assert_eq!(
+                row_ids(&format!("transformer_blocks.0.attn.{layer}.weight"), HIDDEN),
+                range(i * HIDDEN, HIDDEN)
+            );
+            assert_eq!(
+                row_ids(
+                    &format!("single_transformer_blocks.0.attn.{layer}.weight"),
+                    HIDDEN
+                ),
+                range(i * HIDDEN, HIDDEN)
+            );
+        }
+        assert_eq!(
+            row_ids("single_transformer_blocks.0.proj_mlp.weight", 4 * HIDDEN),
+            range(3 * HIDDEN, 4 * HIDDEN)
+        );
+        assert_eq!(
+            row_ids("norm_out.linear.weight", 2 * HIDDEN),
+            [range(HIDDEN, HIDDEN), range(0, HIDDEN)].concat()
+        );
+    }
+
+    #[test]
+    fn split_safetensors() {
+        let dir = TmpDir::new("flux-single-file");
+        let path = dir.path().join("flux.safetensors");
+        let mut tensors = checkpoint()
+            .into_iter()
+            .map(|(name, tensor)| (name.to_string(), tensor))
+            .collect::<HashMap<_, _>>();
+        // A per-tensor scale of a fused layer applies to all of the split layers.
+        tensors.insert(
+            "model.diffusion_model.double_blocks.0.img_attn.qkv.scale_weight".to_string(),
+            Tensor::new(&[0.5f32], &Device::Cpu).unwrap(),
+        );
+        // Other components of the checkpoint are skipped.
+        tensors.insert("vae.decoder.conv_in.weight".to_string(), weight(HIDDEN));
+        diffusion_rs_common::core::safetensors::save(&tensors, &path).unwrap();
+
+        let vb = load_flux_safetensors(&FileData::Path(path), DType::F32, &Device::Cpu).unwrap();
+        check_split(&vb);
+        for layer in ["to_q", "to_k", "to_v"] {
+            assert!(vb.contains_tensor(&format!("transformer_blocks.0.attn.{layer}.scale_weight")));
+        }
+        assert!(!vb.contains_tensor("vae.decoder.conv_in.weight"));
+        assert!(!vb.contains_tensor("decoder.conv_in.weight"));
+    }
+
+    #[test]
+    fn split_gguf() {
+        let qtensors = checkpoint()
+            .into_iter()
+            .map(|(name, tensor)| (name, QTensor::quantize(&tensor, GgmlDType::Q8_0).unwrap()))
+            .collect::<Vec<_>>();
+        let mut data = std::io::Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut data,
+            &[],
+            &qtensors
+                .iter()
+                .map(|(name, tensor)| (*name, tensor))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        let file = FileData::DdufOwned {
+            name: "transformer/flux.gguf".into(),
+            data: data.into_inner(),
+        };
+        let vb = load_gguf(
+            &file,
+            &ModelSource::from_model_id("flux"),
+            DType::F32,
+            &Device::Cpu,
+            |name, tensor| load_flux_gguf_tensor(name, tensor, &Device::Cpu),
+        )
+        .unwrap();
+        check_split(&vb);
+        assert_eq!(
+            vb.get_qtensor("transformer_blocks.0.attn.to_k.weight")
+                .unwrap()
+                .dtype(),
+            GgmlDType::Q8_0
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    ops::Range,
+    sync::Arc,
+};
+
+use diffusion_rs_common::core::{
+    quantized::{ggml_file::qtensor_from_ggml, gguf_file, GgmlDType, QTensor},
+    DType, Device, Result,
+};
+use diffusion_rs_common::{FileData, ModelSource, NiceProgressBar, VarBuilder};
+
+/// A tensor of a GGUF file, kept as raw data so that it can be split by rows before being loaded.
+pub(crate) struct GgufTensor {
+    dtype: GgmlDType,
+    dims: Vec<usize>,
+    data: Vec<u8>,
+}
+
+impl GgufTensor {
+    /// The size of the first dimension.
+    pub(crate) fn rows(&self) -> usize {
+        self.dims.first().copied().unwrap_or(1)
+    }
+
+    pub(crate) fn load(&self, device: &Device) -> Result<QTensor> {
+        qtensor_from_ggml(self.dtype, &self.data, self.dims.clone(), device)
+    }
+
+    /// Load the concatenation of some ranges of rows, for example to split a fused layer.
+    pub(crate) fn load_rows(&self, ranges: &[Range<usize>], device: &Device) -> Result<QTensor> {
+        let rows = self.rows();
+        let row_elems = self.dims.iter().skip(1).product::<usize>();
+        if row_elems % self.dtype.block_size() != 0 {
+            diffusion_rs_common::bail!(
+                "Cannot split rows of {row_elems} elements into {:?} blocks.",
+                self.dtype
+            );
+        }
+        let row_bytes = row_elems / self.dtype.block_size() * self.dtype.type_size();
+        let mut data = Vec::new();
+        let mut n_rows = 0;
+        for range in ranges {
+            if range.end > rows {
+                diffusion_rs_common::bail!("Rows {range:?} are out of bounds for {rows} rows.");
+            }
+            data.extend_from_slice(&self.data[range.start * row_bytes..range.end * row_bytes]);
+            n_rows += range.len();
+        }
+        let mut dims = self.dims.clone();
+        dims[0] = n_rows;
+        qtensor_from_ggml(self.dtype, &data, dims, device)
+    }
+}
+
+/// Load the tensors of a GGUF file, which may be in the DDUF file of `source`, into a `VarBuilder`. For each tensor
+/// of the file, `remap` returns the tensors to load under the names expected by the model, which allows renaming
+/// or splitting them.
+pub(crate) fn load_gguf(
+    file: &FileData,
+    source: &ModelSource,
+    dtype: DType,
+    device: &Device,
+    remap: impl FnMut(&str, GgufTensor) -> Result<Vec<(String, QTensor)>>,
+) -> Result<VarBuilder<'static>> {
+    match file {
+        FileData::Path(path) => {
+            read_gguf(&mut BufReader::new(File::open(path)?), dtype, device, remap)
+        }
+        FileData::Dduf {
+            name: _,
+            start,
+            end,
+        } => {
+            let ModelSource::Dduf { file, name: _ } = source.base_source() else {
+                diffusion_rs_common::bail!("expected dduf model source!");
+            };
+            let data = &file.get_ref()[*start..*end];
+            read_gguf(&mut Cursor::new(data), dtype, device, remap)
+        }
+        FileData::DdufOwned { name: _, data } => {
+            read_gguf(&mut Cursor::new(data), dtype, device, remap)
+        }
+    }
+}
+
+fn read_gguf<R: Read + Seek>(
+    reader: &mut R,
+    dtype: DType,
+    device: &Device,
+    mut remap: impl FnMut(&str, GgufTensor) -> Result<Vec<(String, QTensor)>>,
+) -> Result<VarBuilder<'static>> {
+    let content = gguf_file::Content::read(reader)?;
+
+    // Read the tensors in the order of the file.
+    let mut infos = content.tensor_infos.iter().collect::<Vec<_>>();
+    infos.sort_by_key(|(_, info)| info.offset);
+
+    let mut tensors = HashMap::new();
+    for (name, info) in NiceProgressBar::<_, 'b'>(infos.into_iter(), "Loading GGUF tensors") {
+        let elems = info.shape.elem_count();
+        let block_size = info.ggml_dtype.block_size();
+        if elems % block_size != 0 {
+            diffusion_rs_common::bail!(
+                "GGUF tensor `{name}` has {elems} elements, which is not divisible by the block size {block_size}."
+            );
+        }
+        let mut data = vec![0u8; elems / block_size * info.ggml_dtype.type_size()];
+        reader.seek(SeekFrom::Start(content.tensor_data_offset + info.offset))?;
+        reader.read_exact(&mut data)?;
+        let tensor = GgufTensor {
+            dtype: info.ggml_dtype,
+            dims: info.shape.dims().to_vec(),
+            data,
+        };
+        for (name, qtensor) in remap(name, tensor)? {
+            tensors.insert(name, Arc::new(qtensor));
+        }
+    }
+
+    Ok(VarBuilder::from_qtensors(tensors, dtype, device))
+}
+
+/// The name of a tensor of a T5 encoder in the naming scheme of llama.cpp, such as `enc.blk.0.attn_q.weight`, in
+/// the naming scheme of `T5EncoderModel`. Other names are returned unchanged.
+///
+/// `gated` selects whether `ffn_up` is the gated `wi_1` or the plain `wi` projection.
+pub(crate) fn t5_tensor_name(name: &str, gated: bool) -> String {
+    if let Some(param) = name.strip_prefix("token_embd.") {
+        return format!("shared.{param}");
+    }
+    if let Some(param) = name.strip_prefix("enc.output_norm.") {
+        return format!("encoder.final_layer_norm.{param}");
+    }
+    let Some((i, module, param)) = name.strip_prefix("enc.blk.").and_then(|name| {
+        let (i, name) = name.split_once('.')?;
+        let (module, param) = name.split_once('.')?;
+        Some((i, module, param))
+    }) else {
+        return name.to_string();
+    };
+    let layer = match module {
+        "attn_q" => "layer.0.SelfAttention.q",
+        "attn_k" => "layer.0.SelfAttention.k",
+        "attn_v" => "layer.0.SelfAttention.v",
+        "attn_o" => "layer.0.SelfAttention.o",
+        "attn_rel_b" => "layer.0.SelfAttention.relative_attention_bias",
+        "attn_norm" => "layer.0.layer_norm",
+        "ffn_gate" => "layer.1.DenseReluDense.wi_0",
+        "ffn_up" if gated => "layer.1.DenseReluDense.wi_1",
+        "ffn_up" => "layer.1.DenseReluDense.wi",
+        "ffn_down" => "layer.1.DenseReluDense.wo",
+        "ffn_norm" => "layer.1.layer_norm",
+        _ => return name.to_string(),
+    };
+    format!("encoder.block.{i}.{layer}.{param}")
+}
@@ -0,0 +1,336 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use diffusion_rs_backend::{LoraAdapter, LoraLinear, QuantMethod};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use diffusion_rs_common::{cached_snapshot, get_token, TokenSource};
+use hf_hub::api::sync::ApiBuilder;
+use tracing::warn;
+
+use crate::models::QuantizedModel;
+
+/// Name of the weights file written by diffusers' `save_lora_weights`, preferred when a repository has several.
+const DIFFUSERS_LORA_FILE: &str = "pytorch_lora_weights.safetensors";
+
+const DOWN_SUFFIXES: &[&str] = &[".lora_A.weight", ".lora_down.weight", ".lora.down.weight"];
+const UP_SUFFIXES: &[&str] = &[".lora_B.weight", ".lora_up.weight", ".lora.up.weight"];
+
+/// The weights of the LoRA for a single module.
+pub(crate) struct LoraWeights {
+    /// Down projection, of shape `(rank, in_dim)`.
+    pub(crate) a: Tensor,
+    /// Up projection, of shape `(out_dim, rank)`.
+    pub(crate) b: Tensor,
+    pub(crate) alpha: Option<f64>,
+}
+
+impl LoraWeights {
+    /// The `alpha / rank` factor of this module, where `alpha` defaults to the rank.
+    pub(crate) fn alpha_scale(&self) -> Result<f64> {
+        let rank = self.a.dim(0)?;
+        #[allow(clippy::cast_precision_loss)]
+        Ok(self.alpha.map_or(1., |alpha| alpha / rank as f64))
+    }
+}
+
+/// Read the tensors of a LoRA from a `.safetensors` file, a directory containing one, or a Hugging Face model ID,
+/// which is downloaded with `token`, or read from the local cache if `offline`.
+pub(crate) fn load_lora_tensors(
+    path_or_hf_id: &str,
+    token: &TokenSource,
+    offline: bool,
+) -> anyhow::Result<HashMap<String, Tensor>> {
+    let mut path = PathBuf::from(path_or_hf_id);
+    if offline && !path.exists() {
+        path = cached_snapshot(path_or_hf_id, "main")?;
+    }
+    let file = if path.is_file() {
+        path
+    } else if path.is_dir() {
+        let files = std::fs::read_dir(&path)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        path.join(select_lora_file(&files, path_or_hf_id)?)
+    } else {
+        let api = ApiBuilder::new()
+            .with_token(get_token(token)?)
+            .build()?
+            .model(path_or_hf_id.to_string());
+        let files = api
+            .info()
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?
+            .siblings
+            .into_iter()
+            .map(|x| x.rfilename)
+            .collect::<Vec<_>>();
+        api.get(&select_lora_file(&files, path_or_hf_id)?)
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?
+    };
+    Ok(diffusion_rs_common::core::safetensors::load(
+        file,
+        &Device::Cpu,
+    )?)
+}
+
+fn select_lora_file(files: &[String], source: &str) -> anyhow::Result<String> {
+    if files.iter().any(|file| file == DIFFUSERS_LORA_FILE) {
+        return Ok(DIFFUSERS_LORA_FILE.to_string());
+    }
+    let safetensors = files
+        .iter()
+        .filter(|file| file.ends_with(".safetensors"))
+        .collect::<Vec<_>>();
+    match safetensors.as_slice() {
+        [file] => Ok(file.to_string()),
+        [] => anyhow::bail!("No `.safetensors` file found for LoRA `{source}`."),
+        _ => anyhow::bail!(
+            "Found several `.safetensors` files for LoRA `{source}`, please specify the path to one of them."
+        ),
+    }
+}
+
+/// Group the tensors of a LoRA by the module they apply to.
+///
+/// Tensors other than the low-rank projections and alphas, such as DoRA scales or full `.diff` deltas, are not
+/// supported and are skipped with a warning.
+pub(crate) fn group_modules(
+    tensors: HashMap<String, Tensor>,
+) -> Result<HashMap<String, LoraWeights>> {
+    #[derive(Default)]
+    struct Parts {
+        a: Option<Tensor>,
+        b: Option<Tensor>,
+        alpha: Option<f64>,
+    }
+
+    let mut parts: HashMap<String, Parts> = HashMap::new();
+    let mut skipped = Vec::new();
+    for (key, tensor) in tensors {
+        if let Some(module) = DOWN_SUFFIXES.iter().find_map(|s| key.strip_suffix(s)) {
+            parts.entry(module.to_string()).or_default().a = Some(tensor);
+        } else if let Some(module) = UP_SUFFIXES.iter().find_map(|s| key.strip_suffix(s)) {
+            parts.entry(module.to_string()).or_default().b = Some(tensor);
+        } else if let Some(module) = key.strip_suffix(".alpha") {
+            let alpha = tensor
+                .flatten_all()?
+                .to_dtype(DType::F64)?
+                .to_vec1::<f64>()?;
+            let [alpha] = alpha.as_slice() else {
+                diffusion_rs_common::bail!("Expected a scalar for LoRA alpha `{key}`.");
+            };
+            parts.entry(module.to_string()).or_default().alpha = Some(*alpha);
+        } else {
+            skipped.push(key);
+        }
+    }
+    if let Some(example) = skipped.iter().min() {
+        warn!(
+            "skipping {} unsupported LoRA tensors, such as `{example}`",
+            skipped.len()
+        );
+    }
+
+    parts
+        .into_iter()
+        // Modules with only an alpha are those of skipped tensors.
+        .filter(|(_, parts)| parts.a.is_some() || parts.b.is_some())
+        .map(|(module, parts)| match parts {
+            Parts {
+                a: Some(a),
+                b: Some(b),
+                alpha,
+            } => {
+                if a.rank() != 2 || b.rank() != 2 || a.dim(0)? != b.dim(1)? {
+                    diffusion_rs_common::bail!(
+                        "Mismatched LoRA shapes for `{module}`: down {:?}, up {:?}.",
+                        a.shape(),
+                        b.shape()
+                    );
+                }
+                Ok((module, LoraWeights { a, b, alpha }))
+            }
+            _ => diffusion_rs_common::bail!(
+                "Expected both LoRA down and up projections for `{module}`."
+            ),
+        })
+        .collect()
+}
+
+/// Apply a named low-rank delta to a layer, on the layer's device and in the given dtype.
+pub(crate) fn apply_adapter(
+    layer: &mut Arc<dyn QuantMethod>,
+    name: &str,
+    weights: &LoraWeights,
+    b: &Tensor,
+    scale: f64,
+    dtype: DType,
+) -> Result<()> {
+    let dev = layer.device();
+    let adapter = LoraAdapter {
+        name: name.to_string(),
+        a: weights.a.to_device(&dev)?.to_dtype(dtype)?,
+        b: b.to_device(&dev)?.to_dtype(dtype)?,
+        alpha_scale: weights.alpha_scale()?,
+        scale,
+    };
+    *layer = LoraLinear::apply(layer, adapter)?;
+    Ok(())
+}
+
+/// All linear layers of some models, which LoRA adapters may apply to.
+pub(crate) fn lora_layers(
+    models: Vec<&mut dyn QuantizedModel>,
+) -> Result<Vec<&mut Arc<dyn QuantMethod>>> {
+    let mut layers = Vec::new();
+    for model in models {
+        for layer in model.aggregate_layers()? {
+            layers.extend(layer.0);
+        }
+    }
+    Ok(layers)
+}
+
+/// The LoRA adapters loaded into a pipeline, with their scales.
+#[derive(Default)]
+pub(crate) struct LoraRegistry {
+    adapters: Vec<(String, f64)>,
+    fused: bool,
+}
+
+impl LoraRegistry {
+    pub(crate) fn adapters(&self) -> Vec<(String, f64)> {
+        self.adapters.clone()
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.adapters.iter().any(|(x, _)| x == name)
+    }
+
+    pub(crate) fn check_unfused(&self) -> Result<()> {
+        if self.fused {
+            diffusion_rs_common::bail!(
+                "The LoRA adapters are fused into the weights, unfuse them before modifying them."
+            );
+        }
+        Ok(())
+    }
+
+    /// Record a loaded adapter, replacing any adapter with the same name.
+    pub(crate) fn insert(&mut self, name: String, scale: f64) {
+        self.adapters.retain(|(x, _)| *x != name);
+        self.adapters.push((name, scale));
+    }
+
+    /// Activate exactly the given adapters, with the given scales. Other adapters stay loaded but are inactive.
+    pub(crate) fn set_scales(
+        &mut self,
+        layers: Vec<&mut Arc<dyn QuantMethod>>,
+        scales: &[(String, f64)],
+    ) -> Result<()> {
+        self.check_unfused()?;
+        for (name, _) in scales {
+            if !self.contains(name) {
+                diffusion_rs_common::bail!("No LoRA adapter named `{name}` is loaded.");
+            }
+        }
+        let scale_of = |name: &str| {
+            scales
+                .iter()
+                .find(|(x, _)| x == name)
+                .map_or(0., |(_, scale)| *scale)
+        };
+        for layer in layers {
+            *layer = LoraLinear::map_adapters(layer, |mut adapter| {
+                adapter.scale = scale_of(&adapter.name);
+                Some(adapter)
+            });
+        }
+        for (name, scale) in &mut self.adapters {
+            *scale = scale_of(name);
+        }
+        Ok(())
+    }
+
+    /// Unload an adapter, restoring the base layers it applied to.
+    pub(crate) fn remove(
+        &mut self,
+        layers: Vec<&mut Arc<dyn QuantMethod>>,
+        name: &str,
+    ) -> Result<()> {
+        self.check_unfused()?;
+        if !self.contains(name) {
+            diffusion_rs_common::bail!("No LoRA adapter named `{name}` is loaded.");
+        }
+        remove_adapter(layers, name);
+        self.adapters.retain(|(x, _)| x != name);
+        Ok(())
+    }
+
+    /// Fuse the active adapters into the weights of the unquantized layers. Adapters on quantized layers remain
+    /// applied at runtime.
+    pub(crate) fn fuse(&mut self, layers: Vec<&mut Arc<dyn QuantMethod>>) -> Result<()> {
+        if self.fused {
+            return Ok(());
+        }
+        for layer in layers {
+            *layer = LoraLinear::fuse(layer)?;
+        }
+        self.fused = true;
+        Ok(())
+    }
+
+    pub(crate) fn unfuse(&mut self, layers: Vec<&mut Arc<dyn QuantMethod>>) -> Result<()> {
+        if !self.fused {
+            return Ok(());
+        }
+        for layer in layers {
+            *layer = LoraLinear::unfuse(layer)?;
+        }
+        self.fused = false;
+        Ok(())
+    }
+}
+
+/// Remove an adapter from the layers, for example after it failed to load.
+pub(crate) fn remove_adapter(layers: Vec<&mut Arc<dyn QuantMethod>>, name: &str) {
+    for layer in layers {
+        *layer =
+            LoraLinear::map_adapters(layer, |adapter| (adapter.name != name).then_some(adapter));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_modules_skips_unsupported_tensors() {
+        let dev = Device::Cpu;
+        let tensors = HashMap::from([
+            (
+                "a.lora_A.weight".to_string(),
+                Tensor::zeros((4, 8), DType::F32, &dev).unwrap(),
+            ),
+            (
+                "a.lora_B.weight".to_string(),
+                Tensor::zeros((16, 4), DType::F32, &dev).unwrap(),
+            ),
+            ("a.alpha".to_string(), Tensor::new(2f32, &dev).unwrap()),
+            (
+                "a.dora_scale".to_string(),
+                Tensor::zeros((16, 1), DType::F32, &dev).unwrap(),
+            ),
+            (
+                "b.diff".to_string(),
+                Tensor::zeros((16, 8), DType::F32, &dev).unwrap(),
+            ),
+            (
+                "b.diff_b".to_string(),
+                Tensor::zeros(16, DType::F32, &dev).unwrap(),
+            ),
+            ("b.alpha".to_string(), Tensor::new(2f32, &dev).unwrap()),
+        ]);
+        let modules = group_modules(tensors).unwrap();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules["a"].alpha_scale().unwrap(), 0.5);
+    }
+}
@@ -1,25 +1,52 @@
+mod callbacks;
+mod dduf;
 mod flux;
+mod gguf;
+mod lora;
+mod prompt_cache;
 mod sampling;
+mod save;
 mod scheduler;
+mod single_file;
 
 use std::{
     collections::HashMap,
     fmt::Display,
+    path::Path,
     sync::{Arc, Mutex},
 };
 
 use anyhow::Result;
+pub use callbacks::{
+    CancellationToken, GenerationCancelled, Preview, PreviewCallback, PreviewMode,
+    ProgressBarCallback, StepCallback,
+};
+pub use dduf::{pack_dduf, validate_dduf, DdufReport};
+use diffusion_rs_backend::IsqType;
 use diffusion_rs_common::core::{DType, Device, Tensor};
 use flux::FluxLoader;
-use image::{DynamicImage, RgbImage};
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, GrayImage, Luma, Rgb, RgbImage,
+};
+pub use sampling::{DenoiseFn, SampleContext, Sampler, SamplerType};
+pub(crate) use save::recording;
+pub use scheduler::{SchedulerOverrides, SigmaSchedule};
 use serde::Deserialize;
 
-use diffusion_rs_common::{FileData, FileLoader, ModelSource, NiceProgressBar, TokenSource};
+use diffusion_rs_common::{
+    hf_hub_offline, FileData, FileLoader, ModelSource, NiceProgressBar, NotCachedError, TokenSource,
+};
 use tracing::info;
 
-use crate::TryIntoDType;
+use crate::{ModelDType, TryIntoDType};
+
+use self::save::ModelWriter;
 
 /// Generation parameters.
+///
+/// The [`Default`] implementation is intended as a base for struct update syntax; the size, number of steps and
+/// guidance scale are model specific and should usually be set explicitly.
 #[derive(Debug, Clone)]
 pub struct DiffusionGenerationParams {
     pub height: usize,
@@ -30,6 +57,213 @@ pub struct DiffusionGenerationParams {
     /// Higher guidance scale encourages to generate images that are closely linked to the text `prompt`,
     /// usually at the expense of lower image quality.
     pub guidance_scale: f64,
+    /// The number of images to generate for each prompt. Each prompt is encoded once.
+    pub num_images_per_prompt: usize,
+    /// Seed for the initial noise. The same seed produces the same initial latents on every device.
+    /// The images of a batch use consecutive seeds starting at this one, so each can be regenerated on its own.
+    /// If not specified, a random seed is drawn. The seeds are reported in [`DiffusionGenerationOutput::seeds`].
+    pub seed: Option<u64>,
+    /// Explicit seeds, one per generated image, in the order of the output. Takes precedence over `seed`.
+    pub seeds: Option<Vec<u64>>,
+    /// Negative prompts for true classifier-free guidance: one per prompt or a single one for all prompts.
+    /// Only used when `true_cfg_scale` is greater than 1, in which case an empty negative prompt is used if
+    /// this is not specified.
+    pub negative_prompts: Option<Vec<String>>,
+    /// Scale for true classifier-free guidance. Values greater than 1 run the model on both the prompts and the
+    /// negative prompts at every step and combine the predictions. This is independent of the distilled
+    /// `guidance_scale`, and is useful for models without guidance distillation.
+    pub true_cfg_scale: f64,
+    /// Called after every denoising step. Defaults to a [`ProgressBarCallback`]; set to `None` to disable it.
+    pub callback: Option<Arc<dyn StepCallback>>,
+    /// If specified, the generation is stopped between steps after this token is cancelled and a
+    /// [`GenerationCancelled`] error is returned.
+    pub cancellation: Option<CancellationToken>,
+    /// Live previews of the images while denoising. Disabled by default.
+    pub preview: Option<Preview>,
+    /// What to return from the generation. Defaults to 8-bit images.
+    pub output_type: OutputType,
+    /// The sampling algorithm, either a [`SamplerType`] or a custom [`Sampler`]. Overrides the scheduler of the
+    /// model if specified.
+    pub sampler: Option<Arc<dyn Sampler>>,
+    /// The spacing of the sigmas over the steps. Overrides the scheduler config of the model if specified.
+    pub sigma_schedule: Option<SigmaSchedule>,
+    /// Overrides of the scheduler config of the model, such as the shift. These take precedence over the
+    /// overrides of the pipeline.
+    pub scheduler_overrides: SchedulerOverrides,
+}
+
+impl Default for DiffusionGenerationParams {
+    fn default() -> Self {
+        Self {
+            height: 720,
+            width: 1280,
+            num_steps: 50,
+            guidance_scale: 3.5,
+            num_images_per_prompt: 1,
+            seed: None,
+            seeds: None,
+            negative_prompts: None,
+            true_cfg_scale: 1.0,
+            callback: Some(Arc::new(ProgressBarCallback::default())),
+            cancellation: None,
+            preview: None,
+            output_type: OutputType::Image,
+            sampler: None,
+            sigma_schedule: None,
+            scheduler_overrides: SchedulerOverrides::default(),
+        }
+    }
+}
+
+/// The form in which a generation is returned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputType {
+    /// Decoded 8-bit RGB images, in [`DiffusionGenerationOutput::images`].
+    #[default]
+    Image,
+    /// Decoded F32 tensor of shape `(b, 3, h, w)` in [`DiffusionGenerationOutput::tensor`]. Values are nominally
+    /// in `[-1, 1]` but are not clamped or quantized.
+    Tensor,
+    /// Final latents of shape `(b, c, h, w)` in [`DiffusionGenerationOutput::tensor`], without running the VAE.
+    /// These are in the model's normalized latent space, before the VAE scale and shift are undone.
+    Latents,
+    /// Like [`OutputType::Latents`], but in the packed layout used by the denoiser (`(b, h*w/4, c*4)` for FLUX).
+    PackedLatents,
+}
+
+/// Output of a generation.
+#[derive(Debug, Clone)]
+pub struct DiffusionGenerationOutput {
+    /// Generated images, in the order of the prompts. The images of each prompt are contiguous. Empty unless the
+    /// output type is [`OutputType::Image`].
+    pub images: Vec<DynamicImage>,
+    /// Output tensor for the other output types, on the pipeline's device.
+    pub tensor: Option<Tensor>,
+    /// The seed used for the initial noise of each image. Pass one as [`DiffusionGenerationParams::seed`] to
+    /// regenerate that image.
+    pub seeds: Vec<u64>,
+}
+
+/// Text-encoder outputs for a batch of prompts, as returned by [`Pipeline::encode_prompt`].
+///
+/// These can be reused across generations with [`Pipeline::forward_with_embeds`] to skip the text encoders.
+#[derive(Debug, Clone)]
+pub struct PromptEmbeds {
+    /// Per-token embeddings of shape `(b, seq_len, hidden)`. For FLUX, these are the T5 embeddings.
+    pub prompt_embeds: Tensor,
+    /// Pooled embeddings of shape `(b, hidden)`. For FLUX, these are the CLIP embeddings.
+    pub pooled_prompt_embeds: Tensor,
+}
+
+impl PromptEmbeds {
+    /// The number of prompts in the batch.
+    pub fn batch_size(&self) -> diffusion_rs_common::core::Result<usize> {
+        self.prompt_embeds.dim(0)
+    }
+
+    fn narrow(&self, start: usize, len: usize) -> diffusion_rs_common::core::Result<Self> {
+        Ok(Self {
+            prompt_embeds: self.prompt_embeds.narrow(0, start, len)?,
+            pooled_prompt_embeds: self.pooled_prompt_embeds.narrow(0, start, len)?,
+        })
+    }
+
+    fn repeat(&self, n: usize) -> diffusion_rs_common::core::Result<Self> {
+        Ok(Self {
+            prompt_embeds: self.prompt_embeds.repeat(n)?,
+            pooled_prompt_embeds: self.pooled_prompt_embeds.repeat(n)?,
+        })
+    }
+}
+
+/// Check that at least one prompt is given.
+fn check_prompts(prompts: &[String]) -> anyhow::Result<()> {
+    if prompts.is_empty() {
+        anyhow::bail!("At least one prompt must be given.");
+    }
+    Ok(())
+}
+
+/// Prompts for a generation, either as text or already encoded.
+enum Prompts {
+    Text(Vec<String>),
+    Embeds {
+        embeds: PromptEmbeds,
+        negative_embeds: Option<PromptEmbeds>,
+    },
+}
+
+impl Prompts {
+    /// Encode the prompts if necessary, along with the negative prompts when true CFG is enabled.
+    fn encode(
+        self,
+        model: &mut dyn ModelPipeline,
+        params: &DiffusionGenerationParams,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<(PromptEmbeds, Option<PromptEmbeds>)> {
+        let do_true_cfg = params.true_cfg_scale > 1.;
+        let negative_prompts = |n: usize| -> diffusion_rs_common::core::Result<Vec<String>> {
+            let negative_prompts = params
+                .negative_prompts
+                .clone()
+                .unwrap_or_else(|| vec![String::new()]);
+            match negative_prompts.len() {
+                1 => Ok(vec![negative_prompts[0].clone(); n]),
+                m if m == n => Ok(negative_prompts),
+                m => diffusion_rs_common::bail!("Expected 1 or {n} negative prompts, got {m}."),
+            }
+        };
+
+        match self {
+            Self::Text(prompts) if do_true_cfg => {
+                // Encode the negative prompts in the same batch as the prompts.
+                let n = prompts.len();
+                let mut prompts = prompts;
+                prompts.extend(negative_prompts(n)?);
+                let embeds = model.encode_prompt(prompts, offloading_type)?;
+                Ok((embeds.narrow(0, n)?, Some(embeds.narrow(n, n)?)))
+            }
+            Self::Text(prompts) => Ok((model.encode_prompt(prompts, offloading_type)?, None)),
+            Self::Embeds {
+                embeds,
+                negative_embeds,
+            } if do_true_cfg => {
+                let n = embeds.batch_size()?;
+                let negative_embeds = match negative_embeds {
+                    Some(negative_embeds) => match negative_embeds.batch_size()? {
+                        1 => negative_embeds.repeat(n)?,
+                        m if m == n => negative_embeds,
+                        m => diffusion_rs_common::bail!(
+                            "Expected 1 or {n} negative prompt embeddings, got {m}."
+                        ),
+                    },
+                    None => model.encode_prompt(negative_prompts(n)?, offloading_type)?,
+                };
+                Ok((embeds, Some(negative_embeds)))
+            }
+            Self::Embeds { embeds, .. } => Ok((embeds, None)),
+        }
+    }
+}
+
+/// Images to start denoising from instead of pure noise.
+pub struct InitImage {
+    /// One image per prompt, or a single image used for every prompt.
+    pub images: Vec<DynamicImage>,
+    /// How strongly the images are transformed, in `(0, 1]`. A strength of 1 discards the image content.
+    pub strength: f64,
+    /// Single-channel inpainting masks, one per image or a single mask for all images. White regions are
+    /// generated and black regions are kept from the initial images.
+    pub masks: Option<Vec<DynamicImage>>,
+}
+
+/// Amount of padding, in pixels, to add to each side of an image when outpainting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutpaintPadding {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
 }
 
 #[derive(Debug)]
@@ -44,6 +278,11 @@ pub(crate) enum ComponentElem {
     Other {
         files: HashMap<String, FileData>,
     },
+    /// A model whose weights are loaded from a single GGUF or safetensors file.
+    SingleFile {
+        file: FileData,
+        config: FileData,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -69,6 +308,230 @@ impl Display for ComponentName {
     }
 }
 
+/// Error returned when the files of a model are missing or malformed, when loading or validating it.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ModelFileError {
+    #[error("Missing component `{component}`, required by the `{pipeline}` pipeline.")]
+    MissingComponent { component: String, pipeline: String },
+    #[error("Expected `{0}` file present.")]
+    MissingFile(String),
+    #[error(
+        "Files of the model are missing locally, they must be downloaded before loading it offline: {}.",
+        .0.iter().map(|x| format!("`{x}`")).collect::<Vec<_>>().join(", ")
+    )]
+    MissingOfflineFiles(Vec<String>),
+    #[error("`{file}` is not a valid JSON file: {error}")]
+    InvalidJson { file: String, error: String },
+    #[error("`{file}` is not a valid safetensors file: {error}")]
+    InvalidSafetensors { file: String, error: String },
+    #[error(
+        "Component `{0}` has no `config.json`, `tokenizer_config.json`, `scheduler_config.json` or `preprocessor_config.json`."
+    )]
+    MissingConfig(String),
+    #[error("Unexpected pipeline `{0}` in `model_index.json`.")]
+    UnknownPipeline(String),
+    #[error("`{0}` cannot be stored in DDUF files, which may only contain JSON, safetensors, text and `.model` files.")]
+    UnsupportedFile(String),
+    #[error("The data of `{0}` is out of bounds of the archive, which may be truncated.")]
+    OutOfBounds(String),
+    #[error("`{0}` is compressed, DDUF files must store files uncompressed.")]
+    Compressed(String),
+    #[error("The data of `{file}` starts at offset {offset}, which is not aligned to {alignment} bytes.")]
+    Unaligned {
+        file: String,
+        offset: u64,
+        alignment: u16,
+    },
+}
+
+/// Take a component required by `loader` out of the components of the model.
+pub(crate) fn take_component(
+    components: &mut HashMap<ComponentName, ComponentElem>,
+    component: ComponentName,
+    loader: &dyn Loader,
+) -> std::result::Result<ComponentElem, ModelFileError> {
+    components
+        .remove(&component)
+        .ok_or_else(|| ModelFileError::MissingComponent {
+            component: component.to_string(),
+            pipeline: loader.name().to_string(),
+        })
+}
+
+/// Get a file of a component by its path in the model.
+pub(crate) fn component_file<'a>(
+    files: &'a HashMap<String, FileData>,
+    name: &str,
+) -> std::result::Result<&'a FileData, ModelFileError> {
+    files
+        .get(name)
+        .ok_or_else(|| ModelFileError::MissingFile(name.to_string()))
+}
+
+/// The files of a component of the model, in its directory or in the transformer files of the model.
+struct ComponentFiles<'a> {
+    /// The directory of the component with a trailing `/`, or empty for the transformer files of the model.
+    dir: String,
+    files: Vec<String>,
+    from_transformer: bool,
+    /// The file overriding the weights of the component, see [`ModelSource::override_component_file`].
+    override_file: Option<&'a str>,
+}
+
+impl<'a> ComponentFiles<'a> {
+    fn new(
+        component: &ComponentName,
+        files: &[String],
+        transformer_files: Option<&[String]>,
+        component_files: &'a [(String, String)],
+    ) -> Self {
+        let override_file = component_files
+            .iter()
+            .find(|(x, _)| *x == component.to_string())
+            .map(|(_, file)| file.as_str());
+        let (files, from_transformer, dir) = match transformer_files {
+            Some(files) if *component == ComponentName::Transformer => (files, true, String::new()),
+            _ => (files, false, format!("{component}/")),
+        };
+        let files = files
+            .iter()
+            .filter(|file| file.starts_with(&dir))
+            .filter(|file| !file.ends_with('/'))
+            .cloned()
+            .collect();
+        Self {
+            dir,
+            files,
+            from_transformer,
+            override_file,
+        }
+    }
+
+    fn config_file(&self) -> String {
+        format!("{}config.json", self.dir)
+    }
+
+    fn has_weights(&self) -> bool {
+        self.override_file.is_some()
+            || self.files.iter().any(|file| {
+                file.ends_with(".safetensors")
+                    || file.ends_with(".safetensors.index.json")
+                    || file.ends_with(".gguf")
+            })
+    }
+
+    fn gguf_file(&self) -> Option<&String> {
+        self.files.iter().find(|file| file.ends_with(".gguf"))
+    }
+
+    /// The safetensors files of the weights: the shards listed in the indices of sharded weights, which may not
+    /// all be present when loading offline, or else every safetensors file of the component.
+    fn safetensors_files(&self, loader: &mut FileLoader) -> Result<Vec<String>> {
+        let indices = self
+            .files
+            .iter()
+            .filter(|file| file.ends_with(".safetensors.index.json"))
+            .collect::<Vec<_>>();
+        if indices.is_empty() {
+            return Ok(self
+                .files
+                .iter()
+                .filter(|file| file.ends_with(".safetensors"))
+                .cloned()
+                .collect());
+        }
+        let mut shards = Vec::new();
+        for index in indices {
+            let index_data = loader
+                .read_file_copied(index, self.from_transformer)?
+                .read_to_string_owned()?;
+            let index_data: SafetensorsIndex =
+                serde_json::from_str(&index_data).map_err(|e| ModelFileError::InvalidJson {
+                    file: index.to_string(),
+                    error: e.to_string(),
+                })?;
+            shards.extend(
+                index_data
+                    .weight_map
+                    .into_values()
+                    .map(|shard| format!("{}{shard}", self.dir)),
+            );
+        }
+        shards.sort();
+        shards.dedup();
+        Ok(shards)
+    }
+}
+
+/// The files needed to load the components of the model which are missing from its files, or from the local
+/// cache for component file overrides. When loading offline, these are all reported at once instead of failing on
+/// the first one, as they cannot be downloaded.
+fn missing_offline_files(
+    loader: &mut FileLoader,
+    files: &[String],
+    transformer_files: Option<&[String]>,
+    model_loader: &dyn Loader,
+    component_files: &[(String, String)],
+    token: &TokenSource,
+) -> Result<Vec<String>> {
+    let mut missing = model_loader
+        .required_files()
+        .into_iter()
+        .filter(|name| !files.iter().any(|file| file == name))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    for component in model_loader.required_component_names() {
+        let component_files =
+            ComponentFiles::new(&component, files, transformer_files, component_files);
+        if let Some(file) = component_files.override_file {
+            if single_file::resolve_component_file(file, true, token, true).is_err() {
+                missing.push(file.to_string());
+            }
+        }
+        if component_files.files.is_empty() {
+            missing.push(format!("{component}/"));
+            continue;
+        }
+
+        let config_file = component_files.config_file();
+        let has_config = component_files.files.contains(&config_file);
+        let has_weights = component_files.has_weights();
+        if has_weights && !has_config {
+            missing.push(config_file);
+        } else if has_config && !has_weights {
+            missing.push(format!("{}*.safetensors", component_files.dir));
+        }
+
+        // Sharded weights list their shards in an index, which may be only partially downloaded.
+        if component_files.override_file.is_none() {
+            for file in component_files.safetensors_files(loader)? {
+                if !component_files.files.contains(&file) {
+                    missing.push(file);
+                }
+            }
+        }
+    }
+    Ok(missing)
+}
+
+/// Report a model missing from the local cache when loading offline as its missing files: `model_index.json`, or
+/// the config of the transformer model `transformer_model_id`. Other errors are returned unchanged.
+fn not_cached_error(e: anyhow::Error, transformer_model_id: Option<&str>) -> anyhow::Error {
+    match e.downcast::<NotCachedError>() {
+        Ok(e) if transformer_model_id == Some(e.model_id.as_str()) => {
+            ModelFileError::MissingOfflineFiles(vec![format!("{}/config.json", e.model_id)]).into()
+        }
+        Ok(_) => ModelFileError::MissingOfflineFiles(vec!["model_index.json".to_string()]).into(),
+        Err(e) => e,
+    }
+}
+
+/// The index of sharded safetensors weights.
+#[derive(Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
 /// Offloading setting during loading.
 ///
 /// - Full: offload the largest components of the model to CPU memory and copy them into VRAM as necessary.
@@ -80,6 +543,9 @@ pub enum Offloading {
 pub(crate) trait Loader {
     fn name(&self) -> &'static str;
     fn required_component_names(&self) -> Vec<ComponentName>;
+    /// Files read by the loader besides the configs and weights of the models, such as tokenizer files.
+    fn required_files(&self) -> Vec<&'static str>;
+    #[allow(clippy::too_many_arguments)]
     fn load_from_components(
         &self,
         components: HashMap<ComponentName, ComponentElem>,
@@ -88,16 +554,151 @@ pub(crate) trait Loader {
         silent: bool,
         offloading_type: Option<Offloading>,
         source: Arc<ModelSource>,
+        isq: Option<IsqType>,
+        allow_saving: bool,
     ) -> Result<Arc<Mutex<dyn ModelPipeline>>>;
 }
 
 pub trait ModelPipeline: Send + Sync {
-    fn forward(
+    /// Run the text encoders on a batch of prompts.
+    fn encode_prompt(
         &mut self,
         prompts: Vec<String>,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<PromptEmbeds>;
+
+    /// Set the capacity of the cache of text-encoder outputs, in bytes.
+    fn set_prompt_cache_size(&mut self, size_in_bytes: usize);
+
+    /// Apply the LoRA contained in `tensors` to the model as an adapter named `name`, in addition to the adapters
+    /// already loaded. An adapter with the same name is replaced.
+    fn load_lora(
+        &mut self,
+        name: String,
+        tensors: HashMap<String, Tensor>,
+        scale: f64,
+    ) -> diffusion_rs_common::core::Result<()>;
+
+    /// Activate exactly the given LoRA adapters, with the given scales.
+    fn set_lora_scales(
+        &mut self,
+        scales: &[(String, f64)],
+    ) -> diffusion_rs_common::core::Result<()>;
+
+    fn unload_lora(&mut self, name: &str) -> diffusion_rs_common::core::Result<()>;
+
+    /// The loaded LoRA adapters, with their scales.
+    fn lora_adapters(&self) -> Vec<(String, f64)>;
+
+    fn fuse_loras(&mut self) -> diffusion_rs_common::core::Result<()>;
+
+    fn unfuse_loras(&mut self) -> diffusion_rs_common::core::Result<()>;
+
+    /// Write the components of the model in the diffusers layout.
+    fn save(&mut self, writer: &mut ModelWriter) -> Result<()>;
+
+    /// Returns the output selected by [`DiffusionGenerationParams::output_type`] and the seed used for the
+    /// initial noise of each image. `negative_embeds` are given exactly when true CFG is enabled and match the
+    /// batch size of `embeds`.
+    fn forward(
+        &mut self,
+        embeds: PromptEmbeds,
+        negative_embeds: Option<PromptEmbeds>,
         params: DiffusionGenerationParams,
+        init_image: Option<InitImage>,
         offloading_type: Option<Offloading>,
-    ) -> diffusion_rs_common::core::Result<Tensor>;
+    ) -> diffusion_rs_common::core::Result<(Tensor, Vec<u64>)>;
+}
+
+/// Convert images to a `(b, 3, height, width)` F32 tensor with values in `[-1, 1]`, resizing as necessary.
+pub(crate) fn images_to_tensor(
+    images: &[DynamicImage],
+    height: usize,
+    width: usize,
+    device: &Device,
+) -> diffusion_rs_common::core::Result<Tensor> {
+    let mut tensors = Vec::new();
+    for image in images {
+        #[allow(clippy::cast_possible_truncation)]
+        let image = image
+            .resize_exact(width as u32, height as u32, FilterType::Lanczos3)
+            .to_rgb8();
+        let data = Tensor::from_vec(image.into_raw(), (height, width, 3), &Device::Cpu)?;
+        tensors.push(data.permute((2, 0, 1))?);
+    }
+    let xs = Tensor::stack(&tensors, 0)?.to_dtype(DType::F32)?;
+    ((xs / 127.5)? - 1.)?.to_device(device)
+}
+
+/// Convert a `(b, 3, h, w)` U8 tensor to images.
+pub(crate) fn tensor_to_images(
+    img: &Tensor,
+) -> diffusion_rs_common::core::Result<Vec<DynamicImage>> {
+    let (_b, c, h, w) = img.dims4()?;
+    if c != 3 {
+        diffusion_rs_common::bail!("Expected 3 channels in image output");
+    }
+    let mut images = Vec::new();
+    for b_img in img.chunk(img.dim(0)?, 0)? {
+        let flattened = b_img.squeeze(0)?.permute((1, 2, 0))?.flatten_all()?;
+        #[allow(clippy::cast_possible_truncation)]
+        images.push(DynamicImage::ImageRgb8(
+            RgbImage::from_raw(w as u32, h as u32, flattened.to_vec1::<u8>()?).ok_or(
+                diffusion_rs_common::core::Error::Msg("RgbImage has invalid capacity.".to_string()),
+            )?,
+        ));
+    }
+    Ok(images)
+}
+
+/// Convert masks to a `(b, 1, height, width)` F32 tensor with values in `[0, 1]`, resizing as necessary.
+pub(crate) fn masks_to_tensor(
+    masks: &[DynamicImage],
+    height: usize,
+    width: usize,
+    device: &Device,
+) -> diffusion_rs_common::core::Result<Tensor> {
+    let mut tensors = Vec::new();
+    for mask in masks {
+        #[allow(clippy::cast_possible_truncation)]
+        let mask = mask
+            .resize_exact(width as u32, height as u32, FilterType::Triangle)
+            .to_luma8();
+        tensors.push(Tensor::from_vec(
+            mask.into_raw(),
+            (1, height, width),
+            &Device::Cpu,
+        )?);
+    }
+    let xs = Tensor::stack(&tensors, 0)?.to_dtype(DType::F32)?;
+    (xs / 255.)?.to_device(device)
+}
+
+/// The canvas to inpaint for outpainting `image` with `padding`: the image over a grey background, with a mask
+/// which is white over the padding and black over the image.
+fn outpaint_canvas(image: &DynamicImage, padding: OutpaintPadding) -> (DynamicImage, DynamicImage) {
+    let (w, h) = (image.width(), image.height());
+    let canvas_w = w + padding.left + padding.right;
+    let canvas_h = h + padding.top + padding.bottom;
+
+    let mut canvas = RgbImage::from_pixel(canvas_w, canvas_h, Rgb([127, 127, 127]));
+    imageops::replace(
+        &mut canvas,
+        &image.to_rgb8(),
+        padding.left.into(),
+        padding.top.into(),
+    );
+    let mut mask = GrayImage::from_pixel(canvas_w, canvas_h, Luma([255]));
+    imageops::replace(
+        &mut mask,
+        &GrayImage::from_pixel(w, h, Luma([0])),
+        padding.left.into(),
+        padding.top.into(),
+    );
+    (
+        DynamicImage::ImageRgb8(canvas),
+        DynamicImage::ImageLuma8(mask),
+    )
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -106,104 +707,231 @@ struct ModelIndex {
     name: String,
 }
 
+impl ModelIndex {
+    fn from_slice(data: &[u8]) -> std::result::Result<Self, ModelFileError> {
+        serde_json::from_slice(data).map_err(|e| ModelFileError::InvalidJson {
+            file: "model_index.json".to_string(),
+            error: e.to_string(),
+        })
+    }
+
+    /// The loader for the pipeline class of the model.
+    fn loader(&self) -> std::result::Result<Box<dyn Loader>, ModelFileError> {
+        match self.name.as_str() {
+            "FluxPipeline" => Ok(Box::new(FluxLoader)),
+            other => Err(ModelFileError::UnknownPipeline(other.to_string())),
+        }
+    }
+}
+
+/// Options for [`Pipeline::load`].
+///
+/// The [`Default`] implementation selects the dtype automatically, without offloading or quantization.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// Disable the progress bars while loading.
+    pub silent: bool,
+    /// The Hugging Face token. Only applicable for Hugging Face models, and the LoRA adapters loaded into them.
+    pub token: TokenSource,
+    /// The revision of the Hugging Face model, `main` if not specified. Only applicable for Hugging Face models.
+    pub revision: Option<String>,
+    pub offloading_type: Option<Offloading>,
+    pub dtype: ModelDType,
+    /// Quantize the linear layers of the model in-situ while loading it, which allows running unquantized
+    /// checkpoints with less memory.
+    pub isq: Option<IsqType>,
+    /// Load Hugging Face models and LoRA adapters from the local cache only, without accessing the network. This
+    /// is also enabled by setting `HF_HUB_OFFLINE=1`. All the files missing from the cache are reported in a
+    /// [`ModelFileError::MissingOfflineFiles`] error.
+    pub offline: bool,
+    /// Keep the tensors and files of the model which are needed to save it with [`Pipeline::save_pretrained`] or
+    /// [`Pipeline::save_dduf`]. This holds on to the tensors of the model besides its linear layers, which may be
+    /// copies when they are converted while loading, along with the tokenizer and scheduler files.
+    pub allow_saving: bool,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            silent: false,
+            token: TokenSource::CacheToken,
+            revision: None,
+            offloading_type: None,
+            dtype: ModelDType::Auto,
+            isq: None,
+            offline: false,
+            allow_saving: false,
+        }
+    }
+}
+
 /// Represents the model and provides methods to load and interact with it.
 pub struct Pipeline {
     model: Arc<Mutex<dyn ModelPipeline>>,
+    /// The `model_index.json` of the model, to save it.
+    model_index: String,
     offloading_type: Option<Offloading>,
+    scheduler_overrides: SchedulerOverrides,
+    /// The Hugging Face token, to download LoRA adapters.
+    token: TokenSource,
+    /// Whether LoRA adapters from the Hugging Face Hub are read from the local cache.
+    offline: bool,
 }
 
 impl Pipeline {
-    /// Load the model.
-    ///
-    /// Note:
-    /// - `token` and `revision` are only applicable for Hugging Face models.
-    pub fn load(
-        mut source: ModelSource,
-        silent: bool,
-        token: TokenSource,
-        revision: Option<String>,
-        offloading_type: Option<Offloading>,
-        dtype: &dyn TryIntoDType,
-    ) -> Result<Self> {
+    /// Load the model with the given [`LoadOptions`].
+    pub fn load(mut source: ModelSource, options: LoadOptions) -> Result<Self> {
+        let LoadOptions {
+            silent,
+            token,
+            revision,
+            offloading_type,
+            dtype,
+            isq,
+            offline,
+            allow_saving,
+        } = options;
         info!("loading from source: {source}.");
+        let offline = offline || hf_hub_offline();
 
         let mut components = HashMap::new();
+        let model_index;
+        let component_files = source.component_files().to_vec();
         let model_loader = {
-            let mut loader = FileLoader::from_model_source(&mut source, silent, token, revision)?;
+            let transformer_model_id = match source.base_source() {
+                ModelSource::ModelIdWithTransformer {
+                    transformer_model_id,
+                    ..
+                } => Some(transformer_model_id.clone()),
+                _ => None,
+            };
+            let mut loader = FileLoader::from_model_source(
+                &mut source,
+                silent,
+                token.clone(),
+                revision,
+                offline,
+            )
+            .map_err(|e| not_cached_error(e, transformer_model_id.as_deref()))?;
             let files = loader.list_files()?;
             let transformer_files = loader.list_transformer_files()?;
 
             if !files.contains(&"model_index.json".to_string()) {
-                anyhow::bail!("Expected `model_index.json` file present.");
+                let file = "model_index.json".to_string();
+                if offline {
+                    return Err(ModelFileError::MissingOfflineFiles(vec![file]).into());
+                }
+                return Err(ModelFileError::MissingFile(file).into());
             }
 
-            let ModelIndex { name } = serde_json::from_str(
-                &loader
-                    .read_file_copied("model_index.json", false)?
-                    .read_to_string_owned()?,
-            )?;
-
-            let model_loader: Box<dyn Loader> = match name.as_str() {
-                "FluxPipeline" => Box::new(FluxLoader),
-                other => anyhow::bail!("Unexpected loader type `{other:?}`."),
-            };
+            model_index = loader
+                .read_file_copied("model_index.json", false)?
+                .read_to_string_owned()?;
+            let model_loader = ModelIndex::from_slice(model_index.as_bytes())?.loader()?;
+            if offline {
+                let missing = missing_offline_files(
+                    &mut loader,
+                    &files,
+                    transformer_files.as_deref(),
+                    model_loader.as_ref(),
+                    &component_files,
+                    &token,
+                )?;
+                if !missing.is_empty() {
+                    return Err(ModelFileError::MissingOfflineFiles(missing).into());
+                }
+            }
+            for name in model_loader.required_files() {
+                if !files.iter().any(|file| file == name) {
+                    return Err(ModelFileError::MissingFile(name.to_string()).into());
+                }
+            }
 
             info!("model architecture is: {}", model_loader.name());
 
-            for component in NiceProgressBar::<_, 'g'>(
-                model_loader.required_component_names().into_iter(),
-                "Loading components",
-            ) {
-                let (files, from_transformer, dir) =
-                    if component == ComponentName::Transformer && transformer_files.is_some() {
-                        (transformer_files.clone().unwrap(), true, "".to_string())
-                    } else {
-                        (files.clone(), false, format!("{component}/"))
-                    };
-                let files_for_component = files
-                    .iter()
-                    .filter(|file| file.starts_with(&dir))
-                    .filter(|file| !file.ends_with('/'))
-                    .cloned()
-                    .collect::<Vec<_>>();
+            let component_names = model_loader.required_component_names();
+            for (component, _) in &component_files {
+                if !component_names.iter().any(|x| x.to_string() == *component) {
+                    anyhow::bail!(
+                        "Unknown component `{component}` for a file override, expected one of {}.",
+                        component_names
+                            .iter()
+                            .map(|x| format!("`{x}`"))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+            }
+
+            for component in
+                NiceProgressBar::<_, 'g'>(component_names.into_iter(), "Loading components")
+            {
+                let component_files = ComponentFiles::new(
+                    &component,
+                    &files,
+                    transformer_files.as_deref(),
+                    &component_files,
+                );
+                let from_transformer = component_files.from_transformer;
 
                 // Try to determine the component's type.
+                // 0) SingleFile: the weights are overridden by a GGUF or safetensors file, only the config.json is
+                //    used, or they are in a GGUF file, as saved for models with GGUF-quantized layers
                 // 1) Model: models contain .safetensors and potentially a config.json
                 // 2) Config: general config, a file ends with .json
                 // 3) Other: doesn't have safetensors and is not all json
-                let component_elem = if files_for_component
-                    .iter()
-                    .any(|file| file.ends_with(".safetensors"))
-                {
-                    let mut safetensors = HashMap::new();
-                    for file in files_for_component
-                        .iter()
-                        .filter(|file| file.ends_with(".safetensors"))
-                    {
-                        safetensors.insert(file.clone(), loader.read_file(file, from_transformer)?);
+                if component_files.files.is_empty() {
+                    return Err(ModelFileError::MissingComponent {
+                        component: component.to_string(),
+                        pipeline: model_loader.name().to_string(),
                     }
-                    ComponentElem::Model {
-                        safetensors,
-                        config: loader.read_file(&format!("{dir}config.json"), from_transformer)?,
+                    .into());
+                }
+                let config_file = component_files.config_file();
+                if component_files.has_weights() && !component_files.files.contains(&config_file) {
+                    return Err(ModelFileError::MissingFile(config_file).into());
+                }
+                let component_elem = if let Some(file) = component_files.override_file {
+                    ComponentElem::SingleFile {
+                        file: FileData::Path(single_file::resolve_component_file(
+                            file, silent, &token, offline,
+                        )?),
+                        config: loader.read_file(&config_file, from_transformer)?,
                     }
-                } else if files_for_component
-                    .iter()
-                    .all(|file| file.ends_with(".json"))
-                {
-                    let mut files = HashMap::new();
-                    for file in files_for_component
-                        .iter()
-                        .filter(|file| file.ends_with(".json"))
-                    {
-                        files.insert(file.clone(), loader.read_file(file, from_transformer)?);
+                } else if let Some(file) = component_files.gguf_file() {
+                    ComponentElem::SingleFile {
+                        file: loader.read_file(file, from_transformer)?,
+                        config: loader.read_file(&config_file, from_transformer)?,
                     }
-                    ComponentElem::Config { files }
                 } else {
-                    let mut files = HashMap::new();
-                    for file in files_for_component {
-                        files.insert(file.clone(), loader.read_file(&file, from_transformer)?);
+                    let safetensors_files = component_files.safetensors_files(&mut loader)?;
+                    if !safetensors_files.is_empty() {
+                        let mut safetensors = HashMap::new();
+                        for file in safetensors_files {
+                            let data = loader.read_file(&file, from_transformer)?;
+                            safetensors.insert(file, data);
+                        }
+                        ComponentElem::Model {
+                            safetensors,
+                            config: loader.read_file(&config_file, from_transformer)?,
+                        }
+                    } else if component_files
+                        .files
+                        .iter()
+                        .all(|file| file.ends_with(".json"))
+                    {
+                        let mut files = HashMap::new();
+                        for file in &component_files.files {
+                            files.insert(file.clone(), loader.read_file(file, from_transformer)?);
+                        }
+                        ComponentElem::Config { files }
+                    } else {
+                        let mut files = HashMap::new();
+                        for file in &component_files.files {
+                            files.insert(file.clone(), loader.read_file(file, from_transformer)?);
+                        }
+                        ComponentElem::Other { files }
                     }
-                    ComponentElem::Other { files }
                 };
                 components.insert(component, component_elem);
             }
@@ -227,14 +955,59 @@ impl Pipeline {
             silent,
             offloading_type,
             Arc::new(source),
+            isq,
+            allow_saving,
         )?;
 
         Ok(Self {
             model,
+            model_index,
             offloading_type,
+            scheduler_overrides: SchedulerOverrides::default(),
+            token,
+            offline,
         })
     }
 
+    /// Save the model to the directory `dir` in the diffusers layout, keeping the quantization of its layers,
+    /// for example after loading it with ISQ.
+    ///
+    /// Components with GGUF-quantized layers have their weights saved to a GGUF file, and the other components
+    /// to a safetensors file. LoRA adapters must be fused (see [`Pipeline::fuse_loras`]) or unloaded first.
+    ///
+    /// The model must have been loaded with [`LoadOptions::allow_saving`].
+    pub fn save_pretrained(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let writer = ModelWriter::dir(dir.as_ref())?;
+        self.write_model(writer)
+    }
+
+    /// Save the model to a DDUF file, like [`Pipeline::save_pretrained`]. The result can be loaded with
+    /// [`ModelSource::dduf`].
+    ///
+    /// DDUF files cannot contain GGUF files, so models with GGUF-quantized layers, such as those quantized with
+    /// [`IsqType::Q4K`], can only be saved with [`Pipeline::save_pretrained`].
+    pub fn save_dduf(&self, file: impl AsRef<Path>) -> Result<()> {
+        let writer = ModelWriter::dduf(file.as_ref())?;
+        self.write_model(writer)
+    }
+
+    fn write_model(&self, mut writer: ModelWriter) -> Result<()> {
+        writer.write_file("model_index.json", self.model_index.as_bytes())?;
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .save(&mut writer)?;
+        writer.finish()
+    }
+
+    /// Override the scheduler config of the model, such as the shift, for every generation with this pipeline.
+    ///
+    /// Overrides in [`DiffusionGenerationParams::scheduler_overrides`] take precedence over these.
+    pub fn with_scheduler_overrides(mut self, overrides: SchedulerOverrides) -> Self {
+        self.scheduler_overrides = overrides;
+        self
+    }
+
     /// Generate images based on prompts and generation parameters.
     ///
     /// If a multiple prompts are specified, they are padded and run together as a batch.
@@ -242,30 +1015,432 @@ impl Pipeline {
         &self,
         prompts: Vec<String>,
         params: DiffusionGenerationParams,
-    ) -> anyhow::Result<Vec<DynamicImage>> {
+    ) -> anyhow::Result<DiffusionGenerationOutput> {
+        check_prompts(&prompts)?;
+        self.run(Prompts::Text(prompts), params, None)
+    }
+
+    /// Run the text encoders on prompts. The embeddings can be reused across calls to
+    /// [`Pipeline::forward_with_embeds`], for example to sweep seeds or step counts for the same prompts.
+    pub fn encode_prompt(&self, prompts: Vec<String>) -> anyhow::Result<PromptEmbeds> {
+        check_prompts(&prompts)?;
+        let mut model = self.model.lock().expect("Could not lock model!");
+        #[cfg(feature = "metal")]
+        let res = objc::rc::autoreleasepool(|| model.encode_prompt(prompts, self.offloading_type));
+        #[cfg(not(feature = "metal"))]
+        let res = model.encode_prompt(prompts, self.offloading_type);
+        Ok(res?)
+    }
+
+    /// Set the capacity of the cache of text-encoder outputs, in bytes. A size of 0 disables the cache.
+    ///
+    /// Encoded prompts are cached on the device so that repeated prompts skip the text encoders. The cache
+    /// holds 128 MiB by default.
+    pub fn set_prompt_cache_size(&self, size_in_bytes: usize) {
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .set_prompt_cache_size(size_in_bytes);
+    }
+
+    /// Load a LoRA adapter and apply it to the model with the given scale. The adapter is named `path_or_hf_id`.
+    ///
+    /// `path_or_hf_id` is a `.safetensors` file, a directory containing one, or a Hugging Face model ID. LoRAs in the
+    /// diffusers, kohya and original (BFL) formats are supported, including adapters for the text encoders.
+    ///
+    /// Adapters are applied as a low-rank delta at runtime, so this works on top of quantized models. Loading
+    /// several LoRAs applies all of them.
+    pub fn load_lora<S: ToString>(&self, path_or_hf_id: S, scale: f64) -> Result<()> {
+        let name = path_or_hf_id.to_string();
+        self.load_lora_adapter(&name, name.clone(), scale)
+    }
+
+    /// Load a LoRA adapter like [`Pipeline::load_lora`], under the given name. An adapter with the same name is
+    /// replaced, which allows swapping adapters without reloading the model.
+    pub fn load_lora_adapter<S: ToString>(
+        &self,
+        path_or_hf_id: S,
+        adapter_name: String,
+        scale: f64,
+    ) -> Result<()> {
+        let tensors =
+            lora::load_lora_tensors(&path_or_hf_id.to_string(), &self.token, self.offline)?;
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .load_lora(adapter_name, tensors, scale)?;
+        Ok(())
+    }
+
+    /// Activate exactly the given LoRA adapters, with the given scales. Other adapters stay loaded but are
+    /// inactive until they are activated again.
+    pub fn set_lora_adapters(&self, adapters: &[(&str, f64)]) -> Result<()> {
+        let scales = adapters
+            .iter()
+            .map(|(name, scale)| (name.to_string(), *scale))
+            .collect::<Vec<_>>();
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .set_lora_scales(&scales)?;
+        Ok(())
+    }
+
+    /// Unload a LoRA adapter, restoring the layers it applied to.
+    pub fn unload_lora(&self, adapter_name: &str) -> Result<()> {
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .unload_lora(adapter_name)?;
+        Ok(())
+    }
+
+    /// The names of the loaded LoRA adapters, with their scales. Inactive adapters have a scale of 0.
+    pub fn lora_adapters(&self) -> Vec<(String, f64)> {
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .lora_adapters()
+    }
+
+    /// Fuse the active LoRA adapters into the weights of the unquantized layers, at their current scales. This
+    /// removes the runtime cost of the adapters on those layers. Adapters on quantized layers remain applied at
+    /// runtime.
+    ///
+    /// The original weights are kept in CPU memory while fused, so that [`Pipeline::unfuse_loras`] restores them
+    /// exactly. Adapters cannot be loaded, rescaled or unloaded while fused.
+    pub fn fuse_loras(&self) -> Result<()> {
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .fuse_loras()?;
+        Ok(())
+    }
+
+    /// Undo [`Pipeline::fuse_loras`], applying the adapters at runtime again.
+    pub fn unfuse_loras(&self) -> Result<()> {
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .unfuse_loras()?;
+        Ok(())
+    }
+
+    /// Generate images from prompt embeddings computed by [`Pipeline::encode_prompt`], skipping the text encoders.
+    ///
+    /// When true CFG is enabled, `negative_embeds` may be given as one embedding per prompt or a single embedding
+    /// for all prompts. Otherwise, [`DiffusionGenerationParams::negative_prompts`] are encoded.
+    pub fn forward_with_embeds(
+        &self,
+        embeds: PromptEmbeds,
+        negative_embeds: Option<PromptEmbeds>,
+        params: DiffusionGenerationParams,
+    ) -> anyhow::Result<DiffusionGenerationOutput> {
+        self.run(
+            Prompts::Embeds {
+                embeds,
+                negative_embeds,
+            },
+            params,
+            None,
+        )
+    }
+
+    /// Generate images starting from existing images (image-to-image).
+    ///
+    /// The initial images are resized to the requested `height` and `width`, encoded and noised according
+    /// to `strength`. Only the remaining fraction of `num_steps` given by `strength` is run:
+    /// lower values stay closer to the initial images and a value of 1 ignores their content.
+    ///
+    /// Either one initial image per prompt or a single image shared by all prompts must be given.
+    pub fn forward_img2img(
+        &self,
+        prompts: Vec<String>,
+        init_images: Vec<DynamicImage>,
+        strength: f64,
+        params: DiffusionGenerationParams,
+    ) -> anyhow::Result<DiffusionGenerationOutput> {
+        check_prompts(&prompts)?;
+        if !(strength > 0. && strength <= 1.) {
+            anyhow::bail!("`strength` must be in (0, 1], got {strength}.");
+        }
+        if init_images.len() != 1 && init_images.len() != prompts.len() {
+            anyhow::bail!(
+                "Expected 1 or {} initial images, got {}.",
+                prompts.len(),
+                init_images.len()
+            );
+        }
+        self.run(
+            Prompts::Text(prompts),
+            params,
+            Some(InitImage {
+                images: init_images,
+                strength,
+                masks: None,
+            }),
+        )
+    }
+
+    /// Regenerate the masked regions of existing images (inpainting).
+    ///
+    /// Masks are single-channel images: white regions are generated and black regions are kept from the
+    /// initial images. At every denoising step, the kept regions are replaced with the initial images
+    /// noised to the current timestep. `strength` behaves as in [`Pipeline::forward_img2img`] and is
+    /// usually 1.
+    ///
+    /// Either one initial image and mask per prompt or a single image or mask shared by all prompts must be given.
+    pub fn forward_inpaint(
+        &self,
+        prompts: Vec<String>,
+        init_images: Vec<DynamicImage>,
+        masks: Vec<DynamicImage>,
+        strength: f64,
+        params: DiffusionGenerationParams,
+    ) -> anyhow::Result<DiffusionGenerationOutput> {
+        check_prompts(&prompts)?;
+        if !(strength > 0. && strength <= 1.) {
+            anyhow::bail!("`strength` must be in (0, 1], got {strength}.");
+        }
+        if init_images.len() != 1 && init_images.len() != prompts.len() {
+            anyhow::bail!(
+                "Expected 1 or {} initial images, got {}.",
+                prompts.len(),
+                init_images.len()
+            );
+        }
+        if masks.len() != 1 && masks.len() != prompts.len() {
+            anyhow::bail!(
+                "Expected 1 or {} masks, got {}.",
+                prompts.len(),
+                masks.len()
+            );
+        }
+        self.run(
+            Prompts::Text(prompts),
+            params,
+            Some(InitImage {
+                images: init_images,
+                strength,
+                masks: Some(masks),
+            }),
+        )
+    }
+
+    /// Extend existing images beyond their borders (outpainting).
+    ///
+    /// Each image is padded as specified by `padding` and the padded regions are generated by inpainting.
+    /// The padded canvas is resized to the requested `height` and `width`, so these should usually be set to
+    /// the padded size to avoid distortion.
+    pub fn forward_outpaint(
+        &self,
+        prompts: Vec<String>,
+        init_images: Vec<DynamicImage>,
+        padding: OutpaintPadding,
+        params: DiffusionGenerationParams,
+    ) -> anyhow::Result<DiffusionGenerationOutput> {
+        check_prompts(&prompts)?;
+        let (canvases, masks) = init_images
+            .iter()
+            .map(|image| outpaint_canvas(image, padding))
+            .unzip();
+        self.forward_inpaint(prompts, canvases, masks, 1., params)
+    }
+
+    fn run(
+        &self,
+        prompts: Prompts,
+        mut params: DiffusionGenerationParams,
+        init_image: Option<InitImage>,
+    ) -> anyhow::Result<DiffusionGenerationOutput> {
+        params.scheduler_overrides = params.scheduler_overrides.or(self.scheduler_overrides);
+        if params
+            .preview
+            .as_ref()
+            .is_some_and(|p| p.every_n_steps == 0)
+        {
+            anyhow::bail!("`every_n_steps` for previews must be nonzero.");
+        }
+        let output_type = params.output_type;
         let mut model = self.model.lock().expect("Could not lock model!");
+        let generate = || {
+            let (embeds, negative_embeds) =
+                prompts.encode(&mut *model, &params, self.offloading_type)?;
+            model.forward(
+                embeds,
+                negative_embeds,
+                params,
+                init_image,
+                self.offloading_type,
+            )
+        };
         #[cfg(feature = "metal")]
-        let img =
-            objc::rc::autoreleasepool(|| model.forward(prompts, params, self.offloading_type))?;
+        let res = objc::rc::autoreleasepool(generate);
         #[cfg(not(feature = "metal"))]
-        let img = model.forward(prompts, params, self.offloading_type)?;
-
-        let (_b, c, h, w) = img.dims4()?;
-        let mut images = Vec::new();
-        for b_img in img.chunk(img.dim(0)?, 0)? {
-            let flattened = b_img.squeeze(0)?.permute((1, 2, 0))?.flatten_all()?;
-            if c != 3 {
-                anyhow::bail!("Expected 3 channels in image output");
+        let res = generate();
+        let (img, seeds) = match res {
+            Ok(res) => res,
+            Err(e) if GenerationCancelled::is_cancellation(&e) => {
+                return Err(GenerationCancelled.into())
             }
-            #[allow(clippy::cast_possible_truncation)]
-            images.push(DynamicImage::ImageRgb8(
-                RgbImage::from_raw(w as u32, h as u32, flattened.to_vec1::<u8>()?).ok_or(
-                    diffusion_rs_common::core::Error::Msg(
-                        "RgbImage has invalid capacity.".to_string(),
-                    ),
-                )?,
-            ));
+            Err(e) => return Err(e.into()),
+        };
+
+        match output_type {
+            OutputType::Image => Ok(DiffusionGenerationOutput {
+                images: tensor_to_images(&img)?,
+                tensor: None,
+                seeds,
+            }),
+            OutputType::Tensor | OutputType::Latents | OutputType::PackedLatents => {
+                Ok(DiffusionGenerationOutput {
+                    images: Vec::new(),
+                    tensor: Some(img),
+                    seeds,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use diffusion_rs_common::cached_snapshot_in;
+    use hf_hub::Cache;
+
+    use super::*;
+    use crate::test_utils::TmpDir;
+
+    const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    /// Write `files` to the snapshot of `org/model` in the Hugging Face cache `cache`, with `main` pointing to it,
+    /// as downloaded by `hf_hub`.
+    fn write_snapshot(cache: &Path, files: &[(&str, &str)]) {
+        let repo = cache.join("models--org--model");
+        fs::create_dir_all(repo.join("refs")).unwrap();
+        fs::write(repo.join("refs/main"), format!("{COMMIT}\n")).unwrap();
+        for (name, data) in files {
+            let path = repo.join("snapshots").join(COMMIT).join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+    }
+
+    #[test]
+    fn outpaint_canvas_layout() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 2, Rgb([10, 20, 30])));
+        let padding = OutpaintPadding {
+            left: 1,
+            right: 2,
+            top: 3,
+            bottom: 0,
+        };
+        let (canvas, mask) = outpaint_canvas(&image, padding);
+        let (canvas, mask) = (canvas.to_rgb8(), mask.to_luma8());
+        assert_eq!(canvas.dimensions(), (6, 5));
+        assert_eq!(mask.dimensions(), (6, 5));
+        for (x, y, pixel) in canvas.enumerate_pixels() {
+            let inside = (1..4).contains(&x) && (3..5).contains(&y);
+            let (expected, expected_mask) = if inside {
+                (Rgb([10, 20, 30]), Luma([0]))
+            } else {
+                (Rgb([127, 127, 127]), Luma([255]))
+            };
+            assert_eq!(*pixel, expected, "({x}, {y})");
+            assert_eq!(*mask.get_pixel(x, y), expected_mask, "({x}, {y})");
+        }
+
+        // Without padding, the whole image is kept.
+        let (canvas, mask) = outpaint_canvas(&image, OutpaintPadding::default());
+        assert_eq!(canvas.to_rgb8(), image.to_rgb8());
+        assert!(mask.to_luma8().pixels().all(|p| *p == Luma([0])));
+    }
+
+    #[test]
+    fn offline_snapshot() {
+        let tmp = TmpDir::new("hf_cache");
+        let index = r#"{"weight_map": {"a": "model-00001-of-00002.safetensors", "b": "model-00002-of-00002.safetensors"}}"#;
+        write_snapshot(
+            tmp.path(),
+            &[
+                ("model_index.json", r#"{"_class_name": "FluxPipeline"}"#),
+                ("scheduler/scheduler_config.json", "{}"),
+                ("text_encoder/config.json", "{}"),
+                ("text_encoder/model.safetensors", ""),
+                ("text_encoder_2/config.json", "{}"),
+                ("text_encoder_2/model.safetensors.index.json", index),
+                ("text_encoder_2/model-00001-of-00002.safetensors", ""),
+                ("tokenizer/vocab.json", "{}"),
+                ("tokenizer/merges.txt", ""),
+                ("tokenizer_2/tokenizer_config.json", "{}"),
+                ("transformer/diffusion_pytorch_model.safetensors", ""),
+                ("vae/config.json", "{}"),
+            ],
+        );
+        let cache = Cache::new(tmp.path().to_path_buf());
+        let snapshot = tmp.path().join("models--org--model/snapshots").join(COMMIT);
+        assert_eq!(
+            cached_snapshot_in(&cache, "org/model", "main").unwrap(),
+            snapshot
+        );
+        assert_eq!(
+            cached_snapshot_in(&cache, "org/model", COMMIT).unwrap(),
+            snapshot
+        );
+
+        let mut loader = FileLoader::Local(snapshot);
+        let files = loader.list_files().unwrap();
+        let missing = missing_offline_files(
+            &mut loader,
+            &files,
+            None,
+            &FluxLoader,
+            &[],
+            &TokenSource::None,
+        )
+        .unwrap();
+        assert_eq!(
+            missing,
+            [
+                "tokenizer_2/tokenizer.json",
+                "text_encoder_2/model-00002-of-00002.safetensors",
+                "transformer/config.json",
+                "vae/*.safetensors",
+            ]
+        );
+    }
+
+    #[test]
+    fn offline_snapshot_missing() {
+        let tmp = TmpDir::new("hf_cache_missing");
+        write_snapshot(tmp.path(), &[("model_index.json", "{}")]);
+        let cache = Cache::new(tmp.path().to_path_buf());
+
+        for (model_id, revision) in [("org/model", "dev"), ("org/other", "main")] {
+            let err = cached_snapshot_in(&cache, model_id, revision).unwrap_err();
+            assert_eq!(
+                (err.model_id.as_str(), err.revision.as_str()),
+                (model_id, revision)
+            );
+            let err = not_cached_error(err.into(), None);
+            assert_eq!(
+                err.downcast_ref::<ModelFileError>(),
+                Some(&ModelFileError::MissingOfflineFiles(vec![
+                    "model_index.json".to_string()
+                ]))
+            );
         }
-        Ok(images)
+        let err = cached_snapshot_in(&cache, "org/other", "main").unwrap_err();
+        let err = not_cached_error(err.into(), Some("org/other"));
+        assert_eq!(
+            err.downcast_ref::<ModelFileError>(),
+            Some(&ModelFileError::MissingOfflineFiles(vec![
+                "org/other/config.json".to_string()
+            ]))
+        );
+        let err = not_cached_error(anyhow::anyhow!("other error"), None);
+        assert!(err.downcast_ref::<ModelFileError>().is_none());
     }
 }
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use super::PromptEmbeds;
+
+/// Default capacity of the prompt cache, in bytes.
+pub(crate) const DEFAULT_PROMPT_CACHE_SIZE: usize = 128 * 1024 * 1024;
+
+/// Bounded LRU cache of text-encoder outputs for single prompts.
+///
+/// Entries are keyed by the prompt and the sequence length it was padded to, because the per-token embeddings
+/// depend on the padding.
+pub(crate) struct PromptCache {
+    entries: HashMap<(String, usize), Entry>,
+    capacity: usize,
+    size: usize,
+    tick: u64,
+}
+
+struct Entry {
+    embeds: PromptEmbeds,
+    size: usize,
+    last_used: u64,
+}
+
+fn size_in_bytes(embeds: &PromptEmbeds) -> usize {
+    [&embeds.prompt_embeds, &embeds.pooled_prompt_embeds]
+        .iter()
+        .map(|t| t.elem_count() * t.dtype().size_in_bytes())
+        .sum()
+}
+
+impl PromptCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            size: 0,
+            tick: 0,
+        }
+    }
+
+    /// Set the capacity in bytes, evicting entries as necessary. A capacity of 0 disables the cache.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict(0);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    pub(crate) fn get(&mut self, prompt: &str, seq_len: usize) -> Option<PromptEmbeds> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(&(prompt.to_string(), seq_len))?;
+        entry.last_used = self.tick;
+        Some(entry.embeds.clone())
+    }
+
+    /// Insert the embeddings of a single prompt. Embeddings larger than the capacity are not cached.
+    pub(crate) fn insert(&mut self, prompt: String, seq_len: usize, embeds: PromptEmbeds) {
+        let size = size_in_bytes(&embeds);
+        if size > self.capacity {
+            return;
+        }
+        if let Some(old) = self.entries.remove(&(prompt.clone(), seq_len)) {
+            self.size -= old.size;
+        }
+        self.evict(size);
+        self.tick += 1;
+        self.size += size;
+        self.entries.insert(
+            (prompt, seq_len),
+            Entry {
+                embeds,
+                size,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    /// Evict the least recently used entries until `additional` bytes fit.
+    fn evict(&mut self, additional: usize) {
+        while self.size + additional > self.capacity {
+            let Some(key) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let entry = self.entries.remove(&key).unwrap();
+            self.size -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_common::core::{DType, Device, Tensor};
+
+    use super::*;
+
+    /// Embeddings of `len` f32 values, so `4 * len` bytes.
+    fn embeds(len: usize) -> PromptEmbeds {
+        PromptEmbeds {
+            prompt_embeds: Tensor::zeros((1, len, 1), DType::F32, &Device::Cpu).unwrap(),
+            pooled_prompt_embeds: Tensor::zeros((1, 0), DType::F32, &Device::Cpu).unwrap(),
+        }
+    }
+
+    fn keys(cache: &PromptCache) -> Vec<&str> {
+        let mut keys = cache
+            .entries
+            .keys()
+            .map(|(prompt, _)| prompt.as_str())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn insert_evicts_least_recently_used() {
+        let mut cache = PromptCache::new(40);
+        cache.insert("a".to_string(), 8, embeds(4));
+        cache.insert("b".to_string(), 8, embeds(4));
+        assert_eq!(cache.size, 32);
+        assert!(cache.get("a", 8).is_some());
+
+        // "b" is the least recently used entry.
+        cache.insert("c".to_string(), 8, embeds(3));
+        assert_eq!(keys(&cache), ["a", "c"]);
+        assert_eq!(cache.size, 28);
+        assert!(cache.get("b", 8).is_none());
+
+        // The sequence length is part of the key.
+        assert!(cache.get("a", 16).is_none());
+    }
+
+    #[test]
+    fn insert_replaces_existing_key() {
+        let mut cache = PromptCache::new(40);
+        cache.insert("a".to_string(), 8, embeds(4));
+        cache.insert("b".to_string(), 8, embeds(4));
+        // The old entry does not count towards the capacity, so nothing is evicted.
+        cache.insert("a".to_string(), 8, embeds(6));
+        assert_eq!(keys(&cache), ["a", "b"]);
+        assert_eq!(cache.size, 40);
+        let cached = cache.get("a", 8).unwrap();
+        assert_eq!(cached.prompt_embeds.dims(), [1, 6, 1]);
+    }
+
+    #[test]
+    fn set_capacity_evicts() {
+        let mut cache = PromptCache::new(40);
+        cache.insert("a".to_string(), 8, embeds(4));
+        cache.insert("b".to_string(), 8, embeds(4));
+        cache.set_capacity(20);
+        assert_eq!(keys(&cache), ["b"]);
+        assert_eq!(cache.size, 16);
+
+        cache.set_capacity(0);
+        assert!(cache.entries.is_empty());
+        assert_eq!(cache.size, 0);
+        cache.insert("a".to_string(), 8, embeds(4));
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn insert_skips_entries_larger_than_capacity() {
+        let mut cache = PromptCache::new(40);
+        cache.insert("a".to_string(), 8, embeds(4));
+        cache.insert("b".to_string(), 8, embeds(11));
+        assert_eq!(keys(&cache), ["a"]);
+        assert_eq!(cache.size, 16);
+    }
+}
@@ -1,49 +1,434 @@
-use diffusion_rs_common::{
-    core::{Result, Tensor},
-    NiceProgressBar,
+use std::fmt::Debug;
+
+use diffusion_rs_common::core::{Device, Error, Result, Tensor};
+use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
+
+use super::{
+    callbacks::{CancellationToken, GenerationCancelled, StepCallback},
+    scheduler::SchedulerType,
 };
 
-use super::scheduler::SchedulerType;
+/// Observers of the denoising loop.
+#[derive(Default)]
+pub struct SampleHooks<'a> {
+    pub callback: Option<&'a dyn StepCallback>,
+    pub cancellation: Option<&'a CancellationToken>,
+}
 
-pub enum Sampler {
-    FlowMatchEulerDiscrete,
+/// The denoising model, as seen by a [`Sampler`].
+///
+/// This is implemented for closures with a matching signature.
+pub trait DenoiseFn {
+    /// Predict the flow-matching velocity (noise minus data) of `latents` at timestep (sigma) `t`.
+    ///
+    /// Guidance, including true classifier-free guidance, is applied by the pipeline.
+    fn predict(&self, latents: &Tensor, t: f64) -> Result<Tensor>;
 }
 
-impl Sampler {
-    pub fn new(ty: &SchedulerType) -> Self {
-        match ty {
-            SchedulerType::FlowMatchEulerDiscrete => Self::FlowMatchEulerDiscrete,
+impl<F> DenoiseFn for F
+where
+    F: Fn(&Tensor, f64) -> Result<Tensor>,
+{
+    fn predict(&self, latents: &Tensor, t: f64) -> Result<Tensor> {
+        self(latents, t)
+    }
+}
+
+/// Services provided by the pipeline to a [`Sampler`] during one generation.
+pub struct SampleContext<'a> {
+    rngs: &'a mut [StdRng],
+    post_step: &'a dyn Fn(Tensor, f64) -> Result<Tensor>,
+    hooks: &'a SampleHooks<'a>,
+    step: usize,
+    total: usize,
+}
+
+impl<'a> SampleContext<'a> {
+    pub(crate) fn new(
+        rngs: &'a mut [StdRng],
+        post_step: &'a dyn Fn(Tensor, f64) -> Result<Tensor>,
+        hooks: &'a SampleHooks<'a>,
+        total: usize,
+    ) -> Self {
+        Self {
+            rngs,
+            post_step,
+            hooks,
+            step: 0,
+            total,
         }
     }
 
-    /// Run the denoising process over the given image.
+    /// Returns a [`GenerationCancelled`] error if the generation was cancelled. Samplers should call this before
+    /// every step.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.hooks.cancellation.is_some_and(|c| c.is_cancelled()) {
+            return Err(Error::wrap(GenerationCancelled));
+        }
+        Ok(())
+    }
+
+    /// Finish a step which brought the latents to timestep `t`, and return the latents to continue from.
     ///
-    /// Expects a step closure:
-    /// ```ignore
-    /// fn(img: &Tensor, t_vec: &Tensor) -> Result<Tensor>;
-    /// ``````
-    pub fn sample(
+    /// Samplers must call this after every step. It re-imposes the known regions when inpainting and invokes the
+    /// step callback.
+    pub fn end_step(&mut self, latents: Tensor, t: f64) -> Result<Tensor> {
+        let latents = (self.post_step)(latents, t)?;
+        self.step += 1;
+        if let Some(callback) = self.hooks.callback {
+            callback.on_step(self.step, self.total, t, &latents);
+        }
+        Ok(latents)
+    }
+
+    /// Standard normal noise shaped like `xs`, for stochastic samplers. Each element of the batch is drawn from
+    /// the generator seeded for the corresponding image, so results are reproducible per image.
+    pub fn randn_like(&mut self, xs: &Tensor) -> Result<Tensor> {
+        randn_like(xs, self.rngs)
+    }
+}
+
+/// A sampling algorithm for the denoising loop of flow-matching models.
+///
+/// Samplers integrate the latents along `timesteps`, which are decreasing sigmas ending with 0, by evaluating
+/// `model`. See [`SamplerType`] for the built-in samplers.
+pub trait Sampler: Send + Sync {
+    fn sample(
+        &self,
+        timesteps: &[f64],
+        latents: &Tensor,
+        model: &dyn DenoiseFn,
+        ctx: &mut SampleContext,
+    ) -> Result<Tensor>;
+}
+
+impl Debug for dyn Sampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sampler")
+    }
+}
+
+/// The built-in samplers.
+///
+/// If no sampler is specified in the generation parameters, one is chosen from the model's scheduler config.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerType {
+    /// First-order Euler. One model evaluation per step.
+    Euler,
+    /// Second-order Heun. Two model evaluations per step, except for the last one.
+    Heun,
+    /// Second-order multistep DPM-Solver++ (2M). One model evaluation per step, reusing the previous prediction.
+    DpmPp2M,
+    /// Euler ancestral, which re-injects fresh noise after every step. `eta` scales the amount of noise: 0 is
+    /// equivalent to [`SamplerType::Euler`] and 1 is fully ancestral.
+    EulerAncestral { eta: f64 },
+}
+
+impl SamplerType {
+    pub(crate) fn from_scheduler(ty: &SchedulerType) -> Self {
+        match ty {
+            SchedulerType::FlowMatchEulerDiscrete => Self::Euler,
+            SchedulerType::FlowMatchHeunDiscrete => Self::Heun,
+        }
+    }
+}
+
+impl Sampler for SamplerType {
+    fn sample(
         &self,
         timesteps: &[f64],
-        img: &Tensor,
-        step: impl Fn(&Tensor, &Tensor) -> Result<Tensor>,
+        latents: &Tensor,
+        model: &dyn DenoiseFn,
+        ctx: &mut SampleContext,
     ) -> Result<Tensor> {
-        match self {
-            Self::FlowMatchEulerDiscrete => {
-                let b_sz = img.dim(0)?;
-                let dev = img.device();
-                let t_vec = Tensor::full(1f32, b_sz, dev)?;
-                let mut img = img.clone();
-                for window in NiceProgressBar::<_, 'g'>(timesteps.windows(2), "Denoise loop") {
-                    let (t_curr, t_prev) = match window {
-                        [a, b] => (a, b),
-                        _ => continue,
+        if let Self::EulerAncestral { eta } = self {
+            if *eta < 0. {
+                diffusion_rs_common::bail!("`eta` must not be negative, got {eta}.");
+            }
+        }
+
+        let mut img = latents.clone();
+        // Previous data prediction and its sigma, for multistep samplers.
+        let mut previous: Option<(Tensor, f64)> = None;
+        for window in timesteps.windows(2) {
+            let (t_curr, t_prev) = match window {
+                [a, b] => (*a, *b),
+                _ => continue,
+            };
+            ctx.check_cancelled()?;
+
+            let pred = model.predict(&img, t_curr)?;
+            let next = match self {
+                Self::Euler => (&img + (pred * (t_prev - t_curr))?)?,
+                Self::Heun => {
+                    let euler = (&img + (&pred * (t_prev - t_curr))?)?;
+                    if t_prev > 0. {
+                        let pred_next = model.predict(&euler, t_prev)?;
+                        (&img + (((pred + pred_next)? * 0.5)? * (t_prev - t_curr))?)?
+                    } else {
+                        euler
+                    }
+                }
+                Self::DpmPp2M => {
+                    let denoised = (&img - (&pred * t_curr)?)?;
+                    let next = if t_prev > 0. {
+                        dpmpp_2m_step(&img, &denoised, t_curr, t_prev, previous.as_ref())?
+                    } else {
+                        // The final step is first order.
+                        denoised.clone()
                     };
-                    let pred = step(&img, &(&t_vec * *t_curr)?)?;
-                    img = (img + pred * (t_prev - t_curr))?
+                    previous = Some((denoised, t_curr));
+                    next
                 }
-                Ok(img)
-            }
+                Self::EulerAncestral { eta } => {
+                    let denoised = (&img - (&pred * t_curr)?)?;
+                    if t_prev > 0. {
+                        euler_ancestral_step(&img, &denoised, t_curr, t_prev, *eta, ctx)?
+                    } else {
+                        denoised
+                    }
+                }
+            };
+            img = ctx.end_step(next, t_prev)?;
         }
+        Ok(img)
+    }
+}
+
+/// Run a sampler over the given image.
+///
+/// Expects a step closure, returning the predicted velocity:
+/// ```ignore
+/// fn(img: &Tensor, t_vec: &Tensor) -> Result<Tensor>;
+/// ``````
+///
+/// and a post-step closure, applied to the updated image along with its new timestep after every step.
+/// This allows re-imposing known regions of the image, for example when inpainting:
+/// ```ignore
+/// fn(img: Tensor, t: f64) -> Result<Tensor>;
+/// ``````
+///
+/// Stochastic samplers draw the noise for each image in the batch from the corresponding generator in `rngs`.
+pub(crate) fn run_sampler(
+    sampler: &dyn Sampler,
+    timesteps: &[f64],
+    img: &Tensor,
+    step: impl Fn(&Tensor, &Tensor) -> Result<Tensor>,
+    post_step: impl Fn(Tensor, f64) -> Result<Tensor>,
+    rngs: &mut [StdRng],
+    hooks: &SampleHooks,
+) -> Result<Tensor> {
+    let b_sz = img.dim(0)?;
+    let dev = img.device().clone();
+    let model = |img: &Tensor, t: f64| step(img, &Tensor::full(t as f32, b_sz, &dev)?);
+    let total = timesteps.len().saturating_sub(1);
+    let mut ctx = SampleContext::new(rngs, &post_step, hooks, total);
+    sampler.sample(timesteps, img, &model, &mut ctx)
+}
+
+/// Log signal-to-noise ratio of a flow-matching sigma.
+fn lambda(sigma: f64) -> f64 {
+    (1. - sigma).ln() - sigma.ln()
+}
+
+/// One step of DPM-Solver++ with data prediction, for flow matching where `alpha = 1 - sigma`. This is second order
+/// if a previous prediction is given.
+fn dpmpp_2m_step(
+    img: &Tensor,
+    denoised: &Tensor,
+    sigma: f64,
+    sigma_next: f64,
+    previous: Option<&(Tensor, f64)>,
+) -> Result<Tensor> {
+    let h = lambda(sigma_next) - lambda(sigma);
+    let alpha_next = 1. - sigma_next;
+    // Starting at sigma = 1 gives an infinite step, where `exp(-h)` is 0.
+    let coeff = -alpha_next * (-h).exp_m1();
+    let data = match previous {
+        Some((previous, sigma_previous)) => {
+            let r = (lambda(sigma) - lambda(*sigma_previous)) / h;
+            // An infinite ratio, from a previous sigma of 1, degenerates to the first order update.
+            let d1 = ((denoised - previous)? * (1. / r))?;
+            (denoised + (d1 * 0.5)?)?
+        }
+        None => denoised.clone(),
+    };
+    (img * (sigma_next / sigma))? + (data * coeff)?
+}
+
+/// One step of Euler ancestral for rectified flow: step down to a lower sigma, then add fresh noise to reach the
+/// target sigma.
+fn euler_ancestral_step(
+    img: &Tensor,
+    denoised: &Tensor,
+    sigma: f64,
+    sigma_next: f64,
+    eta: f64,
+    ctx: &mut SampleContext,
+) -> Result<Tensor> {
+    let sigma_down = sigma_next * (1. + (sigma_next / sigma - 1.) * eta);
+    let alpha_next = 1. - sigma_next;
+    let alpha_down = 1. - sigma_down;
+    let renoise = (sigma_next.powi(2)
+        - sigma_down.powi(2) * alpha_next.powi(2) / alpha_down.powi(2))
+    .max(0.)
+    .sqrt();
+
+    let ratio = sigma_down / sigma;
+    let img = ((img * ratio)? + (denoised * (1. - ratio))?)?;
+    if eta == 0. {
+        return Ok(img);
+    }
+    let noise = ctx.randn_like(&img)?;
+    (img * (alpha_next / alpha_down))? + (noise * renoise)?
+}
+
+/// Standard normal noise shaped like `xs`, drawing each element of the batch from the corresponding generator.
+///
+/// The noise is drawn on the CPU so that it is identical on every device.
+fn randn_like(xs: &Tensor, rngs: &mut [StdRng]) -> Result<Tensor> {
+    let b_sz = xs.dim(0)?;
+    if rngs.len() != b_sz {
+        diffusion_rs_common::bail!("Expected {b_sz} generators, got {}.", rngs.len());
+    }
+    let per_sample = xs.elem_count() / b_sz;
+    let data = rngs
+        .iter_mut()
+        .flat_map(|rng| {
+            (0..per_sample)
+                .map(|_| rng.sample::<f32, _>(StandardNormal))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    Tensor::from_vec(data, xs.shape(), &Device::Cpu)?
+        .to_device(xs.device())?
+        .to_dtype(xs.dtype())
+}
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_common::core::{test_utils::max_abs_diff, DType};
+    use rand::SeedableRng;
+
+    use super::*;
+
+    const SAMPLERS: [SamplerType; 5] = [
+        SamplerType::Euler,
+        SamplerType::Heun,
+        SamplerType::DpmPp2M,
+        SamplerType::EulerAncestral { eta: 0. },
+        SamplerType::EulerAncestral { eta: 1. },
+    ];
+
+    const TIMESTEPS: [f64; 5] = [1., 0.9, 0.6, 0.2, 0.];
+
+    fn tensor(data: &[f32]) -> Tensor {
+        Tensor::new(data, &Device::Cpu)
+            .unwrap()
+            .reshape((2, data.len() / 2))
+            .unwrap()
+    }
+
+    /// Run `sampler` over `timesteps` from `latents`, with a generator seeded with `seed` for each image.
+    fn sample(
+        sampler: SamplerType,
+        timesteps: &[f64],
+        latents: &Tensor,
+        model: &dyn DenoiseFn,
+        seed: u64,
+    ) -> Tensor {
+        let mut rngs = (0..latents.dim(0).unwrap())
+            .map(|i| StdRng::seed_from_u64(seed + i as u64))
+            .collect::<Vec<_>>();
+        let post_step = |latents, _| Ok(latents);
+        let hooks = SampleHooks::default();
+        let mut ctx = SampleContext::new(&mut rngs, &post_step, &hooks, timesteps.len() - 1);
+        sampler.sample(timesteps, latents, model, &mut ctx).unwrap()
+    }
+
+    #[test]
+    fn constant_velocity_reaches_data() {
+        let x0 = tensor(&[0.5, -1., 2., 0.]);
+        let noise = tensor(&[1., 0.3, -0.7, 1.5]);
+        let velocity = (&noise - &x0).unwrap();
+        let model = |_: &Tensor, _: f64| Ok(velocity.clone());
+        // Stochastic samplers only reach the data with a model which predicts it from any latents.
+        for sampler in &SAMPLERS[..4] {
+            let out = sample(*sampler, &TIMESTEPS, &noise, &model, 0);
+            let diff = max_abs_diff(&out, &x0).unwrap();
+            assert!(diff < 1e-5, "{sampler:?}: {diff}");
+        }
+    }
+
+    #[test]
+    fn exact_denoiser_reaches_data() {
+        let x0 = tensor(&[0.5, -1., 2., 0.]);
+        let noise = tensor(&[1., 0.3, -0.7, 1.5]);
+        // The velocity bringing any latents at sigma `t` to `x0`.
+        let model = |xs: &Tensor, t: f64| (xs - &x0)? / t;
+        for sampler in SAMPLERS {
+            let out = sample(sampler, &TIMESTEPS, &noise, &model, 0);
+            let diff = max_abs_diff(&out, &x0).unwrap();
+            assert!(diff < 1e-5, "{sampler:?}: {diff}");
+        }
+    }
+
+    #[test]
+    fn euler_ancestral_without_noise_is_euler() {
+        let latents = tensor(&[1., 0.3, -0.7, 1.5]);
+        let model = |xs: &Tensor, t: f64| (xs.sin()? * t)? + 0.5;
+        let euler = sample(SamplerType::Euler, &TIMESTEPS, &latents, &model, 0);
+        let ancestral = sample(
+            SamplerType::EulerAncestral { eta: 0. },
+            &TIMESTEPS,
+            &latents,
+            &model,
+            0,
+        );
+        let diff = max_abs_diff(&euler, &ancestral).unwrap();
+        assert!(diff < 1e-5, "{diff}");
+    }
+
+    #[test]
+    fn dpmpp_2m_first_order_after_sigma_one() {
+        let img = tensor(&[1., 0.3, -0.7, 1.5]);
+        let denoised = tensor(&[0.5, -1., 2., 0.]);
+        let previous = (tensor(&[3., 2., 1., 0.]), 1.);
+        let first_order = dpmpp_2m_step(&img, &denoised, 0.8, 0.5, None).unwrap();
+        let second_order = dpmpp_2m_step(&img, &denoised, 0.8, 0.5, Some(&previous)).unwrap();
+        assert_eq!(max_abs_diff(&first_order, &second_order).unwrap(), 0.);
+
+        // A previous sigma below 1 does use the previous prediction.
+        let previous = (previous.0, 0.9);
+        let second_order = dpmpp_2m_step(&img, &denoised, 0.8, 0.5, Some(&previous)).unwrap();
+        assert!(max_abs_diff(&first_order, &second_order).unwrap() > 1e-3);
+    }
+
+    #[test]
+    fn ancestral_noise_is_reproducible() {
+        let latents = tensor(&[1., 0.3, -0.7, 1.5]);
+        let model = |xs: &Tensor, t: f64| (xs.sin()? * t)? + 0.5;
+        let sampler = SamplerType::EulerAncestral { eta: 1. };
+        let a = sample(sampler, &TIMESTEPS, &latents, &model, 42);
+        let b = sample(sampler, &TIMESTEPS, &latents, &model, 42);
+        let c = sample(sampler, &TIMESTEPS, &latents, &model, 43);
+        assert_eq!(max_abs_diff(&a, &b).unwrap(), 0.);
+        assert!(max_abs_diff(&a, &c).unwrap() > 1e-3);
+    }
+
+    #[test]
+    fn randn_like_per_image() {
+        let xs = Tensor::zeros((3, 4), DType::F32, &Device::Cpu).unwrap();
+        let mut rngs = [1, 2, 3].map(StdRng::seed_from_u64);
+        let batch = randn_like(&xs, &mut rngs).unwrap();
+        // The noise of an image only depends on its generator, not on its position in the batch.
+        let mut rng = [StdRng::seed_from_u64(2)];
+        let single = randn_like(&xs.narrow(0, 0, 1).unwrap(), &mut rng).unwrap();
+        assert_eq!(
+            batch.get(1).unwrap().to_vec1::<f32>().unwrap(),
+            single.get(0).unwrap().to_vec1::<f32>().unwrap()
+        );
+        assert!(randn_like(&xs, &mut rngs[..2]).is_err());
     }
 }
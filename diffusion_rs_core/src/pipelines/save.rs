@@ -0,0 +1,380 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use diffusion_rs_backend::{QuantMethod, SerializedTensor};
+use diffusion_rs_common::core::{
+    quantized::{gguf_file, GgmlDType, QTensor},
+    DType, Device, Tensor,
+};
+use diffusion_rs_common::{DdufWriter, TensorRecord, VarBuilder};
+
+use crate::models::QuantizedModel;
+
+/// Destination of a saved model: a directory in the diffusers layout, or a DDUF file.
+pub(crate) enum ModelWriter {
+    Dir(PathBuf),
+    /// Files are written to `tmp` before being copied into the archive, so that they are never fully held in
+    /// memory.
+    Dduf {
+        writer: Box<DdufWriter>,
+        tmp: PathBuf,
+    },
+}
+
+impl ModelWriter {
+    pub(crate) fn dir(path: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(path)?;
+        Ok(Self::Dir(path.to_path_buf()))
+    }
+
+    pub(crate) fn dduf(path: &Path) -> anyhow::Result<Self> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        Ok(Self::Dduf {
+            writer: Box::new(DdufWriter::create(path)?),
+            tmp: tmp.into(),
+        })
+    }
+
+    /// Write a file with `write`, under `name` relative to the root of the model.
+    fn write_with(
+        &mut self,
+        name: &str,
+        write: impl FnOnce(&Path) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Dir(dir) => {
+                let path = dir.join(name);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                write(&path)
+            }
+            Self::Dduf { writer, tmp } => {
+                write(tmp)?;
+                let size = fs::metadata(&tmp)?.len();
+                writer.add_file(name, &mut File::open(&tmp)?, size)?;
+                fs::remove_file(tmp)?;
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn write_file(&mut self, name: &str, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Self::Dir(_) => self.write_with(name, |path| Ok(fs::write(path, data)?)),
+            Self::Dduf { writer, tmp: _ } => {
+                writer.add_file(name, &mut &data[..], data.len() as u64)
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Self::Dir(_) => Ok(()),
+            Self::Dduf { writer, tmp: _ } => writer.finish(),
+        }
+    }
+}
+
+/// Record the tensors retrieved through `vb` if the model may be saved. Otherwise, nothing is kept.
+pub(crate) fn recording(
+    vb: VarBuilder<'_>,
+    allow_saving: bool,
+) -> (VarBuilder<'_>, Option<TensorRecord>) {
+    if allow_saving {
+        let (vb, record) = vb.recording();
+        (vb, Some(record))
+    } else {
+        (vb, None)
+    }
+}
+
+/// The config and tensors of a loaded model component, besides its linear layers, which are needed to save it.
+pub(crate) struct ComponentRecord {
+    config: Vec<u8>,
+    tensors: TensorRecord,
+    /// Prefix of the names of the linear layers of the model in its weights.
+    prefix: String,
+}
+
+impl ComponentRecord {
+    /// Keep the tensors recorded while loading `model`, except those of its linear layers, which are serialized
+    /// from the layers when saving so that their quantization is kept. The layers are named `{prefix}{layer}` in
+    /// the weights, such as `text_model.` for CLIP.
+    pub(crate) fn new(
+        config: Vec<u8>,
+        tensors: TensorRecord,
+        prefix: &str,
+        model: Option<&mut dyn QuantizedModel>,
+    ) -> anyhow::Result<Self> {
+        if let Some(model) = model {
+            let layers = model
+                .named_layers()?
+                .into_iter()
+                .map(|(name, _)| format!("{prefix}{name}."))
+                .collect::<Vec<_>>();
+            tensors.retain(|name| !layers.iter().any(|layer| name.starts_with(layer)));
+        }
+        Ok(Self {
+            config,
+            tensors,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    /// Save the component to the directory `dir` of the model, as `{weights_name}.safetensors`, or as
+    /// `{weights_name}.gguf` if it has GGUF-quantized layers. Other quantized layers are saved in their own
+    /// format, which is loaded back from safetensors.
+    ///
+    /// DDUF files may only contain JSON, safetensors, text and `.model` files, so components with GGUF-quantized
+    /// layers can only be saved to a directory.
+    pub(crate) fn save(
+        &self,
+        writer: &mut ModelWriter,
+        dir: &str,
+        weights_name: &str,
+        layers: Vec<(String, &mut Arc<dyn QuantMethod>)>,
+    ) -> anyhow::Result<()> {
+        writer.write_file(&format!("{dir}/config.json"), &self.config)?;
+
+        let mut tensors = self
+            .tensors
+            .tensors()
+            .into_iter()
+            .map(|(name, tensor)| (name, SerializedTensor::Tensor(tensor)))
+            .collect::<HashMap<_, _>>();
+        for (layer, method) in layers {
+            for (name, tensor) in method.serialize()? {
+                tensors.insert(format!("{}{layer}.{name}", self.prefix), tensor);
+            }
+        }
+
+        if tensors
+            .values()
+            .any(|tensor| matches!(tensor, SerializedTensor::Quantized(_)))
+        {
+            if let ModelWriter::Dduf { .. } = writer {
+                anyhow::bail!(
+                    "Component `{dir}` has GGUF-quantized layers, which cannot be saved in a DDUF file. Save the model to a directory instead."
+                );
+            }
+            let mut qtensors = Vec::new();
+            for (name, tensor) in tensors {
+                let qtensor = match tensor {
+                    SerializedTensor::Quantized(qtensor) => qtensor,
+                    SerializedTensor::Tensor(tensor) => Arc::new(gguf_tensor(&name, &tensor)?),
+                };
+                qtensors.push((name, qtensor));
+            }
+            qtensors.sort_by(|(a, _), (b, _)| a.cmp(b));
+            writer.write_with(&format!("{dir}/{weights_name}.gguf"), |path| {
+                let qtensors = qtensors
+                    .iter()
+                    .map(|(name, qtensor)| (name.as_str(), qtensor.as_ref()))
+                    .collect::<Vec<_>>();
+                let mut file = BufWriter::new(File::create(path)?);
+                gguf_file::write(&mut file, &[], &qtensors)?;
+                Ok(())
+            })
+        } else {
+            let tensors = tensors
+                .into_iter()
+                .map(|(name, tensor)| match tensor {
+                    SerializedTensor::Tensor(tensor) => (name, tensor),
+                    SerializedTensor::Quantized(_) => unreachable!(),
+                })
+                .collect::<HashMap<_, _>>();
+            writer.write_with(&format!("{dir}/{weights_name}.safetensors"), |path| {
+                Ok(diffusion_rs_common::core::safetensors::save(
+                    &tensors, path,
+                )?)
+            })
+        }
+    }
+}
+
+/// Convert an unquantized tensor to be saved in a GGUF file next to GGUF-quantized layers.
+fn gguf_tensor(name: &str, tensor: &Tensor) -> anyhow::Result<QTensor> {
+    let dtype = match tensor.dtype() {
+        DType::F32 => GgmlDType::F32,
+        DType::F16 => GgmlDType::F16,
+        DType::BF16 => GgmlDType::BF16,
+        dtype => anyhow::bail!(
+            "Cannot save tensor `{name}` of dtype {dtype:?} in a GGUF file, layers quantized with GGUF cannot be saved with layers of other quantizations."
+        ),
+    };
+    Ok(QTensor::quantize(&tensor.to_device(&Device::Cpu)?, dtype)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_backend::IsqType;
+    use diffusion_rs_common::core::test_utils::max_abs_diff;
+    use diffusion_rs_common::{
+        from_mmaped_safetensors, FileData, FileLoader, ModelSource, TokenSource,
+    };
+
+    use super::*;
+    use crate::pipelines::gguf::load_gguf;
+    use crate::test_utils::TmpDir;
+
+    const CONFIG: &[u8] = br#"{"hidden_size": 64}"#;
+
+    type Layers = Vec<(String, Arc<dyn QuantMethod>)>;
+
+    /// The tensors of a tiny component: a norm, which is recorded, and the linear layers `proj` and `out`.
+    fn weights() -> VarBuilder<'static> {
+        let dev = Device::Cpu;
+        let tensors = HashMap::from([
+            (
+                "norm.weight".to_string(),
+                Tensor::arange(0f32, 64., &dev).unwrap(),
+            ),
+            (
+                "proj.weight".to_string(),
+                Tensor::randn(0f32, 1., (64, 64), &dev).unwrap(),
+            ),
+            (
+                "out.weight".to_string(),
+                Tensor::randn(0f32, 1., (32, 64), &dev).unwrap(),
+            ),
+        ]);
+        VarBuilder::from_tensors(tensors, DType::F32, &dev)
+    }
+
+    /// Load the component like a model would, quantizing `proj` with `proj_isq` and `out` with `out_isq`.
+    fn component(proj_isq: Option<IsqType>, out_isq: Option<IsqType>) -> (ComponentRecord, Layers) {
+        let vb = weights();
+        let (recorded, record) = recording(vb.clone(), true);
+        recorded.get(64, "norm.weight").unwrap();
+        let mut layers = Vec::new();
+        for (name, out_dim, isq) in [("proj", 64, proj_isq), ("out", 32, out_isq)] {
+            let mut layer =
+                diffusion_rs_backend::linear_b(64, out_dim, false, &None, vb.pp(name)).unwrap();
+            if let Some(isq) = isq {
+                layer = layer.apply_isq(isq, &Device::Cpu).unwrap();
+            }
+            layers.push((name.to_string(), layer));
+        }
+        let record = ComponentRecord::new(CONFIG.to_vec(), record.unwrap(), "", None).unwrap();
+        (record, layers)
+    }
+
+    fn save(
+        record: &ComponentRecord,
+        layers: &mut Layers,
+        writer: &mut ModelWriter,
+    ) -> anyhow::Result<()> {
+        let layers = layers
+            .iter_mut()
+            .map(|(name, layer)| (name.clone(), layer))
+            .collect();
+        record.save(writer, "transformer", "model", layers)
+    }
+
+    /// Check that the component loaded from `vb` has the weights of the saved one.
+    fn check_loaded(vb: VarBuilder, layers: &Layers) {
+        assert_eq!(
+            vb.get(64, "norm.weight").unwrap().to_vec1::<f32>().unwrap(),
+            weights()
+                .get(64, "norm.weight")
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+        );
+        for (name, layer) in layers {
+            let out_dim = layer.dequantize_w(DType::F32).unwrap().dim(0).unwrap();
+            let loaded =
+                diffusion_rs_backend::linear_b(64, out_dim, false, &None, vb.pp(name)).unwrap();
+            let diff = max_abs_diff(
+                &loaded.dequantize_w(DType::F32).unwrap(),
+                &layer.dequantize_w(DType::F32).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(diff, 0., "layer `{name}`");
+        }
+    }
+
+    #[test]
+    fn save_dir_round_trip() {
+        let tmp = TmpDir::new("save_dir");
+        let (record, mut layers) = component(Some(IsqType::HQQ4), Some(IsqType::F8E4M3));
+        let mut writer = ModelWriter::dir(tmp.path()).unwrap();
+        save(&record, &mut layers, &mut writer).unwrap();
+        writer.finish().unwrap();
+
+        let dir = tmp.path().join("transformer");
+        assert_eq!(fs::read(dir.join("config.json")).unwrap(), CONFIG);
+        let vb = from_mmaped_safetensors(
+            vec![FileData::Path(dir.join("model.safetensors"))],
+            Some(DType::F32),
+            &Device::Cpu,
+            true,
+            Arc::new(ModelSource::local_dir(tmp.path())),
+        )
+        .unwrap();
+        check_loaded(vb, &layers);
+    }
+
+    #[test]
+    fn save_dduf_round_trip() {
+        let tmp = TmpDir::new("save_dduf");
+        let path = tmp.path().join("model.dduf");
+        let (record, mut layers) = component(Some(IsqType::HQQ8), None);
+        let mut writer = ModelWriter::dduf(&path).unwrap();
+        save(&record, &mut layers, &mut writer).unwrap();
+        writer.finish().unwrap();
+
+        let mut source = ModelSource::dduf(path.display()).unwrap();
+        let mut loader =
+            FileLoader::from_model_source(&mut source, true, TokenSource::None, None, false)
+                .unwrap();
+        let config = loader
+            .read_file_copied("transformer/config.json", false)
+            .unwrap();
+        let weights = loader
+            .read_file("transformer/model.safetensors", false)
+            .unwrap();
+        assert_eq!(config.read_to_string_owned().unwrap().as_bytes(), CONFIG);
+        let vb = from_mmaped_safetensors(
+            vec![weights],
+            Some(DType::F32),
+            &Device::Cpu,
+            true,
+            Arc::new(source),
+        )
+        .unwrap();
+        check_loaded(vb, &layers);
+    }
+
+    #[test]
+    fn save_gguf_round_trip() {
+        let tmp = TmpDir::new("save_gguf");
+        let (record, mut layers) = component(None, Some(IsqType::Q8_0));
+
+        let mut writer = ModelWriter::dduf(&tmp.path().join("model.dduf")).unwrap();
+        let err = save(&record, &mut layers, &mut writer).unwrap_err();
+        assert!(err.to_string().contains("GGUF-quantized"), "{err}");
+
+        let mut writer = ModelWriter::dir(tmp.path()).unwrap();
+        save(&record, &mut layers, &mut writer).unwrap();
+        writer.finish().unwrap();
+        let path = tmp.path().join("transformer/model.gguf");
+        let source = ModelSource::local_dir(tmp.path());
+        let vb = load_gguf(
+            &FileData::Path(path),
+            &source,
+            DType::F32,
+            &Device::Cpu,
+            |name, tensor| Ok(vec![(name.to_string(), tensor.load(&Device::Cpu)?)]),
+        )
+        .unwrap();
+        check_loaded(vb, &layers);
+    }
+}
@@ -1,48 +1,265 @@
 use diffusion_rs_common::core::{Context, Result};
 use serde::Deserialize;
 
+/// Scheduler config, as in `scheduler/scheduler_config.json`. Missing fields take the defaults of diffusers.
 #[derive(Deserialize, Clone)]
 pub struct SchedulerConfig {
     #[serde(rename = "_class_name")]
     pub scheduler_type: SchedulerType,
+    #[serde(default = "default_base_image_seq_len")]
     pub base_image_seq_len: usize,
+    #[serde(default = "default_base_shift")]
     pub base_shift: f64,
+    #[serde(default = "default_max_image_seq_len")]
     pub max_image_seq_len: usize,
+    #[serde(default = "default_max_shift")]
     pub max_shift: f64,
+    #[serde(default = "default_shift")]
     pub shift: f64,
+    #[serde(default)]
     pub use_dynamic_shifting: bool,
+    #[serde(default)]
+    pub use_karras_sigmas: bool,
+    #[serde(default)]
+    pub use_exponential_sigmas: bool,
+    #[serde(default)]
+    pub use_beta_sigmas: bool,
+}
+
+fn default_base_image_seq_len() -> usize {
+    256
+}
+
+fn default_base_shift() -> f64 {
+    0.5
+}
+
+fn default_max_image_seq_len() -> usize {
+    4096
+}
+
+fn default_max_shift() -> f64 {
+    1.15
+}
+
+fn default_shift() -> f64 {
+    1.0
+}
+
+/// Overrides of the scheduler config of a model. Unspecified fields keep the value from the config.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SchedulerOverrides {
+    /// Static shift of the sigmas, used when dynamic shifting is disabled.
+    pub shift: Option<f64>,
+    /// Whether to shift the sigmas depending on the image size, between `base_shift` and `max_shift`.
+    pub use_dynamic_shifting: Option<bool>,
+    /// Shift for images of `base_image_seq_len` tokens, with dynamic shifting.
+    pub base_shift: Option<f64>,
+    /// Shift for images of `max_image_seq_len` tokens, with dynamic shifting.
+    pub max_shift: Option<f64>,
+    /// Number of image tokens (packed latent patches) at which `base_shift` applies, with dynamic shifting.
+    pub base_image_seq_len: Option<usize>,
+    /// Number of image tokens (packed latent patches) at which `max_shift` applies, with dynamic shifting. Must be
+    /// greater than `base_image_seq_len`.
+    pub max_image_seq_len: Option<usize>,
+}
+
+impl SchedulerOverrides {
+    /// Combine two sets of overrides, preferring the values of `self`.
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            shift: self.shift.or(other.shift),
+            use_dynamic_shifting: self.use_dynamic_shifting.or(other.use_dynamic_shifting),
+            base_shift: self.base_shift.or(other.base_shift),
+            max_shift: self.max_shift.or(other.max_shift),
+            base_image_seq_len: self.base_image_seq_len.or(other.base_image_seq_len),
+            max_image_seq_len: self.max_image_seq_len.or(other.max_image_seq_len),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub enum SchedulerType {
     #[serde(rename = "FlowMatchEulerDiscreteScheduler")]
     FlowMatchEulerDiscrete,
+    #[serde(rename = "FlowMatchHeunDiscreteScheduler")]
+    FlowMatchHeunDiscrete,
 }
 
+/// Spacing of the sigmas (noise levels) over the denoising steps.
+///
+/// Except for [`SigmaSchedule::Linear`], the schedules span the shifted sigma range of the model's 1000 training
+/// timesteps, matching diffusers and ComfyUI. The shift, dynamic or not, is therefore still applied. Only
+/// [`SigmaSchedule::Custom`] sigmas are not shifted.
+///
+/// If not specified in the generation parameters, the schedule is chosen from the model's scheduler config.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum SigmaSchedule {
+    /// Linearly spaced timesteps from 1 to 0, then shifted.
+    #[default]
+    Linear,
+    /// The schedule of Karras et al. (2022), spending more steps at low noise levels.
+    Karras { rho: f64 },
+    /// Sigmas spaced uniformly in log space.
+    Exponential,
+    /// Sigmas spaced following the quantiles of a beta distribution, spending more steps at both ends.
+    Beta { alpha: f64, beta: f64 },
+    /// Evenly spaced training timesteps, like the "simple" scheduler of ComfyUI.
+    Simple,
+    /// Explicit non-increasing sigmas in `[0, 1]`, ending with 0. They are used as is: the shift of the scheduler
+    /// config is not applied. The number of steps is the number of sigmas minus one, and `num_steps` is ignored.
+    Custom(Vec<f64>),
+}
+
+impl SigmaSchedule {
+    /// Karras schedule with the usual `rho` of 7.
+    pub fn karras() -> Self {
+        Self::Karras { rho: 7. }
+    }
+
+    /// Beta schedule with the usual `alpha` and `beta` of 0.6.
+    pub fn beta() -> Self {
+        Self::Beta {
+            alpha: 0.6,
+            beta: 0.6,
+        }
+    }
+}
+
+/// Number of training timesteps of flow-matching models.
+const NUM_TRAIN_TIMESTEPS: usize = 1000;
+
 fn time_shift(mu: f64, sigma: f64, t: f64) -> f64 {
     let e = mu.exp();
     e / (e + (1. / t - 1.).powf(sigma))
 }
 
+/// Evenly spaced values from `start` to `end`, both included.
+fn linspace(start: f64, end: f64, n: usize) -> impl Iterator<Item = f64> {
+    let step = if n > 1 {
+        (end - start) / (n - 1) as f64
+    } else {
+        0.
+    };
+    (0..n).map(move |i| start + step * i as f64)
+}
+
 impl SchedulerConfig {
-    pub fn get_timesteps(&self, num_steps: usize, mu: Option<f64>) -> Result<Vec<f64>> {
-        let mut sigmas: Vec<f64> = (0..=num_steps)
-            .map(|v| v as f64 / num_steps as f64)
-            .rev()
-            .collect();
+    /// A copy of this config with the given overrides applied.
+    pub fn with_overrides(&self, overrides: &SchedulerOverrides) -> Result<Self> {
+        let config = Self {
+            shift: overrides.shift.unwrap_or(self.shift),
+            use_dynamic_shifting: overrides
+                .use_dynamic_shifting
+                .unwrap_or(self.use_dynamic_shifting),
+            base_shift: overrides.base_shift.unwrap_or(self.base_shift),
+            max_shift: overrides.max_shift.unwrap_or(self.max_shift),
+            base_image_seq_len: overrides
+                .base_image_seq_len
+                .unwrap_or(self.base_image_seq_len),
+            max_image_seq_len: overrides
+                .max_image_seq_len
+                .unwrap_or(self.max_image_seq_len),
+            ..self.clone()
+        };
+        if config.base_image_seq_len >= config.max_image_seq_len {
+            diffusion_rs_common::bail!(
+                "`base_image_seq_len` ({}) must be less than `max_image_seq_len` ({}).",
+                config.base_image_seq_len,
+                config.max_image_seq_len
+            );
+        }
+        Ok(config)
+    }
+
+    /// The sigma schedule selected by the config, if any.
+    pub fn sigma_schedule(&self) -> SigmaSchedule {
+        if self.use_karras_sigmas {
+            SigmaSchedule::karras()
+        } else if self.use_exponential_sigmas {
+            SigmaSchedule::Exponential
+        } else if self.use_beta_sigmas {
+            SigmaSchedule::beta()
+        } else {
+            SigmaSchedule::Linear
+        }
+    }
+
+    fn shift_sigma(&self, sigma: f64, mu: Option<f64>) -> Result<f64> {
+        if self.use_dynamic_shifting {
+            let mu = mu.context("`mu` is required for dynamic shifting")?;
+            Ok(time_shift(mu, 1., sigma))
+        } else {
+            Ok(self.shift * sigma / (1. + (self.shift - 1.) * sigma))
+        }
+    }
+
+    /// Returns `num_steps + 1` decreasing timesteps (sigmas), ending with 0. `num_steps` must be nonzero, except
+    /// for [`SigmaSchedule::Custom`] sigmas which are returned as is.
+    pub fn get_timesteps(
+        &self,
+        num_steps: usize,
+        mu: Option<f64>,
+        schedule: &SigmaSchedule,
+    ) -> Result<Vec<f64>> {
         match self.scheduler_type {
-            SchedulerType::FlowMatchEulerDiscrete => {
-                if self.use_dynamic_shifting {
-                    let mu = mu.context("`mu` is required for dynamic shifting")?;
-                    sigmas = sigmas
-                        .iter()
-                        .map(|sigma| time_shift(mu, 1., *sigma))
-                        .collect();
-                } else {
-                    sigmas = sigmas
-                        .iter()
-                        .map(|sigma| self.shift * sigma / (1. + (self.shift - 1.) * sigma))
-                        .collect();
+            SchedulerType::FlowMatchEulerDiscrete | SchedulerType::FlowMatchHeunDiscrete => {
+                if num_steps == 0 && !matches!(schedule, SigmaSchedule::Custom(_)) {
+                    diffusion_rs_common::bail!("The number of steps must be nonzero.");
+                }
+
+                let sigma_max = self.shift_sigma(1., mu)?;
+                let sigma_min = self.shift_sigma(1. / NUM_TRAIN_TIMESTEPS as f64, mu)?;
+
+                let mut sigmas = match schedule {
+                    SigmaSchedule::Linear => linspace(1., 0., num_steps + 1)
+                        .map(|sigma| self.shift_sigma(sigma, mu))
+                        .collect::<Result<Vec<_>>>()?,
+                    SigmaSchedule::Karras { rho } => {
+                        let min_inv_rho = sigma_min.powf(1. / rho);
+                        let max_inv_rho = sigma_max.powf(1. / rho);
+                        linspace(0., 1., num_steps)
+                            .map(|ramp| {
+                                (max_inv_rho + ramp * (min_inv_rho - max_inv_rho)).powf(*rho)
+                            })
+                            .collect()
+                    }
+                    SigmaSchedule::Exponential => {
+                        linspace(sigma_max.ln(), sigma_min.ln(), num_steps)
+                            .map(f64::exp)
+                            .collect()
+                    }
+                    SigmaSchedule::Beta { alpha, beta } => linspace(0., 1., num_steps)
+                        .map(|t| {
+                            let ppf = beta_ppf(1. - t, *alpha, *beta);
+                            sigma_min + ppf * (sigma_max - sigma_min)
+                        })
+                        .collect(),
+                    SigmaSchedule::Simple => {
+                        let stride = NUM_TRAIN_TIMESTEPS as f64 / num_steps as f64;
+                        (0..num_steps)
+                            .map(|i| {
+                                let t = NUM_TRAIN_TIMESTEPS - (i as f64 * stride) as usize;
+                                self.shift_sigma(t as f64 / NUM_TRAIN_TIMESTEPS as f64, mu)
+                            })
+                            .collect::<Result<Vec<_>>>()?
+                    }
+                    SigmaSchedule::Custom(sigmas) => {
+                        if sigmas.len() < 2
+                            || sigmas.iter().any(|s| !(0. ..=1.).contains(s))
+                            || sigmas.windows(2).any(|w| w[1] > w[0])
+                            || sigmas.last() != Some(&0.)
+                        {
+                            diffusion_rs_common::bail!(
+                                "Custom sigmas must be at least 2 non-increasing values in [0, 1] \
+                                 ending with 0, got {sigmas:?}."
+                            );
+                        }
+                        return Ok(sigmas.clone());
+                    }
+                };
+                if sigmas.last() != Some(&0.) {
+                    sigmas.push(0.);
                 }
 
                 Ok(sigmas)
@@ -50,3 +267,204 @@ impl SchedulerConfig {
         }
     }
 }
+
+/// Quantile function of the beta distribution, by bisection of the CDF.
+fn beta_ppf(p: f64, a: f64, b: f64) -> f64 {
+    let (mut lo, mut hi) = (0f64, 1f64);
+    for _ in 0..64 {
+        let mid = (lo + hi) / 2.;
+        if regularized_incomplete_beta(mid, a, b) < p {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    (lo + hi) / 2.
+}
+
+/// The regularized incomplete beta function `I_x(a, b)`, which is the CDF of the beta distribution.
+fn regularized_incomplete_beta(x: f64, a: f64, b: f64) -> f64 {
+    if x <= 0. {
+        return 0.;
+    }
+    if x >= 1. {
+        return 1.;
+    }
+    let ln_front = ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1. - x).ln();
+    // The continued fraction converges quickly for x < (a + 1) / (a + b + 2); use the symmetry otherwise.
+    if x < (a + 1.) / (a + b + 2.) {
+        ln_front.exp() * beta_continued_fraction(x, a, b) / a
+    } else {
+        1. - ln_front.exp() * beta_continued_fraction(1. - x, b, a) / b
+    }
+}
+
+/// Continued fraction for the incomplete beta function, evaluated with the modified Lentz method.
+fn beta_continued_fraction(x: f64, a: f64, b: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let mut c = 1.;
+    let mut d = 1. - (a + b) * x / (a + 1.);
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1. / d;
+    let mut h = d;
+    for m in 1..300 {
+        let m = m as f64;
+        let m2 = 2. * m;
+        // Even step.
+        let aa = m * (b - m) * x / ((a + m2 - 1.) * (a + m2));
+        d = 1. + aa * d;
+        if d.abs() < TINY {
+            d = TINY;
+        }
+        c = 1. + aa / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        d = 1. / d;
+        h *= d * c;
+        // Odd step.
+        let aa = -(a + m) * (a + b + m) * x / ((a + m2) * (a + m2 + 1.));
+        d = 1. + aa * d;
+        if d.abs() < TINY {
+            d = TINY;
+        }
+        c = 1. + aa / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        d = 1. / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.).abs() < 1e-15 {
+            break;
+        }
+    }
+    h
+}
+
+/// Natural logarithm of the gamma function, using the Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFS: [f64; 6] = [
+        76.180_091_729_471_46,
+        -86.505_320_329_416_77,
+        24.014_098_240_830_91,
+        -1.231_739_572_450_155,
+        0.120_865_097_386_617_9e-2,
+        -0.539_523_938_495_3e-5,
+    ];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut ser = 1.000_000_000_190_015;
+    for (i, coeff) in COEFFS.iter().enumerate() {
+        ser += coeff / (x + 1. + i as f64);
+    }
+    -tmp + (2.506_628_274_631_000_5 * ser / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SchedulerConfig {
+        serde_json::from_str(r#"{"_class_name": "FlowMatchEulerDiscreteScheduler", "shift": 3.0}"#)
+            .unwrap()
+    }
+
+    #[test]
+    fn ln_gamma_values() {
+        assert!((ln_gamma(0.5) - std::f64::consts::PI.sqrt().ln()).abs() < 1e-9);
+        assert!(ln_gamma(1.).abs() < 1e-9);
+        assert!(ln_gamma(2.).abs() < 1e-9);
+        assert!((ln_gamma(5.) - 24f64.ln()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn incomplete_beta_values() {
+        for x in [0., 0.1, 0.25, 0.5, 0.9, 1.] {
+            // The uniform distribution.
+            assert!((regularized_incomplete_beta(x, 1., 1.) - x).abs() < 1e-9);
+            // I_x(2, 1) = x^2.
+            assert!((regularized_incomplete_beta(x, 2., 1.) - x * x).abs() < 1e-9);
+            // I_x(a, b) = 1 - I_{1-x}(b, a).
+            let lhs = regularized_incomplete_beta(x, 0.6, 2.5);
+            let rhs = 1. - regularized_incomplete_beta(1. - x, 2.5, 0.6);
+            assert!((lhs - rhs).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn beta_ppf_values() {
+        for p in [0., 0.1, 0.3, 0.5, 0.7, 1.] {
+            assert!((beta_ppf(p, 1., 1.) - p).abs() < 1e-9);
+            // Symmetric distributions have symmetric quantiles.
+            assert!((beta_ppf(p, 0.6, 0.6) + beta_ppf(1. - p, 0.6, 0.6) - 1.).abs() < 1e-9);
+            // The quantile function inverts the CDF.
+            let x = beta_ppf(p, 0.6, 2.5);
+            assert!((regularized_incomplete_beta(x, 0.6, 2.5) - p).abs() < 1e-9);
+        }
+        assert!((beta_ppf(0.5, 0.6, 0.6) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn schedules_are_decreasing() {
+        let config = config();
+        for schedule in [
+            SigmaSchedule::Linear,
+            SigmaSchedule::karras(),
+            SigmaSchedule::Exponential,
+            SigmaSchedule::beta(),
+            SigmaSchedule::Simple,
+        ] {
+            let sigmas = config.get_timesteps(10, None, &schedule).unwrap();
+            assert_eq!(sigmas.len(), 11, "{schedule:?}");
+            assert_eq!(sigmas.last(), Some(&0.), "{schedule:?}");
+            assert!(sigmas.windows(2).all(|w| w[1] < w[0]), "{schedule:?}");
+            assert!(config.get_timesteps(0, None, &schedule).is_err());
+        }
+    }
+
+    #[test]
+    fn overrides() {
+        let config = config()
+            .with_overrides(&SchedulerOverrides {
+                shift: Some(1.5),
+                max_image_seq_len: Some(8192),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(config.shift, 1.5);
+        assert_eq!(config.base_image_seq_len, 256);
+        assert_eq!(config.max_image_seq_len, 8192);
+        for (base, max) in [(4096, 4096), (8192, 4096)] {
+            assert!(config
+                .with_overrides(&SchedulerOverrides {
+                    base_image_seq_len: Some(base),
+                    max_image_seq_len: Some(max),
+                    ..Default::default()
+                })
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn custom_sigmas() {
+        let config = config();
+        let sigmas = vec![1., 0.5, 0.5, 0.];
+        let schedule = SigmaSchedule::Custom(sigmas.clone());
+        // Custom sigmas are not shifted and ignore the number of steps.
+        assert_eq!(config.get_timesteps(0, None, &schedule).unwrap(), sigmas);
+        for sigmas in [
+            vec![],
+            vec![0.],
+            vec![1., 0.5],
+            vec![0.5, 1., 0.],
+            vec![2., 0.],
+        ] {
+            assert!(config
+                .get_timesteps(2, None, &SigmaSchedule::Custom(sigmas))
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, ops::Range, path::Path, path::PathBuf};
+
+use diffusion_rs_common::core::{safetensors::MmapedSafetensors, DType, Device, Result, Tensor};
+use diffusion_rs_common::{
+    cached_snapshot, get_token, FileData, NiceProgressBar, TokenSource, VarBuilder,
+};
+use hf_hub::api::sync::ApiBuilder;
+
+/// The rows of a tensor of a checkpoint making up a tensor of the model, as ranges to concatenate. `None` is the
+/// whole tensor.
+pub(crate) type Rows = Option<Vec<Range<usize>>>;
+
+/// Resolve a file overriding the weights of a component, given as a local path, or as a Hugging Face model ID
+/// followed by the path of the file in the repository, which is read from the local cache if `offline`.
+///
+/// Repositories are always read at their `main` revision, since the revision of the pipeline is that of another
+/// repository.
+pub(crate) fn resolve_component_file(
+    file: &str,
+    silent: bool,
+    token: &TokenSource,
+    offline: bool,
+) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(file);
+    if path.is_file() {
+        return Ok(path);
+    }
+    let mut parts = file.splitn(3, '/');
+    let (Some(org), Some(repo), Some(filename)) = (parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!(
+            "File `{file}` does not exist, expected a local path or `<model id>/<file name>`."
+        );
+    };
+    if offline {
+        let path = cached_snapshot(&format!("{org}/{repo}"), "main")?.join(filename);
+        if !path.is_file() {
+            anyhow::bail!(
+                "File `{filename}` of `{org}/{repo}` is not in the Hugging Face cache, it must be downloaded before loading it offline."
+            );
+        }
+        return Ok(path);
+    }
+    let api = ApiBuilder::new()
+        .with_progress(!silent)
+        .with_token(get_token(token)?)
+        .build()?
+        .model(format!("{org}/{repo}"));
+    api.get(filename)
+        .map_err(|e| anyhow::Error::msg(e.to_string()))
+}
+
+/// The path of a single-file safetensors checkpoint, which is memory-mapped and so must be a local file.
+pub(crate) fn safetensors_path(file: &FileData) -> Result<&Path> {
+    match file {
+        FileData::Path(path) => Ok(path),
+        FileData::Dduf { name, .. } | FileData::DdufOwned { name, .. } => {
+            diffusion_rs_common::bail!(
+                "Single-file safetensors weights must be a local file, got `{}` in a DDUF file.",
+                name.display()
+            )
+        }
+    }
+}
+
+/// Load the tensors of a `.safetensors` file into a `VarBuilder`, keeping the dtype they are stored with. For
+/// each tensor of the file, `remap` returns the tensors expected by the model which it makes up, given its shape,
+/// which allows renaming or splitting them. Tensors making up no tensor of the model are not loaded.
+pub(crate) fn load_safetensors(
+    file: &FileData,
+    dtype: DType,
+    device: &Device,
+    mut remap: impl FnMut(&str, &[usize]) -> Result<Vec<(String, Rows)>>,
+) -> Result<VarBuilder<'static>> {
+    let st = unsafe { MmapedSafetensors::new(safetensors_path(file)?)? };
+    let names = st
+        .tensors()
+        .into_iter()
+        .map(|(name, view)| (name, view.shape().to_vec()))
+        .collect::<Vec<_>>();
+
+    let mut tensors = HashMap::new();
+    for (name, shape) in NiceProgressBar::<_, 'b'>(names.into_iter(), "Loading tensors") {
+        let targets = remap(&name, &shape)?;
+        if targets.is_empty() {
+            continue;
+        }
+        let tensor = st.load(&name, device)?;
+        for (target, rows) in targets {
+            let tensor = match rows {
+                Some(rows) => {
+                    let parts = rows
+                        .iter()
+                        .map(|range| tensor.narrow(0, range.start, range.len()))
+                        .collect::<Result<Vec<_>>>()?;
+                    Tensor::cat(&parts, 0)?
+                }
+                None => tensor.clone(),
+            };
+            tensors.insert(target, tensor);
+        }
+    }
+
+    Ok(VarBuilder::from_tensors(tensors, dtype, device))
+}
@@ -2,7 +2,7 @@ use std::time::Instant;
 
 use clap::Parser;
 use diffusion_rs_core::{
-    DiffusionGenerationParams, ModelDType, ModelSource, Offloading, Pipeline, TokenSource,
+    DiffusionGenerationParams, LoadOptions, ModelSource, Offloading, Pipeline,
 };
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
@@ -40,29 +40,29 @@ fn main() -> anyhow::Result<()> {
 
     let pipeline = Pipeline::load(
         ModelSource::dduf(args.file)?,
-        false,
-        TokenSource::CacheToken,
-        None,
-        args.offloading,
-        &ModelDType::Auto,
+        LoadOptions {
+            offloading_type: args.offloading,
+            ..Default::default()
+        },
     )?;
 
     let start = Instant::now();
 
-    let images = pipeline.forward(
+    let output = pipeline.forward(
         vec![args.prompt],
         DiffusionGenerationParams {
             height: 720,
             width: 1280,
             num_steps: args.num_steps,
             guidance_scale: args.guidance_scale,
+            ..Default::default()
         },
     )?;
 
     let end = Instant::now();
     println!("Took: {:.2}s", end.duration_since(start).as_secs_f32());
 
-    images[0].save("image.png")?;
+    output.images[0].save("image.png")?;
 
     Ok(())
 }
@@ -1,7 +1,7 @@
 use std::time::Instant;
 
 use diffusion_rs_core::{
-    DiffusionGenerationParams, ModelDType, ModelSource, Offloading, Pipeline, TokenSource,
+    DiffusionGenerationParams, LoadOptions, ModelSource, Offloading, Pipeline,
 };
 
 use clap::{Parser, ValueEnum};
@@ -46,11 +46,10 @@ fn main() -> anyhow::Result<()> {
 
     let pipeline = Pipeline::load(
         ModelSource::from_model_id(model_id),
-        false,
-        TokenSource::CacheToken,
-        None,
-        args.offloading,
-        &ModelDType::Auto,
+        LoadOptions {
+            offloading_type: args.offloading,
+            ..Default::default()
+        },
     )?;
     let num_steps = match args.which {
         Which::Dev => 50,
@@ -63,20 +62,21 @@ fn main() -> anyhow::Result<()> {
 
     let start = Instant::now();
 
-    let images = pipeline.forward(
+    let output = pipeline.forward(
         vec![args.prompt],
         DiffusionGenerationParams {
             height: 720,
             width: 1280,
             num_steps,
             guidance_scale,
+            ..Default::default()
         },
     )?;
 
     let end = Instant::now();
     println!("Took: {:.2}s", end.duration_since(start).as_secs_f32());
 
-    images[0].save("image.png")?;
+    output.images[0].save("image.png")?;
 
     Ok(())
 }
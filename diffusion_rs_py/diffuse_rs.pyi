@@ -21,6 +21,69 @@ class Offloading(Enum):
 
     Full = 0
 
+@dataclass
+class IsqType(Enum):
+    """
+    Quantization applied in-situ to the linear layers of unquantized models while loading.
+    """
+
+    Q4_0 = 0
+    Q4_1 = 1
+    Q5_0 = 2
+    Q5_1 = 3
+    Q8_0 = 4
+    Q8_1 = 5
+    Q2K = 6
+    Q3K = 7
+    Q4K = 8
+    Q5K = 9
+    Q6K = 10
+    Q8K = 11
+    HQQ8 = 12
+    HQQ4 = 13
+    F8E4M3 = 14
+
+@dataclass
+class Sampler(Enum):
+    """
+    Sampling algorithm for the denoising loop. `EulerAncestral` adds noise at every step, scaled by `eta`.
+    """
+
+    Euler = 0
+    Heun = 1
+    DpmPp2M = 2
+    EulerAncestral = 3
+
+@dataclass
+class SigmaSchedule(Enum):
+    """
+    Spacing of the sigmas over the denoising steps. `Custom` sigmas must be non-increasing and end with 0; they are used as is, without shift, and determine the number of steps.
+    """
+    @dataclass
+    class Linear:
+        pass
+
+    @dataclass
+    class Karras:
+        rho: float = 7.0
+
+    @dataclass
+    class Exponential:
+        pass
+
+    @dataclass
+    class Beta:
+        alpha: float = 0.6
+        beta: float = 0.6
+
+    @dataclass
+    class Simple:
+        pass
+
+    @dataclass
+    class Custom:
+        sigmas: list[float]
+
 @dataclass
 class ModelSource(Enum):
     """
@@ -44,6 +107,30 @@ class DiffusionGenerationParams:
     width: int
     num_steps: int
     guidance_scale: float
+    seed: int | None = None
+    negative_prompts: list[str] | None = None
+    true_cfg_scale: float = 1.0
+    num_images_per_prompt: int = 1
+    seeds: list[int] | None = None
+    sampler: Sampler | None = None
+    eta: float = 1.0
+    sigma_schedule: SigmaSchedule | None = None
+    shift: float | None = None
+    use_dynamic_shifting: bool | None = None
+    base_shift: float | None = None
+    max_shift: float | None = None
+
+@dataclass
+class DiffusionGenerationOutput:
+    """
+    Output of a generation.
+
+    - `images`: PNG images as bytes objects, in the order of the prompts. The images of each prompt are contiguous.
+    - `seeds`: the seed used for the initial noise of each image. Pass one as `DiffusionGenerationParams.seed` to regenerate that image.
+    """
+
+    images: list[bytes]
+    seeds: list[int]
 
 class Pipeline:
     def __init__(
@@ -54,17 +141,28 @@ class Pipeline:
         revision: str | None = None,
         offloading: Offloading | None = None,
         ModelDType: ModelDType = ModelDType.Auto,
+        component_files: dict[str, str] | None = None,
+        isq: IsqType | None = None,
+        offline: bool = False,
+        allow_saving: bool = False,
     ) -> None:
         """
         Load a model.
 
         - `source`: the source of the model
         - `silent`: silent loading, defaults to `False`.
-        - `token`: specifies a literal Hugging Face token for accessing gated models.
+        - `token`: specifies a literal Hugging Face token for accessing gated models, also used for LoRA adapters.
         - `revision`: specifies a specific Hugging Face model revision, otherwise the default is used.
         - `token_source` specifies where to load the HF token from.
         - `offloading`: offloading setting for the model.
         - `dtype`: dtype selection for the model. The default is to use an automatic strategy with a fallback pattern: BF16 -> F16 -> F32
+        - `component_files`: components whose weights are loaded from single GGUF or safetensors files (such as FP8 checkpoints), for example
+          `{"transformer": "city96/FLUX.1-dev-gguf/flux1-dev-Q4_K_S.gguf"}`. The files are local paths or a Hugging Face model ID followed by the file name, read from the `main` revision.
+        - `isq`: quantize the linear layers of unquantized models in-situ while loading, for example `IsqType.Q4K`.
+        - `offline`: load Hugging Face models and LoRA adapters from the local cache only, without accessing the network. This is also enabled by
+          setting `HF_HUB_OFFLINE=1`. Files missing from the cache are all listed in the error.
+        - `allow_saving`: keep what is needed to save the model with `save_pretrained` or `save_dduf`, which holds on to the tensors of the
+          model besides its linear layers. Defaults to `False`.
         """
         ...
 
@@ -72,9 +170,119 @@ class Pipeline:
         self,
         prompts: list[str],
         params: DiffusionGenerationParams,
-    ) -> list[bytes]:
+    ) -> DiffusionGenerationOutput:
         """
         Execute the diffusion model on the given batch of prompts.
 
-        Image data is returned as bytes objects and is in the order of the prompts
+        Images are returned as PNG bytes objects in the order of the prompts, with the seed used for each.
+        """
+
+    def forward_img2img(
+        self,
+        prompts: list[str],
+        init_images: list[bytes],
+        params: DiffusionGenerationParams,
+        strength: float = 0.8,
+    ) -> DiffusionGenerationOutput:
+        """
+        Execute the diffusion model on the given batch of prompts, starting from existing images.
+
+        - `init_images`: encoded images (for example PNG bytes), either one per prompt or a single image for all prompts.
+        - `strength`: how strongly the images are transformed, in (0, 1]. 1 ignores the image content.
+
+        Images are returned as PNG bytes objects in the order of the prompts, with the seed used for each.
+        """
+
+    def forward_inpaint(
+        self,
+        prompts: list[str],
+        init_images: list[bytes],
+        masks: list[bytes],
+        params: DiffusionGenerationParams,
+        strength: float = 1.0,
+    ) -> DiffusionGenerationOutput:
+        """
+        Regenerate the masked regions of existing images.
+
+        - `init_images`: encoded images, either one per prompt or a single image for all prompts.
+        - `masks`: encoded single-channel masks. White regions are generated, black regions are kept.
+        - `strength`: how strongly the images are transformed, in (0, 1].
+
+        Images are returned as PNG bytes objects in the order of the prompts, with the seed used for each.
+        """
+
+    def forward_outpaint(
+        self,
+        prompts: list[str],
+        init_images: list[bytes],
+        params: DiffusionGenerationParams,
+        left: int = 0,
+        right: int = 0,
+        top: int = 0,
+        bottom: int = 0,
+    ) -> DiffusionGenerationOutput:
+        """
+        Extend existing images beyond their borders by the given padding, in pixels.
+        The padded canvas is resized to the `height` and `width` of `params`.
+
+        Images are returned as PNG bytes objects in the order of the prompts, with the seed used for each.
+        """
+
+    def set_prompt_cache_size(self, size_in_bytes: int) -> None:
+        """
+        Set the capacity of the cache of encoded prompts, in bytes. A size of 0 disables the cache.
+        The cache holds 128 MiB by default.
+        """
+
+    def load_lora(self, path_or_hf_id: str, scale: float = 1.0) -> None:
+        """
+        Load a LoRA adapter from a .safetensors file, a directory or a Hugging Face model ID, and apply it with the given scale.
+        Diffusers, kohya and original (BFL) formats are supported. Loading several LoRAs applies all of them.
+        The adapter is named `path_or_hf_id`.
+        """
+
+    def load_lora_adapter(
+        self, path_or_hf_id: str, adapter_name: str, scale: float = 1.0
+    ) -> None:
+        """
+        Load a LoRA adapter like `load_lora`, under the given name. An adapter with the same name is replaced.
+        """
+
+    def set_lora_adapters(self, adapters: list[tuple[str, float]]) -> None:
+        """
+        Activate exactly the given LoRA adapters, as (name, scale) pairs. Other adapters stay loaded but are inactive.
+        """
+
+    def unload_lora(self, adapter_name: str) -> None:
+        """
+        Unload a LoRA adapter, restoring the layers it applied to.
+        """
+
+    def lora_adapters(self) -> list[tuple[str, float]]:
+        """
+        The loaded LoRA adapters with their scales. Inactive adapters have a scale of 0.
+        """
+
+    def fuse_loras(self) -> None:
+        """
+        Fuse the active LoRA adapters into the weights of the unquantized layers, removing their runtime cost.
+        Adapters cannot be loaded, rescaled or unloaded while fused.
+        """
+
+    def unfuse_loras(self) -> None:
+        """
+        Undo `fuse_loras`, applying the adapters at runtime again.
+        """
+
+    def save_pretrained(self, dir: str) -> None:
+        """
+        Save the model to a directory in the diffusers layout, keeping the quantization of its layers. Components
+        with GGUF-quantized layers are saved to a GGUF file. LoRA adapters must be fused or unloaded first. The model must
+        have been loaded with `allow_saving=True`.
+        """
+
+    def save_dduf(self, file: str) -> None:
+        """
+        Save the model to a DDUF file, like `save_pretrained`. The result can be loaded with `ModelSource.DdufFile`. DDUF files cannot
+        contain GGUF files, so models with GGUF-quantized layers (such as `IsqType.Q4K`) can only be saved with `save_pretrained`.
         """
@@ -4,12 +4,13 @@ import io
 
 pipeline = Pipeline(source=ModelSource.DdufFile("FLUX.1-dev-Q4-bnb.dduf"))
 
-image_bytes = pipeline.forward(
+output = pipeline.forward(
     prompts=["Draw a picture of a sunrise."],
     params=DiffusionGenerationParams(
         height=720, width=1280, num_steps=50, guidance_scale=3.5
     ),
 )
 
-image = Image.open(io.BytesIO(image_bytes[0]))
+image = Image.open(io.BytesIO(output.images[0]))
 image.show()
+print(f"Seed: {output.seeds[0]}")
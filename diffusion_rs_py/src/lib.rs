@@ -1,4 +1,4 @@
-use std::io::Cursor;
+use std::{collections::HashMap, io::Cursor, sync::Arc};
 
 use pyo3::{
     pyclass, pymethods, pymodule,
@@ -16,6 +16,35 @@ pub enum Offloading {
     Full,
 }
 
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sampler {
+    Euler,
+    Heun,
+    DpmPp2M,
+    EulerAncestral,
+}
+
+#[pyclass]
+#[derive(Clone, Debug)]
+pub enum SigmaSchedule {
+    Linear(),
+    #[pyo3(constructor = (rho = 7.0))]
+    Karras {
+        rho: f64,
+    },
+    Exponential(),
+    #[pyo3(constructor = (alpha = 0.6, beta = 0.6))]
+    Beta {
+        alpha: f64,
+        beta: f64,
+    },
+    Simple(),
+    Custom {
+        sigmas: Vec<f64>,
+    },
+}
+
 #[pyclass]
 #[derive(Clone, Debug)]
 pub enum ModelSource {
@@ -31,6 +60,18 @@ pub struct DiffusionGenerationParams {
     pub width: usize,
     pub num_steps: usize,
     pub guidance_scale: f64,
+    pub seed: Option<u64>,
+    pub negative_prompts: Option<Vec<String>>,
+    pub true_cfg_scale: f64,
+    pub num_images_per_prompt: usize,
+    pub seeds: Option<Vec<u64>>,
+    pub sampler: Option<Sampler>,
+    pub eta: f64,
+    pub sigma_schedule: Option<SigmaSchedule>,
+    pub shift: Option<f64>,
+    pub use_dynamic_shifting: Option<bool>,
+    pub base_shift: Option<f64>,
+    pub max_shift: Option<f64>,
 }
 
 #[pyclass(eq, eq_int)]
@@ -43,31 +84,88 @@ pub enum ModelDType {
     F32,
 }
 
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IsqType {
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q8_1,
+    Q2K,
+    Q3K,
+    Q4K,
+    Q5K,
+    Q6K,
+    Q8K,
+    HQQ8,
+    HQQ4,
+    F8E4M3,
+}
+
 #[pymethods]
 impl DiffusionGenerationParams {
     #[new]
+    #[allow(clippy::too_many_arguments)]
     #[pyo3(signature = (
         height,
         width,
         num_steps,
         guidance_scale,
+        seed = None,
+        negative_prompts = None,
+        true_cfg_scale = 1.0,
+        num_images_per_prompt = 1,
+        seeds = None,
+        sampler = None,
+        eta = 1.0,
+        sigma_schedule = None,
+        shift = None,
+        use_dynamic_shifting = None,
+        base_shift = None,
+        max_shift = None,
     ))]
     pub fn new(
         height: usize,
         width: usize,
         num_steps: usize,
         guidance_scale: f64,
+        seed: Option<u64>,
+        negative_prompts: Option<Vec<String>>,
+        true_cfg_scale: f64,
+        num_images_per_prompt: usize,
+        seeds: Option<Vec<u64>>,
+        sampler: Option<Sampler>,
+        eta: f64,
+        sigma_schedule: Option<SigmaSchedule>,
+        shift: Option<f64>,
+        use_dynamic_shifting: Option<bool>,
+        base_shift: Option<f64>,
+        max_shift: Option<f64>,
     ) -> PyResult<Self> {
         Ok(Self {
             height,
             width,
             num_steps,
             guidance_scale,
+            seed,
+            negative_prompts,
+            true_cfg_scale,
+            num_images_per_prompt,
+            seeds,
+            sampler,
+            eta,
+            sigma_schedule,
+            shift,
+            use_dynamic_shifting,
+            base_shift,
+            max_shift,
         })
     }
 
     pub fn __repr__(&self) -> String {
-        format!("DiffusionGenerationParams(height = {}, width = {}, num_steps = {}, guidance_scale = {})", self.height,self.width,self.num_steps,self.guidance_scale)
+        format!("DiffusionGenerationParams(height = {}, width = {}, num_steps = {}, guidance_scale = {}, seed = {:?}, negative_prompts = {:?}, true_cfg_scale = {}, num_images_per_prompt = {}, seeds = {:?}, sampler = {:?}, eta = {}, sigma_schedule = {:?}, shift = {:?}, use_dynamic_shifting = {:?}, base_shift = {:?}, max_shift = {:?})", self.height,self.width,self.num_steps,self.guidance_scale,self.seed,self.negative_prompts,self.true_cfg_scale,self.num_images_per_prompt,self.seeds,self.sampler,self.eta,self.sigma_schedule,self.shift,self.use_dynamic_shifting,self.base_shift,self.max_shift)
     }
 
     pub fn __str__(&self) -> String {
@@ -75,6 +173,34 @@ impl DiffusionGenerationParams {
     }
 }
 
+/// Output of a generation: the images as PNG bytes, in the order of the prompts, and the seed used for each.
+#[pyclass]
+pub struct DiffusionGenerationOutput {
+    images: Vec<Py<PyBytes>>,
+    #[pyo3(get)]
+    seeds: Vec<u64>,
+}
+
+#[pymethods]
+impl DiffusionGenerationOutput {
+    #[getter]
+    fn images(&self, py: Python<'_>) -> Vec<Py<PyBytes>> {
+        self.images
+            .iter()
+            .map(|image| image.clone_ref(py))
+            .collect()
+    }
+}
+
+impl From<diffusion_rs_core::DiffusionGenerationOutput> for DiffusionGenerationOutput {
+    fn from(output: diffusion_rs_core::DiffusionGenerationOutput) -> Self {
+        Self {
+            images: images_to_bytes(output.images),
+            seeds: output.seeds,
+        }
+    }
+}
+
 #[pyclass]
 pub struct Pipeline(diffusion_rs_core::Pipeline);
 
@@ -88,7 +214,12 @@ impl Pipeline {
         revision = None,
         offloading = None,
         dtype = ModelDType::Auto,
+        component_files = None,
+        isq = None,
+        offline = false,
+        allow_saving = false,
     ))]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         source: ModelSource,
         silent: bool,
@@ -96,11 +227,15 @@ impl Pipeline {
         revision: Option<String>,
         offloading: Option<Offloading>,
         dtype: ModelDType,
+        component_files: Option<HashMap<String, String>>,
+        isq: Option<IsqType>,
+        offline: bool,
+        allow_saving: bool,
     ) -> PyResult<Self> {
         let token = token
             .map(diffusion_rs_core::TokenSource::Literal)
             .unwrap_or(diffusion_rs_core::TokenSource::CacheToken);
-        let source = match source {
+        let mut source = match source {
             ModelSource::DdufFile { file } => {
                 diffusion_rs_core::ModelSource::dduf(file).map_err(wrap_anyhow_error)?
             }
@@ -108,6 +243,9 @@ impl Pipeline {
                 diffusion_rs_core::ModelSource::from_model_id(model_id)
             }
         };
+        for (component, file) in component_files.unwrap_or_default() {
+            source = source.override_component_file(component, file);
+        }
         let offloading = offloading.map(|offloading| match offloading {
             Offloading::Full => diffusion_rs_core::Offloading::Full,
         });
@@ -117,9 +255,38 @@ impl Pipeline {
             ModelDType::BF16 => diffusion_rs_core::ModelDType::BF16,
             ModelDType::F32 => diffusion_rs_core::ModelDType::F32,
         };
+        let isq = isq.map(|isq| match isq {
+            IsqType::Q4_0 => diffusion_rs_core::IsqType::Q4_0,
+            IsqType::Q4_1 => diffusion_rs_core::IsqType::Q4_1,
+            IsqType::Q5_0 => diffusion_rs_core::IsqType::Q5_0,
+            IsqType::Q5_1 => diffusion_rs_core::IsqType::Q5_1,
+            IsqType::Q8_0 => diffusion_rs_core::IsqType::Q8_0,
+            IsqType::Q8_1 => diffusion_rs_core::IsqType::Q8_1,
+            IsqType::Q2K => diffusion_rs_core::IsqType::Q2K,
+            IsqType::Q3K => diffusion_rs_core::IsqType::Q3K,
+            IsqType::Q4K => diffusion_rs_core::IsqType::Q4K,
+            IsqType::Q5K => diffusion_rs_core::IsqType::Q5K,
+            IsqType::Q6K => diffusion_rs_core::IsqType::Q6K,
+            IsqType::Q8K => diffusion_rs_core::IsqType::Q8K,
+            IsqType::HQQ8 => diffusion_rs_core::IsqType::HQQ8,
+            IsqType::HQQ4 => diffusion_rs_core::IsqType::HQQ4,
+            IsqType::F8E4M3 => diffusion_rs_core::IsqType::F8E4M3,
+        });
         Ok(Self(
-            diffusion_rs_core::Pipeline::load(source, silent, token, revision, offloading, &dtype)
-                .map_err(wrap_anyhow_error)?,
+            diffusion_rs_core::Pipeline::load(
+                source,
+                diffusion_rs_core::LoadOptions {
+                    silent,
+                    token,
+                    revision,
+                    offloading_type: offloading,
+                    dtype,
+                    isq,
+                    offline,
+                    allow_saving,
+                },
+            )
+            .map_err(wrap_anyhow_error)?,
         ))
     }
 
@@ -127,38 +294,241 @@ impl Pipeline {
         &self,
         prompts: Vec<String>,
         params: DiffusionGenerationParams,
-    ) -> PyResult<Vec<Py<PyBytes>>> {
-        let images = self
+    ) -> PyResult<DiffusionGenerationOutput> {
+        let output = self
+            .0
+            .forward(prompts, params.into())
+            .map_err(wrap_anyhow_error)?;
+
+        Ok(output.into())
+    }
+
+    #[pyo3(signature = (
+        prompts,
+        init_images,
+        params,
+        strength = 0.8,
+    ))]
+    fn forward_img2img(
+        &self,
+        prompts: Vec<String>,
+        init_images: Vec<Vec<u8>>,
+        params: DiffusionGenerationParams,
+        strength: f64,
+    ) -> PyResult<DiffusionGenerationOutput> {
+        let output = self
             .0
-            .forward(
+            .forward_img2img(
                 prompts,
-                diffusion_rs_core::DiffusionGenerationParams {
-                    height: params.height,
-                    width: params.width,
-                    num_steps: params.num_steps,
-                    guidance_scale: params.guidance_scale,
+                images_from_bytes(init_images)?,
+                strength,
+                params.into(),
+            )
+            .map_err(wrap_anyhow_error)?;
+
+        Ok(output.into())
+    }
+
+    #[pyo3(signature = (
+        prompts,
+        init_images,
+        masks,
+        params,
+        strength = 1.0,
+    ))]
+    fn forward_inpaint(
+        &self,
+        prompts: Vec<String>,
+        init_images: Vec<Vec<u8>>,
+        masks: Vec<Vec<u8>>,
+        params: DiffusionGenerationParams,
+        strength: f64,
+    ) -> PyResult<DiffusionGenerationOutput> {
+        let output = self
+            .0
+            .forward_inpaint(
+                prompts,
+                images_from_bytes(init_images)?,
+                images_from_bytes(masks)?,
+                strength,
+                params.into(),
+            )
+            .map_err(wrap_anyhow_error)?;
+
+        Ok(output.into())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (
+        prompts,
+        init_images,
+        params,
+        left = 0,
+        right = 0,
+        top = 0,
+        bottom = 0,
+    ))]
+    fn forward_outpaint(
+        &self,
+        prompts: Vec<String>,
+        init_images: Vec<Vec<u8>>,
+        params: DiffusionGenerationParams,
+        left: u32,
+        right: u32,
+        top: u32,
+        bottom: u32,
+    ) -> PyResult<DiffusionGenerationOutput> {
+        let output = self
+            .0
+            .forward_outpaint(
+                prompts,
+                images_from_bytes(init_images)?,
+                diffusion_rs_core::OutpaintPadding {
+                    left,
+                    right,
+                    top,
+                    bottom,
                 },
+                params.into(),
             )
             .map_err(wrap_anyhow_error)?;
 
-        let mut images_bytes = Vec::new();
-        for image in images {
-            let mut buf = Vec::new();
-            image
-                .write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png)
-                .unwrap();
-            let bytes: Py<PyBytes> = Python::with_gil(move |py| PyBytes::new(py, &buf).into());
-            images_bytes.push(bytes);
+        Ok(output.into())
+    }
+
+    fn set_prompt_cache_size(&self, size_in_bytes: usize) {
+        self.0.set_prompt_cache_size(size_in_bytes);
+    }
+
+    #[pyo3(signature = (path_or_hf_id, scale = 1.0))]
+    fn load_lora(&self, path_or_hf_id: String, scale: f64) -> PyResult<()> {
+        self.0
+            .load_lora(path_or_hf_id, scale)
+            .map_err(wrap_anyhow_error)
+    }
+
+    #[pyo3(signature = (path_or_hf_id, adapter_name, scale = 1.0))]
+    fn load_lora_adapter(
+        &self,
+        path_or_hf_id: String,
+        adapter_name: String,
+        scale: f64,
+    ) -> PyResult<()> {
+        self.0
+            .load_lora_adapter(path_or_hf_id, adapter_name, scale)
+            .map_err(wrap_anyhow_error)
+    }
+
+    fn set_lora_adapters(&self, adapters: Vec<(String, f64)>) -> PyResult<()> {
+        let adapters = adapters
+            .iter()
+            .map(|(name, scale)| (name.as_str(), *scale))
+            .collect::<Vec<_>>();
+        self.0
+            .set_lora_adapters(&adapters)
+            .map_err(wrap_anyhow_error)
+    }
+
+    fn unload_lora(&self, adapter_name: String) -> PyResult<()> {
+        self.0.unload_lora(&adapter_name).map_err(wrap_anyhow_error)
+    }
+
+    fn lora_adapters(&self) -> Vec<(String, f64)> {
+        self.0.lora_adapters()
+    }
+
+    fn fuse_loras(&self) -> PyResult<()> {
+        self.0.fuse_loras().map_err(wrap_anyhow_error)
+    }
+
+    fn unfuse_loras(&self) -> PyResult<()> {
+        self.0.unfuse_loras().map_err(wrap_anyhow_error)
+    }
+
+    fn save_pretrained(&self, dir: String) -> PyResult<()> {
+        self.0.save_pretrained(dir).map_err(wrap_anyhow_error)
+    }
+
+    fn save_dduf(&self, file: String) -> PyResult<()> {
+        self.0.save_dduf(file).map_err(wrap_anyhow_error)
+    }
+}
+
+impl From<DiffusionGenerationParams> for diffusion_rs_core::DiffusionGenerationParams {
+    fn from(params: DiffusionGenerationParams) -> Self {
+        Self {
+            height: params.height,
+            width: params.width,
+            num_steps: params.num_steps,
+            guidance_scale: params.guidance_scale,
+            seed: params.seed,
+            negative_prompts: params.negative_prompts,
+            true_cfg_scale: params.true_cfg_scale,
+            num_images_per_prompt: params.num_images_per_prompt,
+            seeds: params.seeds,
+            sampler: params.sampler.map(|sampler| {
+                Arc::new(match sampler {
+                    Sampler::Euler => diffusion_rs_core::SamplerType::Euler,
+                    Sampler::Heun => diffusion_rs_core::SamplerType::Heun,
+                    Sampler::DpmPp2M => diffusion_rs_core::SamplerType::DpmPp2M,
+                    Sampler::EulerAncestral => {
+                        diffusion_rs_core::SamplerType::EulerAncestral { eta: params.eta }
+                    }
+                }) as Arc<dyn diffusion_rs_core::Sampler>
+            }),
+            sigma_schedule: params.sigma_schedule.map(|schedule| match schedule {
+                SigmaSchedule::Linear() => diffusion_rs_core::SigmaSchedule::Linear,
+                SigmaSchedule::Karras { rho } => diffusion_rs_core::SigmaSchedule::Karras { rho },
+                SigmaSchedule::Exponential() => diffusion_rs_core::SigmaSchedule::Exponential,
+                SigmaSchedule::Beta { alpha, beta } => {
+                    diffusion_rs_core::SigmaSchedule::Beta { alpha, beta }
+                }
+                SigmaSchedule::Simple() => diffusion_rs_core::SigmaSchedule::Simple,
+                SigmaSchedule::Custom { sigmas } => {
+                    diffusion_rs_core::SigmaSchedule::Custom(sigmas)
+                }
+            }),
+            scheduler_overrides: diffusion_rs_core::SchedulerOverrides {
+                shift: params.shift,
+                use_dynamic_shifting: params.use_dynamic_shifting,
+                base_shift: params.base_shift,
+                max_shift: params.max_shift,
+                ..Default::default()
+            },
+            ..Default::default()
         }
+    }
+}
+
+fn images_from_bytes(images: Vec<Vec<u8>>) -> PyResult<Vec<image::DynamicImage>> {
+    images
+        .iter()
+        .map(|bytes| image::load_from_memory(bytes))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
+}
 
-        Ok(images_bytes)
+fn images_to_bytes(images: Vec<image::DynamicImage>) -> Vec<Py<PyBytes>> {
+    let mut images_bytes = Vec::new();
+    for image in images {
+        let mut buf = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png)
+            .unwrap();
+        let bytes: Py<PyBytes> = Python::with_gil(move |py| PyBytes::new(py, &buf).into());
+        images_bytes.push(bytes);
     }
+    images_bytes
 }
 
 #[pymodule]
 fn diffusion_rs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<ModelSource>()?;
+    m.add_class::<Sampler>()?;
+    m.add_class::<SigmaSchedule>()?;
+    m.add_class::<IsqType>()?;
     m.add_class::<DiffusionGenerationParams>()?;
+    m.add_class::<DiffusionGenerationOutput>()?;
     m.add_class::<Pipeline>()?;
     Ok(())
 }